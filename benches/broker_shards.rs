@@ -0,0 +1,46 @@
+//! Measures how `async_std_server` message throughput scales with the
+//! number of broker shards.
+//!
+//! Starts the server once per shard count and has pairs of clients send
+//! direct messages to each other over loopback. Run with
+//! `cargo bench --bench broker_shards`, optionally followed by `-- 1 2 4 8`
+//! to pick the shard counts. By default they double up to the core count.
+
+use async_std::task;
+use chat_rs::bench;
+use std::process::Command;
+use std::thread;
+
+const PAIRS: usize = 64;
+const MESSAGES_PER_PAIR: usize = 5_000;
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    // `cargo bench` passes `--bench` along, only numbers are shard counts.
+    let mut shard_counts: Vec<usize> = std::env::args().skip(1)
+        .filter_map(|arg| arg.parse().ok())
+        .collect();
+    if shard_counts.is_empty() {
+        shard_counts.push(1);
+        while shard_counts.last().unwrap() * 2 <= cores {
+            shard_counts.push(shard_counts.last().unwrap() * 2);
+        }
+    }
+
+    println!("{} cores, {} pairs x {} messages", cores, PAIRS, MESSAGES_PER_PAIR);
+    println!("{:>6} {:>12} {:>14}", "shards", "seconds", "messages/s");
+    for shards in shard_counts {
+        let addr = bench::free_addr().unwrap();
+        let mut server = Command::new(env!("CARGO_BIN_EXE_async_std_server"));
+        server.env("CHAT_BROKER_SHARDS", shards.to_string())
+            // Receivers may fall behind, measure throughput without drops.
+            .env("CHAT_CLIENT_QUEUE", MESSAGES_PER_PAIR.to_string());
+        let mut server = bench::spawn(server, &addr).unwrap();
+        let outcome = task::block_on(bench::pairs(&addr, PAIRS, MESSAGES_PER_PAIR)).unwrap();
+        server.kill().unwrap();
+        server.wait().unwrap();
+
+        // Only delivered messages count.
+        println!("{:>6} {:>12.3} {:>14.0}", shards, outcome.elapsed.as_secs_f64(), outcome.per_second(outcome.delivered));
+    }
+}
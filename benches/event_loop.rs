@@ -0,0 +1,131 @@
+//! Measures broadcast throughput of `kqueue_server`, the event-loop server,
+//! for a number of worker threads.
+//!
+//! Starts the server once per worker count and has every client send
+//! messages to a group all clients joined, over loopback. Run with
+//! `cargo bench --bench event_loop`, optionally followed by `-- 0 2 4` to
+//! pick the worker counts. By default it runs without workers, and with a
+//! worker per spare core if there are any.
+//!
+//! Workers only decode requests, routing and writing stay on the event
+//! loop. They pay off when requests are costly to decode and there are
+//! cores to spare, with small messages like these the handoff costs more
+//! than it saves, which is why `server.workers` defaults to 0.
+//!
+//! `-- --baseline BINARY` also measures the server from before the event
+//! loop, whose poller and broadcast threads took turns over a pair of
+//! channels. It broadcasts what it reads without routing, so its clients
+//! don't log in and count bytes instead of messages. To build it:
+//!
+//! ```text
+//! git worktree add /tmp/lockstep 0a5bdd4
+//! cargo build --release --bin kqueue_server --manifest-path /tmp/lockstep/Cargo.toml
+//! cargo bench --bench event_loop -- --baseline /tmp/lockstep/target/release/kqueue_server
+//! ```
+
+use async_std::task;
+use chat_rs::bench::{self, Client, Load, Outcome};
+use chat_rs::protocol::{GroupId, Recipient};
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+const CLIENTS: usize = 32;
+const MESSAGES_PER_CLIENT: usize = 2_000;
+const TEXT_LEN: usize = 64;
+const GROUP: &str = "everyone";
+/// The baseline can't tell when it registered a client, it gets this long.
+const BASELINE_SETTLE: Duration = Duration::from_millis(200);
+
+/// A server to measure.
+enum Target {
+    Workers(usize),
+    Baseline(PathBuf),
+}
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    // `cargo bench` passes `--bench` along, only numbers are worker counts.
+    let mut args = std::env::args().skip(1);
+    let mut targets = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--baseline" => targets.push(Target::Baseline(args.next().expect("--baseline needs a binary").into())),
+            arg => targets.extend(arg.parse().ok().map(Target::Workers)),
+        }
+    }
+    if !targets.iter().any(|target| matches!(target, Target::Workers(_))) {
+        targets.push(Target::Workers(0));
+        if cores > 1 {
+            targets.push(Target::Workers(cores - 1));
+        }
+    }
+
+    println!("{} cores, {} clients x {} messages, each to everyone", cores, CLIENTS, MESSAGES_PER_CLIENT);
+    println!("{:>8} {:>12} {:>14} {:>16}", "workers", "seconds", "messages/s", "deliveries/s");
+    for target in targets {
+        let outcome = run(&target);
+        let label = match target {
+            Target::Workers(workers) => workers.to_string(),
+            Target::Baseline(_) => String::from("lockstep"),
+        };
+        println!(
+            "{:>8} {:>12.3} {:>14.0} {:>16.0}",
+            label,
+            outcome.elapsed.as_secs_f64(),
+            outcome.per_second(outcome.delivered / CLIENTS),
+            outcome.per_second(outcome.delivered),
+        );
+    }
+}
+
+fn run(target: &Target) -> Outcome {
+    let addr = bench::free_addr().unwrap();
+    let server = match target {
+        Target::Workers(workers) => {
+            let mut server = Command::new(env!("CARGO_BIN_EXE_kqueue_server"));
+            server.env("CHAT_WORKERS", workers.to_string());
+            server
+        }
+        Target::Baseline(binary) => Command::new(binary),
+    };
+    let mut server = bench::spawn(server, &addr).unwrap();
+
+    let group = Recipient::Group(GroupId(GROUP.to_string()));
+    let mut load = Load {
+        messages: Some(MESSAGES_PER_CLIENT),
+        batch: bench::FLOOD_BATCH,
+        size: TEXT_LEN,
+        fan_out: CLIENTS,
+        ..Load::default()
+    };
+    let baseline = matches!(target, Target::Baseline(_));
+    // The baseline passes on payloads without their length.
+    if baseline {
+        load.unframed = Some(load.payload_len(&group));
+    }
+    let outcome = task::block_on(async {
+        let mut clients = Vec::with_capacity(CLIENTS);
+        for i in 0..CLIENTS {
+            let client = match baseline {
+                true => Client::connect(&addr).await.unwrap(),
+                false => {
+                    let mut client = Client::login(&addr, &format!("c{}", i)).await.unwrap();
+                    client.join(GROUP).await.unwrap();
+                    client
+                }
+            };
+            clients.push(client);
+        }
+        if baseline {
+            task::sleep(BASELINE_SETTLE).await;
+        }
+        bench::drive(clients, |_| Some(group.clone()), &load).await
+    });
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    outcome
+}
@@ -0,0 +1,62 @@
+//! Puts the same load on each server: `async_std_server`, `threads_server`,
+//! `kqueue_server` (epoll on Linux) and, on Linux, `uring_server`.
+//!
+//! Pairs of clients send each other direct messages as fast as they can,
+//! which gives the throughput. Then a single client on an otherwise idle
+//! server sends messages to itself at a steady rate, which gives the
+//! latency. Run with `cargo bench --bench servers`, optionally followed by
+//! the names of the servers to run.
+
+use async_std::task;
+use chat_rs::bench::{self, Client, Load};
+use chat_rs::protocol::{Recipient, UserId};
+use std::process::Command;
+
+const PAIRS: usize = 32;
+const MESSAGES_PER_PAIR: usize = 5_000;
+const ECHOES: usize = 2_000;
+/// Slow enough that every echo finds the server idle.
+const ECHO_RATE: f64 = 1_000.0;
+
+const SERVERS: &[(&str, &str)] = &[
+    ("async_std_server", env!("CARGO_BIN_EXE_async_std_server")),
+    ("threads_server", env!("CARGO_BIN_EXE_threads_server")),
+    ("kqueue_server", env!("CARGO_BIN_EXE_kqueue_server")),
+    ("uring_server", env!("CARGO_BIN_EXE_uring_server")),
+];
+
+fn main() {
+    // `cargo bench` passes `--bench` along, skip the flags.
+    let names: Vec<String> = std::env::args().skip(1).filter(|arg| !arg.starts_with('-')).collect();
+    let servers = SERVERS.iter()
+        .filter(|(name, _)| names.is_empty() || names.iter().any(|wanted| wanted == name))
+        .filter(|(name, _)| *name != "uring_server" || cfg!(target_os = "linux"));
+
+    println!("{} pairs x {} messages, {} echoes", PAIRS, MESSAGES_PER_PAIR, ECHOES);
+    println!("{:>16} {:>12} {:>14} {:>10} {:>10}", "server", "seconds", "messages/s", "p50 us", "p99 us");
+    for (name, program) in servers {
+        let addr = bench::free_addr().unwrap();
+        let mut server = Command::new(program);
+        // Receivers may fall behind, measure throughput without drops.
+        server.env("CHAT_CLIENT_QUEUE", MESSAGES_PER_PAIR.to_string());
+        let mut server = bench::spawn(server, &addr).unwrap();
+        let (throughput, latency) = task::block_on(async {
+            let throughput = bench::pairs(&addr, PAIRS, MESSAGES_PER_PAIR).await.unwrap();
+            let echo = Client::login(&addr, "echo").await.unwrap();
+            let load = Load { messages: Some(ECHOES), rate: ECHO_RATE, ..Load::default() };
+            let latency = bench::drive(vec![echo], |_| Some(Recipient::User(UserId(String::from("echo")))), &load).await;
+            (throughput, latency)
+        });
+        server.kill().unwrap();
+        server.wait().unwrap();
+
+        println!(
+            "{:>16} {:>12.3} {:>14.0} {:>10} {:>10}",
+            name,
+            throughput.elapsed.as_secs_f64(),
+            throughput.per_second(throughput.delivered),
+            latency.percentile(0.5).unwrap_or_default(),
+            latency.percentile(0.99).unwrap_or_default(),
+        );
+    }
+}
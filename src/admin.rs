@@ -0,0 +1,138 @@
+//! Operating a running server over its admin socket.
+//!
+//! The server listens on the Unix-domain socket at `admin.socket`, which
+//! only its owner can open. Every [`AdminRequest`] carries the token from
+//! `admin.token` and is answered with one [`AdminResponse`], both framed
+//! like the chat protocol.
+
+use crate::config::{Config, ConfigError};
+use crate::protocol::{read_frame, write_frame};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Where the admin socket is and what it takes to use it.
+#[derive(Debug, Clone)]
+pub struct AdminSettings {
+    pub socket: PathBuf,
+    pub token: String,
+}
+
+impl AdminSettings {
+    /// Reads `admin.socket` and `admin.token`. The socket is off unless
+    /// both are set.
+    pub fn from_config(config: &Config) -> Result<Option<AdminSettings>, ConfigError> {
+        let Some(socket) = config.get_str("admin.socket") else {
+            return Ok(None);
+        };
+        let token = match config.get_str("admin.token") {
+            Some(token) if !token.is_empty() => token.to_string(),
+            _ => return Err(config.invalid("admin.token", "required when admin.socket is set")),
+        };
+
+        Ok(Some(AdminSettings { socket: PathBuf::from(socket), token }))
+    }
+
+    /// Whether `token` is ours, taking the same time wherever they differ.
+    pub fn authorizes(&self, token: &str) -> bool {
+        let (ours, theirs) = (self.token.as_bytes(), token.as_bytes());
+        ours.len() == theirs.len() && ours.iter().zip(theirs).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AdminRequest {
+    pub token: String,
+    pub command: AdminCommand,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// Connected users and where they connect from.
+    Users,
+    /// Disconnects user `name`. It may log in again right away.
+    Kick { name: String },
+    /// Disconnects user `name` and refuses connections from its address
+    /// for `duration`.
+    Ban { name: String, duration: Duration },
+    /// Sends `text` to everyone connected.
+    Announce { text: String },
+    /// Members of group `name`, or of every group.
+    Groups { name: Option<String> },
+    /// Queue lengths and counts per broker shard.
+    Stats,
+    /// Re-reads the configuration, like SIGHUP. Answered with an error if
+    /// it has one, and nothing changes then.
+    Reload,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum AdminResponse {
+    Users(Vec<UserInfo>),
+    Groups(Vec<GroupInfo>),
+    Stats(Vec<ShardStats>),
+    Done,
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserInfo {
+    pub name: String,
+    pub addr: SocketAddr,
+    /// Frames waiting to be sent to the user.
+    pub queued: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GroupInfo {
+    pub name: String,
+    /// Users on other servers are `name@server`.
+    pub members: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ShardStats {
+    pub shard: usize,
+    /// Events waiting for the shard.
+    pub queued_events: usize,
+    pub users: usize,
+    pub groups: usize,
+    pub links: usize,
+}
+
+/// How long `ban` refuses the address unless given a length.
+pub const DEFAULT_BAN: Duration = Duration::from_secs(300);
+
+/// Reads the command `chatctl` was given, as its remaining arguments.
+pub fn parse_command(words: &[String]) -> Result<AdminCommand, String> {
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    Ok(match words.as_slice() {
+        ["users"] => AdminCommand::Users,
+        ["kick", name] => AdminCommand::Kick { name: name.to_string() },
+        ["ban", name] => AdminCommand::Ban { name: name.to_string(), duration: DEFAULT_BAN },
+        ["ban", name, secs] => {
+            let secs = secs.parse().map_err(|e| format!("ban length {:?}: {}", secs, e))?;
+            AdminCommand::Ban { name: name.to_string(), duration: Duration::from_secs(secs) }
+        }
+        ["announce", text @ ..] if !text.is_empty() => AdminCommand::Announce { text: text.join(" ") },
+        ["groups"] => AdminCommand::Groups { name: None },
+        ["groups", name] => AdminCommand::Groups { name: Some(name.to_string()) },
+        ["stats"] => AdminCommand::Stats,
+        ["reload"] => AdminCommand::Reload,
+        [] => Err("no command given, see --help")?,
+        [command, ..] => Err(format!("unknown command or arguments for {:?}, see --help", command))?,
+    })
+}
+
+/// Sends `command` to the server listening on `socket` and waits for the
+/// answer.
+pub fn call(socket: &Path, token: &str, command: AdminCommand) -> io::Result<AdminResponse> {
+    let stream = UnixStream::connect(socket)?;
+    write_frame(&mut &stream, &AdminRequest { token: token.to_string(), command })?;
+
+    read_frame(&mut BufReader::new(&stream))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the admin connection"))
+}
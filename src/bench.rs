@@ -0,0 +1,399 @@
+//! Load driver shared by `chat-bench` and the cargo benches.
+//!
+//! [`spawn`] starts a server on a [`free_addr`] without the limits that
+//! would get in the way of one address sending this much. Simulated
+//! [`Client`]s log in over loopback and [`drive`] has them send a [`Load`]
+//! and time what arrives. Every message carries the time it was sent in its
+//! media, so latency covers the whole way from the sender's socket through
+//! the server to the receiver. Clients are tasks rather than threads,
+//! thousands of them are cheap.
+
+use crate::protocol::{encode_frame, payload_len, read_frame_async, ClientFrame, Message, Recipient, Request, ServerFrame, UserId};
+use async_std::io::{BufReader, ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use async_std::sync::Mutex;
+use async_std::task;
+use std::error::Error;
+use std::io;
+use std::net::{Shutdown, TcpListener};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long receivers get to catch up once the senders are done.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a spawned server gets to start listening.
+const SPAWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Messages a sender flooding the server writes at once, about what fits
+/// in a buffered writer's 8 KiB. Writing them one by one would measure the
+/// system calls.
+pub const FLOOD_BATCH: usize = 64;
+
+/// A loopback address nothing listens on, for a spawned server.
+pub fn free_addr() -> io::Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.to_string())
+}
+
+/// Starts `server` on `addr` once it listens there. Rate limits are off,
+/// other settings can be given as environment variables of `server`.
+pub fn spawn(mut server: Command, addr: &str) -> Result<Child, Box<dyn Error>> {
+    let program = server.get_program().to_string_lossy().into_owned();
+    let mut server = server
+        .arg(addr)
+        .env("CHAT_MESSAGE_RATE", "0")
+        .env("CHAT_BYTE_RATE", "0")
+        .env("CHAT_CONNECTION_RATE", "0")
+        .env("CHAT_CONNECTIONS_PER_IP", "0")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("{}: {}", program, e))?;
+
+    let started = Instant::now();
+    while started.elapsed() < SPAWN_TIMEOUT {
+        if std::net::TcpStream::connect(addr).is_ok() {
+            return Ok(server);
+        }
+        if let Some(status) = server.try_wait()? {
+            Err(format!("{} exited with {}", program, status))?;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let _ = server.kill();
+    let _ = server.wait();
+
+    Err(format!("{} did not start listening on {}", program, addr).into())
+}
+
+/// Raises the limit on open files towards `needed`. Returns the limit if
+/// it stays below that.
+pub fn raise_file_limit(needed: usize) -> Option<libc::rlim_t> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: `limit` is a valid rlimit to fill in and read.
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+    if limit.rlim_cur >= needed as libc::rlim_t {
+        return None;
+    }
+    limit.rlim_cur = limit.rlim_max.min(needed as libc::rlim_t);
+    // SAFETY: as above.
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 || limit.rlim_cur < needed as libc::rlim_t {
+        return Some(limit.rlim_cur);
+    }
+
+    None
+}
+
+/// A simulated client.
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    /// Its sender and its receiver answering pings share it, so that frames
+    /// don't interleave.
+    writer: Arc<Mutex<TcpStream>>,
+    stream: TcpStream,
+}
+
+impl Client {
+    /// Connects without logging in.
+    pub async fn connect(addr: &str) -> io::Result<Client> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+
+        Ok(Client {
+            reader: BufReader::new(stream.clone()),
+            writer: Arc::new(Mutex::new(stream.clone())),
+            stream,
+        })
+    }
+
+    /// Connects and logs in as `name`.
+    pub async fn login(addr: &str, name: &str) -> io::Result<Client> {
+        let mut client = Client::connect(addr).await?;
+        client.request(ClientFrame::Login { name: name.to_string(), direct_addrs: Vec::new() }).await?;
+        client.wait_for(|frame| matches!(frame, ServerFrame::Welcome { .. })).await?;
+
+        Ok(client)
+    }
+
+    /// Joins `group`, once the server has handled it.
+    pub async fn join(&mut self, group: &str) -> io::Result<()> {
+        // The pong comes once the server has handled the join.
+        self.request(ClientFrame::Join { group: group.to_string() }).await?;
+        self.request(ClientFrame::Ping { token: 0 }).await?;
+        self.wait_for(|frame| matches!(frame, ServerFrame::Pong { .. })).await
+    }
+
+    async fn request(&self, frame: ClientFrame) -> io::Result<()> {
+        self.writer.lock().await.write_all(&encode_frame(&Request { id: 0, frame })?).await
+    }
+
+    /// Reads frames until one `matches`, skipping heartbeats.
+    async fn wait_for(&mut self, matches: impl Fn(&ServerFrame) -> bool) -> io::Result<()> {
+        loop {
+            match read_frame_async(&mut self.reader).await? {
+                Some(frame) if matches(&frame) => return Ok(()),
+                Some(ServerFrame::Ping { .. }) => continue,
+                Some(frame) => return Err(io::Error::other(format!("unexpected {:?}", frame))),
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+    }
+}
+
+/// What the senders of a run send.
+#[derive(Debug, Clone)]
+pub struct Load {
+    /// How long to send for, or until `messages` are sent.
+    pub duration: Option<Duration>,
+    /// Messages each sender sends, or as many as fit in `duration`.
+    pub messages: Option<usize>,
+    /// Messages per second per sender, 0 sends as fast as the server takes
+    /// them.
+    pub rate: f64,
+    /// Messages a sender writes at once.
+    pub batch: usize,
+    /// Text of each message, in bytes.
+    pub size: usize,
+    /// How many clients receive each message.
+    pub fan_out: usize,
+    /// For servers that pass on payloads without their length, receivers
+    /// count this many bytes as a message instead of reading frames. There
+    /// is no latency then.
+    pub unframed: Option<usize>,
+}
+
+impl Load {
+    /// Length of the payload of each request sent to `to`, which is what
+    /// a server passing on payloads without their length writes.
+    pub fn payload_len(&self, to: &Recipient) -> usize {
+        payload_len(&Request { id: 0, frame: ClientFrame::Message(self.message(to.clone(), Duration::ZERO)) })
+            .unwrap_or_default()
+    }
+
+    /// A message to `to`, sent `at` into the run.
+    fn message(&self, to: Recipient, at: Duration) -> Message {
+        Message {
+            from: UserId(String::new()),
+            to,
+            text: Some("x".repeat(self.size)),
+            media: Some((at.as_micros() as u64).to_le_bytes().to_vec()),
+        }
+    }
+}
+
+impl Default for Load {
+    fn default() -> Load {
+        Load { duration: None, messages: None, rate: 0.0, batch: 1, size: 64, fan_out: 1, unframed: None }
+    }
+}
+
+/// What a run delivered.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub sent: usize,
+    /// Deliveries there would be if every message reached everyone.
+    pub expected: usize,
+    pub delivered: usize,
+    /// End-to-end latency of each delivery, in microseconds, sorted.
+    pub latencies: Vec<u32>,
+    /// Error frames, e.g. for messages the server refused.
+    pub errors: usize,
+    /// Clients the server disconnected before the run was over.
+    pub dropped: usize,
+    /// Until the senders were done or the last message arrived, whichever
+    /// came later.
+    pub elapsed: Duration,
+}
+
+impl Outcome {
+    /// `count` per second of the run.
+    pub fn per_second(&self, count: usize) -> f64 {
+        count as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// The latency at or below which fraction `p` of the deliveries came, in
+    /// microseconds.
+    pub fn percentile(&self, p: f64) -> Option<u32> {
+        let last = self.latencies.len().checked_sub(1)?;
+        Some(self.latencies[(last as f64 * p) as usize])
+    }
+}
+
+/// What a client saw while receiving.
+#[derive(Debug, Default)]
+struct Received {
+    delivered: usize,
+    latencies: Vec<u32>,
+    /// When the last message arrived, since the run started.
+    last: Duration,
+    errors: usize,
+    dropped: bool,
+}
+
+/// Has every client with a `recipient` send `load` to it, and every client
+/// receive, until all messages arrived or `DRAIN_TIMEOUT` after the senders
+/// are done.
+pub async fn drive(clients: Vec<Client>, recipient: impl Fn(usize) -> Option<Recipient>, load: &Load) -> Outcome {
+    let start = Instant::now();
+    let received = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+    let mut streams = Vec::new();
+    let mut receiving = Vec::new();
+    let mut sending = Vec::new();
+    for (index, Client { reader, writer, stream }) in clients.into_iter().enumerate() {
+        if let Some(to) = recipient(index) {
+            sending.push(task::spawn(send(Arc::clone(&writer), to, load.clone(), start)));
+        }
+        let (received, done) = (Arc::clone(&received), Arc::clone(&done));
+        receiving.push(task::spawn(receive(reader, writer, load.unframed, start, received, done)));
+        streams.push(stream);
+    }
+
+    let mut sent = 0;
+    for sender in sending {
+        sent += sender.await;
+    }
+    let sending = start.elapsed();
+    let expected = sent * load.fan_out;
+    let draining = Instant::now();
+    while received.load(Ordering::Relaxed) < expected && draining.elapsed() < DRAIN_TIMEOUT {
+        task::sleep(Duration::from_millis(10)).await;
+    }
+    // Receivers take the end of the connection for the end of the run.
+    done.store(true, Ordering::Relaxed);
+    for stream in &streams {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    let mut outcome = Outcome { sent, expected, elapsed: sending, ..Outcome::default() };
+    for receiver in receiving {
+        let seen = receiver.await;
+        outcome.delivered += seen.delivered;
+        outcome.latencies.extend(seen.latencies);
+        outcome.errors += seen.errors;
+        outcome.dropped += seen.dropped as usize;
+        outcome.elapsed = outcome.elapsed.max(seen.last);
+    }
+    outcome.latencies.sort_unstable();
+
+    outcome
+}
+
+/// Logs in `pairs` pairs of clients, the first of each sending `messages`
+/// direct messages to the second as fast as the server takes them.
+pub async fn pairs(addr: &str, pairs: usize, messages: usize) -> io::Result<Outcome> {
+    let mut clients = Vec::with_capacity(pairs * 2);
+    for name in (0..pairs).map(|i| format!("r{}", i)).chain((0..pairs).map(|i| format!("s{}", i))) {
+        clients.push(Client::login(addr, &name).await?);
+    }
+    let recipient = |client: usize| {
+        let receiver = client.checked_sub(pairs)?;
+        Some(Recipient::User(UserId(format!("r{}", receiver))))
+    };
+
+    let load = Load { messages: Some(messages), batch: FLOOD_BATCH, ..Load::default() };
+
+    Ok(drive(clients, recipient, &load).await)
+}
+
+/// Sends messages to `to` until the run is over, `load.batch` at a time
+/// and `load.rate` a second on average. Returns how many it sent.
+async fn send(writer: Arc<Mutex<TcpStream>>, to: Recipient, load: Load, start: Instant) -> usize {
+    let interval = match load.rate {
+        0.0 => Duration::ZERO,
+        rate => Duration::from_secs_f64(load.batch as f64 / rate),
+    };
+    let over = |sent: usize| {
+        load.messages.is_some_and(|messages| sent >= messages)
+            || load.duration.is_some_and(|duration| start.elapsed() >= duration)
+    };
+    let mut next = Duration::ZERO;
+    let mut sent = 0;
+    while !over(sent) {
+        if let Some(wait) = next.checked_sub(start.elapsed()) {
+            task::sleep(wait).await;
+        }
+        next += interval;
+        if over(sent) {
+            break;
+        }
+
+        let mut batch = Vec::new();
+        let count = load.messages.map_or(load.batch, |messages| load.batch.min(messages - sent));
+        for _ in 0..count {
+            let message = load.message(to.clone(), start.elapsed());
+            let Ok(frame) = encode_frame(&Request { id: sent as u64, frame: ClientFrame::Message(message) }) else {
+                return sent;
+            };
+            batch.extend_from_slice(&frame);
+            sent += 1;
+        }
+        if writer.lock().await.write_all(&batch).await.is_err() {
+            return sent - count;
+        }
+        if interval.is_zero() {
+            // Writes that don't block don't yield.
+            task::yield_now().await;
+        }
+    }
+
+    sent
+}
+
+/// Reads messages until the connection ends and answers pings.
+async fn receive(
+    mut reader: BufReader<TcpStream>,
+    writer: Arc<Mutex<TcpStream>>,
+    unframed: Option<usize>,
+    start: Instant,
+    received: Arc<AtomicUsize>,
+    done: Arc<AtomicBool>,
+) -> Received {
+    let mut seen = Received::default();
+    if let Some(len) = unframed {
+        let (mut buf, mut bytes) = (vec![0; 64 * 1024], 0);
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => bytes += n,
+            }
+            seen.delivered += bytes / len;
+            received.fetch_add(bytes / len, Ordering::Relaxed);
+            seen.last = start.elapsed();
+            bytes %= len;
+        }
+        seen.dropped = !done.load(Ordering::Relaxed);
+        return seen;
+    }
+
+    loop {
+        match read_frame_async(&mut reader).await {
+            Ok(Some(ServerFrame::Message(message))) => {
+                let now = start.elapsed();
+                let sent = message.media.as_deref()
+                    .and_then(|media| Some(u64::from_le_bytes(media.try_into().ok()?)))
+                    .unwrap_or_default();
+                let latency = (now.as_micros() as u64).saturating_sub(sent);
+                seen.latencies.push(latency.min(u32::MAX as u64) as u32);
+                seen.delivered += 1;
+                seen.last = now;
+                received.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(Some(ServerFrame::Ping { token })) => {
+                let Ok(pong) = encode_frame(&Request { id: 0, frame: ClientFrame::Pong { token } }) else {
+                    continue;
+                };
+                let _ = writer.lock().await.write_all(&pong).await;
+            }
+            Ok(Some(ServerFrame::Error { .. })) => seen.errors += 1,
+            Ok(Some(_)) => {}
+            Ok(None) | Err(_) => {
+                seen.dropped = !done.load(Ordering::Relaxed);
+                return seen;
+            }
+        }
+    }
+}
@@ -1,131 +1,892 @@
-
-#![allow(unused)]
-
-use serde::{Deserialize, Serialize};
 extern crate async_std;
 extern crate futures;
 use async_std::{
-    io::BufReader,
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    channel,
+    io::{self, BufReader},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::net::{UnixListener, UnixStream},
     prelude::*,
     task,
 };
-use futures::channel::mpsc;
+use chat_rs::admin::{AdminCommand, AdminRequest, AdminResponse, AdminSettings, GroupInfo, ShardStats, UserInfo};
+use chat_rs::config::Config;
+use chat_rs::federation::{handshake, Federation};
+use chat_rs::logging;
+use chat_rs::metrics::{self, Counter, Exposition, Gauge, Histogram, LATENCY_BUCKETS};
+use chat_rs::outbox::{outbox, Outbox, OutboxReceiver, Policy};
+use chat_rs::protocol::{
+    decode_frame, encode_frame, read_payload_async, ClientFrame, ErrorCode, FrameTooLarge,
+    GroupId, LinkFrame, Message, Recipient, Request, ServerFrame, UserId,
+};
+use chat_rs::ratelimit::{BanPolicy, Budget, Guard, Rate};
+use chat_rs::server::{self, ChatServer, ConnectionId, Output, Relay, Topology};
+use chat_rs::storage::History;
+use chat_rs::tls;
+use futures::channel::{mpsc, oneshot};
+use futures::io::{AsyncRead, AsyncWrite};
 use futures::sink::SinkExt;
-use futures::{select, FutureExt};
+use futures::{select, AsyncReadExt as _, FutureExt};
+use futures_rustls::TlsAcceptor;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use tracing::{error, field, info, info_span, trace, warn, Instrument, Span};
 use std::{
-    collections::hash_map::{Entry, HashMap},
+    collections::{HashMap, VecDeque},
+    fs::Permissions,
     future::Future,
-    sync::Arc,
+    net::Shutdown,
+    os::unix::fs::PermissionsExt,
+    sync::{Arc, Mutex, OnceLock, RwLock},
+    time::{Duration, Instant},
 };
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 type Sender<T> = mpsc::UnboundedSender<T>;
 type Receiver<T> = mpsc::UnboundedReceiver<T>;
+/// What a client sends, read over TLS if it is on.
+type ClientReader = BufReader<Box<dyn AsyncRead + Unpin + Send>>;
+type ClientWriter = Box<dyn AsyncWrite + Unpin + Send>;
 
 #[derive(Debug)]
 enum Void {}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct UserId(String);
-#[derive(Serialize, Deserialize, Debug)]
-struct GroupId(String);
-#[derive(Serialize, Deserialize, Debug)]
-enum Recipient {
-    User(UserId),
-    Group(GroupId)
+/// Tells a task waiting on the matching receiver to stop, see `stop_signal`.
+/// Dropping it does the same, so that returning early with an error stops
+/// the task too.
+struct Stop(Sender<Void>);
+
+impl Stop {
+    fn stop(self) {
+        self.0.close_channel();
+    }
+}
+
+/// A `Stop` and the receiver a task waits on, which ends once `stop` is
+/// called.
+fn stop_signal() -> (Stop, Receiver<Void>) {
+    let (sender, receiver) = mpsc::unbounded();
+    (Stop(sender), receiver)
+}
+
+/// Frames kept per federated server while its link is down. The oldest ones
+/// are dropped first.
+const MAX_PENDING_LINK_FRAMES: usize = 1024;
+const MAX_LINK_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// How long a federated server has to authenticate its link.
+const LINK_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const QUEUE_REPORT_INTERVAL: Duration = Duration::from_secs(30);
+const SHUTDOWN_REASON: &str = "server shutting down";
+/// How often the time events wait for a broker shard is measured.
+const LAG_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long metrics scrapes and admin commands wait for the shards to
+/// answer.
+const COLLECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long an admin client gets to send its command.
+const ADMIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a refused connection gets to read why.
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a client has to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to stop accepting when out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const KICK_REASON: &str = "disconnected by an operator";
+
+/// What the metrics endpoint reports, see `render_metrics`.
+struct Metrics {
+    /// Logged in clients.
+    connected_peers: Gauge,
+    connections: Counter,
+    /// Messages handled by a shard, whether delivered or not.
+    messages_routed: Counter,
+    /// Framed bytes read from and written to clients and linked servers.
+    bytes_received: Counter,
+    bytes_sent: Counter,
+    /// Requests sent before logging in.
+    not_logged_in: Counter,
+    /// Logins with a name that is already taken.
+    names_taken: Counter,
+    /// Linked servers that failed the federation handshake.
+    federation_denied: Counter,
+    /// Admin commands with a wrong token.
+    admin_denied: Counter,
+    rate_limited: Counter,
+    /// Time events wait in a shard's queue.
+    broker_lag: Histogram,
+    /// Time appending a message to the history takes.
+    storage_write: Histogram,
 }
-#[derive(Serialize, Deserialize, Debug)]
-struct Message {
-    from: UserId,
-    to: Recipient,
-    text: Option<String>,
-    media: Option<Vec<u8>>
+
+static METRICS: Metrics = Metrics {
+    connected_peers: Gauge::new(),
+    connections: Counter::new(),
+    messages_routed: Counter::new(),
+    bytes_received: Counter::new(),
+    bytes_sent: Counter::new(),
+    not_logged_in: Counter::new(),
+    names_taken: Counter::new(),
+    federation_denied: Counter::new(),
+    admin_denied: Counter::new(),
+    rate_limited: Counter::new(),
+    broker_lag: Histogram::new(LATENCY_BUCKETS),
+    storage_write: Histogram::new(LATENCY_BUCKETS),
+};
+
+/// Outbound queue settings for one class of connections.
+#[derive(Debug, Clone, Copy)]
+struct QueueLimits {
+    capacity: usize,
+    policy: Policy,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    /// Events waiting for each broker shard. Connections stop reading from
+    /// their sockets while their shard's queue is full.
+    broker_queue: usize,
+    clients: QueueLimits,
+    links: QueueLimits,
+}
+
+impl Limits {
+    /// Reads `limits.*`.
+    fn from_config(config: &Config) -> Result<Limits> {
+        Ok(Limits {
+            broker_queue: config.get("limits.broker_queue", 4096)?,
+            clients: QueueLimits {
+                capacity: config.get("limits.client_queue", 1024)?,
+                policy: config.get("limits.client_policy", Policy::DropEphemeral)?,
+            },
+            links: QueueLimits {
+                capacity: config.get("limits.link_queue", 8192)?,
+                policy: config.get("limits.link_policy", Policy::Disconnect)?,
+            },
+        })
+    }
+}
+
+/// Application level keepalive for client connections.
+#[derive(Debug, Clone, Copy)]
+struct Heartbeat {
+    /// How often clients are pinged.
+    interval: Duration,
+    /// Clients that send nothing for this long, not even a `Pong`, are
+    /// evicted.
+    timeout: Duration,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Mess {
-    message: String
+impl Heartbeat {
+    /// Reads `heartbeat.interval` and `heartbeat.idle_timeout`.
+    fn from_config(config: &Config) -> Result<Heartbeat> {
+        let heartbeat = Heartbeat {
+            interval: config.secs("heartbeat.interval", 10)?,
+            timeout: config.secs("heartbeat.idle_timeout", 30)?,
+        };
+        if heartbeat.interval.is_zero() || heartbeat.interval >= heartbeat.timeout {
+            Err(config.invalid("heartbeat.interval", "must be positive and shorter than heartbeat.idle_timeout"))?
+        }
+
+        Ok(heartbeat)
+    }
+}
+
+/// Flood control, see `chat_rs::ratelimit`. Rates are per second.
+#[derive(Debug, Clone, Copy)]
+struct RateLimits {
+    /// Frames per client, heartbeats aside.
+    messages: Rate,
+    /// Bytes per client.
+    bytes: Rate,
+    /// New connections per address.
+    connections: Rate,
+    bans: BanPolicy,
+}
+
+impl RateLimits {
+    /// Reads `rates.*` and `bans.*`. A rate or number of strikes of zero
+    /// turns that limit off.
+    fn from_config(config: &Config) -> Result<RateLimits> {
+        Ok(RateLimits {
+            messages: rate(config, "rates.messages", 10.0, "rates.message_burst", 20.0)?,
+            bytes: rate(config, "rates.bytes", 64.0 * 1024.0, "rates.byte_burst", 256.0 * 1024.0)?,
+            connections: rate(config, "rates.connections", 1.0, "rates.connection_burst", 10.0)?,
+            bans: BanPolicy {
+                strikes: config.get("bans.strikes", 5)?,
+                window: config.secs("bans.window", 60)?,
+                duration: config.secs("bans.duration", 300)?,
+            },
+        })
+    }
+}
+
+fn rate(config: &Config, per_second: &str, default_rate: f64, burst: &str, default_burst: f64) -> Result<Rate> {
+    let rate = Rate {
+        per_second: config.get(per_second, default_rate)?,
+        burst: config.get(burst, default_burst)?,
+    };
+    if !(rate.per_second >= 0.0 && rate.per_second.is_finite()) {
+        Err(config.invalid(per_second, "must be a non-negative number"))?
+    }
+    if rate.per_second > 0.0 && !(rate.burst >= 1.0 && rate.burst.is_finite()) {
+        Err(config.invalid(burst, "must be at least 1"))?
+    }
+
+    Ok(rate)
+}
+
+/// Settings that can change while the server runs.
+#[derive(Debug, Clone, Copy)]
+struct Tunables {
+    /// `broker_queue` is only read at startup, queue sizes and policies
+    /// apply to connections opened after a change.
+    limits: Limits,
+    heartbeat: Heartbeat,
+    rates: RateLimits,
+    /// How long clients get to receive what is still queued for them on
+    /// shutdown.
+    shutdown_timeout: Duration,
+}
+
+impl Tunables {
+    fn from_config(config: &Config) -> Result<Tunables> {
+        Ok(Tunables {
+            limits: Limits::from_config(config)?,
+            heartbeat: Heartbeat::from_config(config)?,
+            rates: RateLimits::from_config(config)?,
+            shutdown_timeout: config.secs("server.shutdown_timeout", 5)?,
+        })
+    }
 }
+
+/// The current [`Tunables`], replaced on reload. Readers take a copy.
+#[derive(Debug, Clone)]
+struct Settings(Arc<RwLock<Tunables>>);
+
+impl Settings {
+    fn get(&self) -> Tunables {
+        *self.0.read().unwrap()
+    }
+
+    fn set(&self, tunables: Tunables) {
+        *self.0.write().unwrap() = tunables;
+    }
+}
+
+/// Keys of settings in [`Tunables`], the others need a restart.
+const LIVE_KEYS: &[&str] = &[
+    "server.shutdown_timeout",
+    "limits.client_queue",
+    "limits.client_policy",
+    "limits.link_queue",
+    "limits.link_policy",
+    "heartbeat.interval",
+    "heartbeat.idle_timeout",
+    "rates.messages",
+    "rates.message_burst",
+    "rates.bytes",
+    "rates.byte_burst",
+    "rates.connections",
+    "rates.connection_burst",
+    "bans.strikes",
+    "bans.window",
+    "bans.duration",
+];
+
 fn main() {
     // main
     fn run() -> Result<()> {
-        task::block_on(accept_loop("127.0.0.1:8000"))
+        let config = Config::load()?;
+        logging::init(&config, None)?;
+        let addr = config.get_str("server.addr").unwrap_or("127.0.0.1:8000").to_string();
+        let federation = Federation::from_config(&config)?;
+        let shards = config.get("server.shards", std::thread::available_parallelism().map_or(1, |n| n.get()))?;
+        let tunables = Tunables::from_config(&config)?;
+
+        task::block_on(accept_loop(addr, federation, shards, tunables, config))
+    }
+
+    /// Reloads the configuration on SIGHUP. Receives the first SIGINT or
+    /// SIGTERM, a second one exits right away.
+    fn handle_signals(reloader: Arc<Reloader>) -> Result<channel::Receiver<i32>> {
+        let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+        let (sender, receiver) = channel::bounded(1);
+        std::thread::spawn(move || {
+            for signal in signals.forever() {
+                if signal == SIGHUP {
+                    if let Err(e) = reloader.reload() {
+                        error!("Not reloading configuration: {}", e);
+                    }
+                } else if sender.try_send(signal).is_err() {
+                    error!(signal, "Received signal again, exiting");
+                    std::process::exit(1);
+                }
+            }
+        });
+
+        Ok(receiver)
+    }
+
+    /// Applies changed settings, on SIGHUP or when an admin asks.
+    struct Reloader {
+        /// What the server started with, changes to settings not in
+        /// `LIVE_KEYS` are reported against it.
+        started: Config,
+        /// What was last loaded.
+        current: Mutex<Config>,
+        settings: Settings,
+        guard: Arc<Guard>,
+    }
+
+    impl Reloader {
+        /// Re-reads the configuration and applies the settings that can
+        /// change while running. Changes to the others are reported against
+        /// what the server started with, as they stay pending until a
+        /// restart. Nothing changes if the configuration has errors.
+        fn reload(&self) -> Result<()> {
+            let config = Config::load()?;
+            // Settings that need a restart are checked too, so that the
+            // next start doesn't fail.
+            Federation::from_config(&config)?;
+            tls::server_config(&config)?;
+            History::from_config(&config)?;
+            config.get("server.shards", 1usize)?;
+            let tunables = Tunables::from_config(&config)?;
+
+            self.settings.set(tunables);
+            self.guard.set_limits(tunables.rates.connections, tunables.rates.bans);
+            let mut current = self.current.lock().unwrap();
+            for key in current.changed(&config).into_iter().filter(|key| LIVE_KEYS.contains(key)) {
+                info!(key, "Reloaded setting");
+            }
+            for key in self.started.changed(&config).into_iter().filter(|key| !LIVE_KEYS.contains(key)) {
+                warn!(key, "Setting changed, restart to apply");
+            }
+            *current = config;
+
+            Ok(())
+        }
     }
 
-    async fn accept_loop(addr: impl ToSocketAddrs) -> Result<()> {
+    async fn accept_loop(
+        addr: impl ToSocketAddrs,
+        federation: Option<Federation>,
+        shard_count: usize,
+        tunables: Tunables,
+        config: Config,
+    ) -> Result<()> {
+        let metrics_addr: Option<SocketAddr> = config.get_opt("metrics.addr")?;
+        let admin = AdminSettings::from_config(&config)?;
+        let tls = tls::server_config(&config)?.map(TlsAcceptor::from);
+        let history = History::from_config(&config)?;
+        let settings = Settings(Arc::new(RwLock::new(tunables)));
+        let rates = tunables.rates;
+        let guard = Arc::new(Guard::new(rates.connections, rates.bans));
+        let reloader = Arc::new(Reloader {
+            current: Mutex::new(config.clone()),
+            started: config,
+            settings: settings.clone(),
+            guard: Arc::clone(&guard),
+        });
+        let signals = handle_signals(Arc::clone(&reloader))?;
+        let limits = tunables.limits;
         let listener = TcpListener::bind(addr).await?;
-        let (broker_sender, broker_receiver) = mpsc::unbounded();
-        let broker_handle = task::spawn(broker_loop(broker_receiver));
+        let topology = Topology {
+            shards: shard_count.max(1),
+            server_name: federation.as_ref().map(|f| f.server_name.clone()).unwrap_or_default(),
+            peers: federation.iter().flat_map(|f| &f.peers).map(|(server, _)| server.clone()).collect(),
+        };
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..shard_count.max(1))
+            .map(|_| channel::bounded(limits.broker_queue.max(1)))
+            .unzip();
+        let (internal_senders, internal_receivers): (Vec<_>, Vec<_>) = (0..shard_count.max(1))
+            .map(|_| channel::unbounded())
+            .unzip();
+        let shards = Shards {
+            senders: Arc::new(senders),
+            internal: Arc::new(internal_senders),
+            topology: Arc::new(topology),
+            outboxes: Arc::new(RwLock::new(HashMap::new())),
+        };
+        // Every writer task holds a sender, so the channel closes once all of
+        // them are done.
+        let (writers_sender, mut writers_receiver) = mpsc::unbounded::<Void>();
+        let (storage, storage_handle) = match history {
+            Some(history) => {
+                let (storage, handle) = storage_thread(history);
+                (Some(storage), Some(handle))
+            }
+            None => (None, None),
+        };
+        let broker_handles: Vec<_> = receivers.into_iter().zip(internal_receivers).enumerate()
+            .map(|(shard, (events, internal))| {
+                let storage = storage.clone();
+                let broker = broker_loop(shard, events, internal, shards.clone(), settings.clone(), storage, writers_sender.clone());
+                task::spawn(broker.instrument(info_span!("shard", shard)))
+            })
+            .collect();
+
+        let reporter = shards.clone();
+        task::spawn(async move {
+            loop {
+                task::sleep(QUEUE_REPORT_INTERVAL).await;
+                reporter.relay_to_all(|| Event::ReportQueues);
+            }
+        });
+
+        // Goes through the same queues as connections, so that the lag is
+        // what they see.
+        let prober = shards.clone();
+        task::spawn(async move {
+            loop {
+                task::sleep(LAG_PROBE_INTERVAL).await;
+                prober.send_to_all(|| Event::Probe { sent: Instant::now() }).await;
+            }
+        });
+
+        if let Some(metrics_addr) = metrics_addr {
+            let shards = shards.clone();
+            spawn_and_log_error(async move {
+                metrics::serve(metrics_addr, || render_metrics(shards.clone())).await?;
+                Ok(())
+            });
+        }
+
+        if let Some(admin) = &admin {
+            spawn_and_log_error(admin_loop(shards.clone(), Arc::clone(&reloader), admin.clone()));
+        }
+
+        if let Some(federation) = federation {
+            let federation = Arc::new(federation);
+            if let Some(link_addr) = federation.listen_addr {
+                spawn_and_log_error(link_accept_loop(shards.clone(), Arc::clone(&federation), link_addr));
+            }
+            for (server, addr) in &federation.peers {
+                let Some(addr) = *addr else {
+                    continue;
+                };
+                let dial = link_dial_loop(shards.clone(), Arc::clone(&federation), server.clone(), addr);
+                task::spawn(dial.instrument(info_span!("link", peer = %addr, server = %server)));
+            }
+        }
+
+        drop(storage);
+        info!(addr = %listener.local_addr()?, shards = broker_handles.len(), tls = tls.is_some(), "Waiting for connections");
         let mut incoming = listener.incoming();
-        println!("Waiting for connections...");
+        let mut connections = 0u64;
 
-        while let Some(stream) = incoming.next().await {
-            let stream = stream?;
-            println!("Accepting from: {}", stream.peer_addr()?);
-            spawn_and_log_error(connection_loop(broker_sender.clone(), stream));
+        loop {
+            select! {
+            stream = incoming.next().fuse() => match stream {
+                Some(stream) => {
+                    let Some((stream, peer_addr)) = accepted(stream, "client").await else {
+                        continue;
+                    };
+                    match guard.admit(peer_addr.ip()) {
+                        Ok(()) => {
+                            connections += 1;
+                            METRICS.connections.inc();
+                            let span = info_span!("connection", id = connections, peer = %peer_addr, user = field::Empty);
+                            info!(parent: &span, "Accepted connection");
+                            let (guard, writers) = (Arc::clone(&guard), writers_sender.clone());
+                            let connection = connection_loop(
+                                shards.clone(), connections, stream, tls.clone(), settings.clone(), guard, writers,
+                            );
+                            span.in_scope(|| spawn_and_log_error(connection));
+                        }
+                        Err(retry_after) => {
+                            warn!(peer = %peer_addr, ?retry_after, "Refusing connection");
+                            let error = ServerFrame::Error { request: None, error: ErrorCode::RateLimited { retry_after } };
+                            let error = encode_frame(&error)?;
+                            // Slow readers must not hold up the others.
+                            let tls = tls.clone();
+                            task::spawn(async move {
+                                let _ = async_std::io::timeout(REFUSAL_TIMEOUT, async {
+                                    let (_, mut writer) = secure(stream, tls.as_ref()).await?;
+                                    writer.write_all(&error).await?;
+                                    futures::io::AsyncWriteExt::close(&mut writer).await
+                                }).await;
+                            });
+                        }
+                    }
+                },
+                None => break,
+            },
+            signal = signals.recv().fuse() => {
+                info!(signal = signal?, "Shutting down");
+                break;
+            },
+        }
+        }
+        drop(incoming);
+        drop(listener);
+        if let Some(admin) = &admin {
+            let _ = std::fs::remove_file(&admin.socket);
         }
 
-        drop(broker_sender);
-        broker_handle.await;
+        // Shards hold each other's senders, so they have to be told to stop.
+        shards.relay_to_all(|| Event::Shutdown { reason: String::from(SHUTDOWN_REASON) });
+        drop(shards);
+        drop(writers_sender);
+        let flushed = async {
+            for broker_handle in broker_handles {
+                broker_handle.await;
+            }
+            // The shards are gone, and with them the senders of the storage
+            // thread, which stops once it has written what they sent.
+            if let Some(storage_handle) = storage_handle {
+                let _ = task::spawn_blocking(move || storage_handle.join()).await;
+            }
+            if let Some(void) = writers_receiver.next().await {
+                match void {}
+            }
+        };
+        match async_std::future::timeout(settings.get().shutdown_timeout, flushed).await {
+            Ok(()) => info!("Shutdown complete"),
+            Err(_) => warn!("Shutdown deadline passed, dropping unsent frames"),
+        }
 
         Ok(())
     }
 
-    async fn connection_loop(mut broker: Sender<Event>, stream: TcpStream) -> Result<()> {
-        let stream = Arc::new(stream);
-        let reader = BufReader::new(&*stream);
-        let mut lines = reader.lines();
+    /// Senders of every broker shard, each running a `ChatServer`. Each user,
+    /// group and federated server is owned by the shard its name hashes to,
+    /// and each connection served by its home shard, see `Topology`.
+    #[derive(Debug, Clone)]
+    struct Shards {
+        /// Bounded queues for events coming from connections.
+        senders: Arc<Vec<channel::Sender<Event>>>,
+        /// Unbounded queues for events shards send each other. Shards never
+        /// wait on each other, so they can't deadlock.
+        internal: Arc<Vec<channel::Sender<Event>>>,
+        topology: Arc<Topology>,
+        /// Outboxes of the connections. Frames for a connection are queued
+        /// by whichever shard routes them.
+        outboxes: Arc<RwLock<HashMap<ConnectionId, Outbox>>>,
+    }
 
-        let name = match lines.next().await {
-            None => Err("peer disconnected immediately")?,
-            Some(line) => line?,
+    impl Shards {
+        /// Sends `event` to the shard owning the user, group or federated
+        /// server `address`, waiting while its queue is full.
+        async fn send(&self, address: &str, event: Event) {
+            let _ = self.senders[self.topology.owner(address)].send(event).await;
+        }
+
+        /// Sends `event` to the home shard of `connection`.
+        async fn send_home(&self, connection: ConnectionId, event: Event) {
+            let _ = self.senders[self.topology.home(connection)].send(event).await;
+        }
+
+        async fn send_to_all(&self, event: impl Fn() -> Event) {
+            for shard in self.senders.iter() {
+                let _ = shard.send(event()).await;
+            }
+        }
+
+        /// Like `send`, for events sent by a shard or the admin socket.
+        fn relay(&self, address: &str, event: Event) {
+            self.relay_to(self.topology.owner(address), event);
+        }
+
+        fn relay_to(&self, shard: usize, event: Event) {
+            let _ = self.internal[shard].try_send(event);
+        }
+
+        fn relay_to_all(&self, event: impl Fn() -> Event) {
+            for shard in self.internal.iter() {
+                let _ = shard.try_send(event());
+            }
+        }
+    }
+
+    /// Appends the messages the shards send to `history`, until all of
+    /// them are gone.
+    fn storage_thread(mut history: History) -> (std::sync::mpsc::Sender<Message>, std::thread::JoinHandle<()>) {
+        let (sender, receiver) = std::sync::mpsc::channel::<Message>();
+        let handle = std::thread::spawn(move || {
+            for message in receiver {
+                let started = Instant::now();
+                if let Err(e) = history.append(message) {
+                    error!("Could not store message: {}", e);
+                }
+                METRICS.storage_write.observe(started.elapsed().as_secs_f64());
+            }
+        });
+
+        (sender, handle)
+    }
+
+    /// The two halves of a client connection, after the TLS handshake if
+    /// `tls` is set.
+    async fn secure(stream: TcpStream, tls: Option<&TlsAcceptor>) -> io::Result<(ClientReader, ClientWriter)> {
+        let Some(tls) = tls else {
+            return Ok((BufReader::new(Box::new(stream.clone())), Box::new(stream)));
         };
+        let stream = async_std::io::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream)).await?;
+        let (reader, writer) = stream.split();
+
+        Ok((BufReader::new(Box::new(reader)), Box::new(writer)))
+    }
+
+    async fn connection_loop(
+        shards: Shards,
+        connection: ConnectionId,
+        stream: TcpStream,
+        tls: Option<TlsAcceptor>,
+        settings: Settings,
+        guard: Arc<Guard>,
+        writers: Sender<Void>,
+    ) -> Result<()> {
         let addr = stream.peer_addr()?;
-        let (_shutdown_sender, shutdown_receiver) = mpsc::unbounded::<Void>();
-        broker.send(Event::NewPeer {
-            name: name.clone(),
-            stream: Arc::clone(&stream),
-            shutdown: shutdown_receiver,
-        }).await.unwrap();
+        let stream = Arc::new(stream);
+        let (mut reader, client_writer) = secure(TcpStream::clone(&stream), tls.as_ref()).await?;
+        let tunables = settings.get();
+        // Set by the home shard once logged in.
+        let user = Arc::new(OnceLock::new());
+        let mut flood = Flood {
+            budget: Budget::new(tunables.rates.messages, tunables.rates.bytes),
+            user: Arc::clone(&user),
+            guard,
+            settings: settings.clone(),
+        };
+        let limits = tunables.limits.clients;
+        let (messages, mut outgoing) = outbox(limits.capacity, limits.policy);
+        // Stops the writer once the connection is cleaned up.
+        let (writer, shutdown_receiver) = stop_signal();
+        let writer_stream = Arc::clone(&stream);
+        spawn_and_log_error(async move {
+            let res = connection_writer_loop(&mut outgoing, client_writer, &writer_stream, shutdown_receiver).await;
+            drop(writers);
+            res
+        });
+        // Ping tokens are microseconds since the connection started.
+        let started = Instant::now();
+        let (heartbeat, heartbeat_receiver) = stop_signal();
+        task::spawn(heartbeat_loop(messages.clone(), Arc::clone(&user), settings.clone(), started, heartbeat_receiver));
+        shards.outboxes.write().unwrap().insert(connection, messages.clone());
+        shards.send_home(connection, Event::Connected { connection, addr, user, span: Span::current() }).await;
+        let mut round_trip = None;
 
-        while let Some(line) = lines.next().await {
-            let line = line?;
-            let (dest, msg) = match line.find(':') {
-                None => continue,
-                Some(idx) => (&line[..idx], line[idx + 1 ..].trim()),
+        // Read errors still have to disconnect below.
+        let res: Result<()> = async {
+        loop {
+            let heartbeat = settings.get().heartbeat;
+            let (len, request) = match read_request(&mut reader, heartbeat.timeout).await? {
+                Incoming::Closed => break,
+                Incoming::Request { len, request } => (len, request),
+                Incoming::Malformed { len } => {
+                    let refusal = match flood.charge(len, false) {
+                        Ok(()) => Refusal::Skip(None, ErrorCode::MalformedFrame),
+                        Err(retry_after) => flood.refuse(addr, None, retry_after),
+                    };
+                    if refuse(&messages, &mut reader, heartbeat.timeout, refusal).await? {
+                        break;
+                    }
+                    continue;
+                }
+                Incoming::TooLarge { len } => {
+                    let refusal = Refusal::Close(None, ErrorCode::TooLarge { len: len as u64 });
+                    refuse(&messages, &mut reader, heartbeat.timeout, refusal).await?;
+                    break;
+                }
             };
-            let dest: Vec<String> = dest.split(',').map(|name| name.trim().to_string()).collect();
-            let msg: String = msg.trim().to_string();
+            if let Err(retry_after) = flood.charge(len, request.frame.is_heartbeat()) {
+                let refusal = flood.refuse(addr, Some(request.id), retry_after);
+                if refuse(&messages, &mut reader, heartbeat.timeout, refusal).await? {
+                    break;
+                }
+                continue;
+            }
+            match request.frame {
+                ClientFrame::Pong { token } => {
+                    round_trip = Some(started.elapsed().saturating_sub(Duration::from_micros(token)));
+                }
+                _ => shards.send_home(connection, Event::Chat(server::Event::Request { connection, request })).await,
+            }
+        }
+        Ok(())
+        }.await;
+
+        shards.send_home(connection, Event::Chat(server::Event::Disconnected { connection })).await;
+        shards.outboxes.write().unwrap().remove(&connection);
+        heartbeat.stop();
+        writer.stop();
+        info!(?round_trip, "Client disconnected");
+
+        res
+    }
+
+    /// Flood control of one client.
+    struct Flood {
+        /// Until login, what this connection may send.
+        budget: Budget,
+        /// The name logged in with, whose budget the guard keeps. Renames
+        /// don't change it.
+        user: Arc<OnceLock<String>>,
+        guard: Arc<Guard>,
+        /// For the current rates, which may change on reload.
+        settings: Settings,
+    }
+
+    impl Flood {
+        /// Takes what a frame of `len` bytes costs from the client's budget,
+        /// or returns how long the client has to wait before sending it.
+        fn charge(&mut self, len: usize, heartbeat: bool) -> std::result::Result<(), Duration> {
+            let rates = self.settings.get().rates;
+            match self.user.get() {
+                Some(name) => self.guard.charge(name, rates.messages, rates.bytes, len, heartbeat),
+                None => {
+                    self.budget.set_rates(rates.messages, rates.bytes);
+                    self.budget.charge(len, heartbeat)
+                }
+            }
+        }
 
-            broker.send(Event::Message {
-                from: name.clone(),
-                to: dest,
-                msg,
-            }).await.unwrap();
+        /// Refuses a request over the limits, closing the connection once
+        /// the client is banned.
+        fn refuse(&self, addr: SocketAddr, request: Option<u64>, retry_after: Duration) -> Refusal {
+            METRICS.rate_limited.inc();
+            match self.guard.strike(addr.ip()) {
+                Some(ban) => {
+                    warn!(?ban, "Banning address");
+                    Refusal::Close(request, ErrorCode::RateLimited { retry_after: ban })
+                }
+                None => Refusal::Skip(request, ErrorCode::RateLimited { retry_after }),
+            }
         }
+    }
 
-        println!("Client {} disconnected...", addr);
+    /// A request the connection won't act on.
+    enum Refusal {
+        /// Tell the client and carry on with the next request.
+        Skip(Option<u64>, ErrorCode),
+        /// Tell the client and close the connection.
+        Close(Option<u64>, ErrorCode),
+    }
 
-        Ok(())
+    impl Refusal {
+        /// The refused request, the error and whether to close.
+        fn into_parts(self) -> (Option<u64>, ErrorCode, bool) {
+            match self {
+                Refusal::Skip(request, error) => (request, error, false),
+                Refusal::Close(request, error) => (request, error, true),
+            }
+        }
+    }
+
+    /// Sends the client why its request was refused. Returns whether the
+    /// connection is done, after the writer has closed it.
+    async fn refuse(
+        messages: &Outbox,
+        reader: &mut ClientReader,
+        timeout: Duration,
+        refusal: Refusal,
+    ) -> Result<bool> {
+        let (request, error, close) = refusal.into_parts();
+        messages.push(encode_frame(&ServerFrame::Error { request, error })?, false);
+        if close {
+            // The writer closes the connection once the error is out.
+            messages.close();
+            linger(reader, timeout).await;
+        }
+
+        Ok(close)
+    }
+
+    /// Discards whatever the client still sends until the connection is
+    /// closed, so that unread data doesn't make the close reset it before
+    /// the client got our last frames.
+    async fn linger(reader: &mut ClientReader, timeout: Duration) {
+        let _ = async_std::future::timeout(timeout, async_std::io::copy(reader, &mut async_std::io::sink())).await;
+    }
+
+    /// What a client sent.
+    enum Incoming {
+        Closed,
+        Request { len: usize, request: Request },
+        /// A frame that didn't decode. It still counts against the limits.
+        Malformed { len: usize },
+        /// A frame longer than `MAX_FRAME_LEN`, nothing can be read after it.
+        TooLarge { len: usize },
+    }
+
+    /// Reads the next request, giving up on clients that stay silent for
+    /// longer than `timeout`.
+    async fn read_request(reader: &mut ClientReader, timeout: Duration) -> Result<Incoming> {
+        let payload = match async_std::future::timeout(timeout, read_payload_async(reader)).await {
+            Ok(Ok(Some(payload))) => payload,
+            Ok(Ok(None)) => return Ok(Incoming::Closed),
+            Ok(Err(e)) => match FrameTooLarge::find(&e) {
+                Some(too_large) => return Ok(Incoming::TooLarge { len: too_large.len }),
+                None => Err(e)?,
+            },
+            Err(_) => Err(format!("Evicting client silent for {:?}", timeout))?,
+        };
+        let len = 4 + payload.len();
+        METRICS.bytes_received.add(len as u64);
+
+        Ok(match decode_frame(&payload) {
+            Ok(request) => Incoming::Request { len, request },
+            Err(_) => Incoming::Malformed { len },
+        })
     }
 
+    /// Pings the client every heartbeat interval once it has logged in as
+    /// `user`, until the connection ends.
+    async fn heartbeat_loop(
+        messages: Outbox,
+        user: Arc<OnceLock<String>>,
+        settings: Settings,
+        started: Instant,
+        shutdown: Receiver<Void>,
+    ) {
+        let mut shutdown = shutdown.fuse();
+        loop {
+            select! {
+            _ = task::sleep(settings.get().heartbeat.interval).fuse() => {
+                if user.get().is_none() {
+                    continue;
+                }
+                let ping = ServerFrame::Ping { token: started.elapsed().as_micros() as u64 };
+                if !push_frame(&messages, &ping) {
+                    break;
+                }
+            },
+            void = shutdown.next().fuse() => match void {
+                Some(void) => match void {},
+                None => break,
+            },
+        }
+        }
+    }
+
+    /// Writes what is queued in `messages` to `writer`, which writes to
+    /// `stream`.
     async fn connection_writer_loop(
-        messages: &mut Receiver<Vec<u8>>,
-        stream: Arc<TcpStream>,
+        messages: &mut OutboxReceiver,
+        mut writer: impl AsyncWrite + Unpin,
+        stream: &TcpStream,
         shutdown: Receiver<Void>,
     ) -> Result<()> {
-        let mut stream = &*stream;
-        let mut messages = messages.fuse();
         let mut shutdown = shutdown.fuse();
         loop {
             select! {
             msg = messages.next().fuse() => match msg {
-                Some(msg) =>  {
-                        println!("{:?}", &msg);
-                        stream.write_all(&msg).await?
+                Some(msg) => {
+                    trace!(len = msg.len(), "Writing frame");
+                    writer.write_all(&msg).await?;
+                    METRICS.bytes_sent.add(msg.len() as u64);
+                },
+                None if messages.overflowed() => {
+                    // Also stops the reader, which then cleans up.
+                    let _ = stream.shutdown(Shutdown::Both);
+                    Err(format!("Disconnecting slow consumer {}", stream.peer_addr()?))?
+                },
+                None => {
+                    // Everything queued is written, let the client know
+                    // there won't be more.
+                    let _ = futures::io::AsyncWriteExt::close(&mut writer).await;
+                    let _ = stream.shutdown(Shutdown::Both);
+                    break;
                 },
-                None => break,
             },
             void = shutdown.next().fuse() => match void {
                 Some(void) => match void {},
@@ -136,86 +897,682 @@ fn main() {
         Ok(())
     }
 
+    /// The stream the listener accepted and its peer, or `None` after
+    /// logging why the connection was lost. Failed connections mustn't stop
+    /// the server, the next one may work.
+    async fn accepted(stream: io::Result<TcpStream>, what: &str) -> Option<(TcpStream, SocketAddr)> {
+        let e = match stream.and_then(|stream| Ok((stream.peer_addr()?, stream))) {
+            Ok((peer_addr, stream)) => return Some((stream, peer_addr)),
+            Err(e) => e,
+        };
+        warn!("Some {} could not connect: {}", what, e);
+        backoff_if_exhausted(&e).await;
+
+        None
+    }
+
+    /// Waits for `ACCEPT_BACKOFF` when out of file descriptors. Accepting
+    /// right away would fail over and over.
+    async fn backoff_if_exhausted(e: &io::Error) {
+        if matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) {
+            warn!(backoff = ?ACCEPT_BACKOFF, "Not accepting connections for now");
+            task::sleep(ACCEPT_BACKOFF).await;
+        }
+    }
+
+    async fn link_accept_loop(shards: Shards, federation: Arc<Federation>, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        let mut incoming = listener.incoming();
+        info!(%addr, "Waiting for federated servers");
+
+        while let Some(stream) = incoming.next().await {
+            let Some((stream, peer_addr)) = accepted(stream, "federated server").await else {
+                continue;
+            };
+            let span = info_span!("link", peer = %peer_addr, server = field::Empty);
+            info!(parent: &span, "Accepted server link");
+            span.in_scope(|| spawn_and_log_error(link_loop(shards.clone(), Arc::clone(&federation), stream, None)));
+        }
+
+        Ok(())
+    }
+
+    /// Keeps a link to `server` open, reconnecting with exponential backoff.
+    async fn link_dial_loop(shards: Shards, federation: Arc<Federation>, server: String, addr: SocketAddr) {
+        let mut delay = Duration::from_secs(1);
+        loop {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => {
+                    delay = Duration::from_secs(1);
+                    if let Err(e) = link_loop(shards.clone(), Arc::clone(&federation), stream, Some(&server)).await {
+                        warn!("Link failed: {}", e);
+                    }
+                }
+                Err(e) => warn!("Could not connect: {}", e),
+            }
+            task::sleep(delay).await;
+            delay = (delay * 2).min(MAX_LINK_RETRY_DELAY);
+        }
+    }
+
+    /// Serves a link with another server, `dialed` if we opened it.
+    async fn link_loop(shards: Shards, federation: Arc<Federation>, stream: TcpStream, dialed: Option<&str>) -> Result<()> {
+        let stream = Arc::new(stream);
+        let server = match async_std::io::timeout(LINK_HANDSHAKE_TIMEOUT, handshake(&mut &*stream, &federation)).await {
+            Ok(server) if dialed.is_some_and(|dialed| dialed != server) => {
+                METRICS.federation_denied.inc();
+                Err(format!("dialed {} but {} answered", dialed.unwrap_or_default(), server))?
+            }
+            Ok(server) => server,
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::PermissionDenied {
+                    METRICS.federation_denied.inc();
+                }
+                Err(e)?
+            }
+        };
+        let (writer, shutdown_receiver) = stop_signal();
+        Span::current().record("server", server.as_str());
+        info!("Linked");
+
+        shards.send(&server, Event::LinkUp {
+            server: server.clone(),
+            stream: Arc::clone(&stream),
+            shutdown: shutdown_receiver,
+            span: Span::current(),
+        }).await;
+
+        let mut reader = BufReader::new(&*stream);
+        while let Some(payload) = read_payload_async(&mut reader).await? {
+            METRICS.bytes_received.add(4 + payload.len() as u64);
+            let frame: LinkFrame = decode_frame(&payload)?;
+            // Hand the frame to the shard owning its local target.
+            let target = match &frame {
+                LinkFrame::Route(Message { to: Recipient::User(UserId(to)), .. })
+                | LinkFrame::Route(Message { to: Recipient::Group(GroupId(to)), .. })
+                | LinkFrame::Deliver { user: to, .. }
+                | LinkFrame::Join { group: to, .. }
+                | LinkFrame::Leave { group: to, .. } => to.clone(),
+                frame => {
+                    warn!(frame = frame.kind(), "Unexpected frame from server");
+                    continue;
+                }
+            };
+            shards.send(&target, Event::Chat(server::Event::Remote { server: server.clone(), frame })).await;
+        }
+
+        writer.stop();
+        info!("Link closed");
+
+        Ok(())
+    }
+
     #[derive(Debug)]
     enum Event {
-        NewPeer {
+        /// A client connected. Its home shard sets `user` and records it in
+        /// `span` once it logs in.
+        Connected {
+            connection: ConnectionId,
+            addr: SocketAddr,
+            user: Arc<OnceLock<String>>,
+            span: Span,
+        },
+        /// For the shard's `ChatServer`.
+        Chat(server::Event),
+        /// Logs the depth of the receiving shard's queues.
+        ReportQueues,
+        /// Measures how long events wait for the shard.
+        Probe {
+            sent: Instant,
+        },
+        /// Asks for the number of frames queued in each outbox, as kind,
+        /// name and length.
+        CollectQueues {
+            depths: Sender<(&'static str, String, usize)>,
+        },
+        /// Answers with the users of the receiving shard.
+        ListUsers {
+            users: Sender<UserInfo>,
+        },
+        /// Answers with the groups hosted by the receiving shard, or just
+        /// group `name`.
+        ListGroups {
+            name: Option<String>,
+            groups: Sender<GroupInfo>,
+        },
+        /// Answers with the counts of the receiving shard.
+        Stats {
+            stats: Sender<ShardStats>,
+        },
+        /// Disconnects user `name`. Answers with where it connected from,
+        /// or `None` if it isn't connected.
+        Kick {
             name: String,
+            reason: String,
+            kicked: oneshot::Sender<Option<SocketAddr>>,
+        },
+        /// Tells every connection the receiving shard serves why the server
+        /// is going away and stops the shard once their queues are flushed.
+        Shutdown {
+            reason: String,
+        },
+        LinkUp {
+            server: String,
             stream: Arc<TcpStream>,
             shutdown: Receiver<Void>,
+            span: Span,
         },
-        Message {
-            from: String,
-            to: Vec<String>,
-            msg: String,
-        },
     }
 
-    async fn broker_loop(events: Receiver<Event>) {
+    #[derive(Debug, Default)]
+    struct Link {
+        /// `None` while there is no link to the server.
+        messages: Option<Outbox>,
+        /// Frames waiting for the link to come (back) up.
+        pending: VecDeque<Vec<u8>>,
+    }
+
+    /// A connection served by a shard, as far as the shard's `ChatServer`
+    /// doesn't know it.
+    struct Home {
+        /// What the connection logs, and its writer and heartbeat.
+        span: Span,
+        /// Set once logged in, for the connection's flood control.
+        user: Arc<OnceLock<String>>,
+    }
+
+    /// Runs the `ChatServer` of one shard. Queues the frames it sends, hands
+    /// what it relays to the other shards and keeps the links to the
+    /// federated servers the shard owns.
+    async fn broker_loop(
+        shard: usize,
+        events: channel::Receiver<Event>,
+        internal: channel::Receiver<Event>,
+        shards: Shards,
+        settings: Settings,
+        storage: Option<std::sync::mpsc::Sender<Message>>,
+        writers: mpsc::UnboundedSender<Void>,
+    ) {
         let (disconnect_sender, mut disconnect_receiver) = // 1
-            mpsc::unbounded::<(String, Receiver<Vec<u8>>)>();
-        let mut peers: HashMap<String, Sender<Vec<u8>>> = HashMap::new();
+            mpsc::unbounded::<OutboxReceiver>();
+        let mut chat = ChatServer::sharded(shard, Topology::clone(&shards.topology));
+        let mut homes: HashMap<ConnectionId, Home> = HashMap::new();
+        let mut links: HashMap<String, Link> = HashMap::new();
         let mut events = events.fuse();
+        let mut internal = internal.fuse();
         loop {
             let event = select! {
             event = events.next().fuse() => match event {
                 None => break, // 2
                 Some(event) => event,
             },
+            event = internal.next().fuse() => match event {
+                None => break,
+                Some(event) => event,
+            },
             disconnect = disconnect_receiver.next().fuse() => {
-                let (name, _pending_messages) = disconnect.unwrap(); // 3
-                assert!(peers.remove(&name).is_some());
+                // Frames that didn't make it over a broken link are retried
+                // once it is back up.
+                let mut pending_messages = match disconnect {
+                    Some(pending_messages) => pending_messages, // 3
+                    None => continue,
+                };
+                for (server, link) in links.iter_mut() {
+                    if link.messages.as_ref().is_some_and(|l| l.is_connected_to(&pending_messages)) {
+                        warn!(%server, "Link is down, queueing frames");
+                        link.messages = None;
+                        let mut frames: VecDeque<_> = pending_messages.drain().into();
+                        frames.append(&mut link.pending);
+                        while frames.len() > MAX_PENDING_LINK_FRAMES {
+                            frames.pop_front();
+                        }
+                        link.pending = frames;
+                    }
+                }
                 continue;
             },
         };
             match event {
-                Event::Message { from, to, msg } => {
-                    for addr in to {
-                        if let Some(peer) = peers.get_mut(&addr) {
-                            let msg = Message {
-                                from: UserId(from.clone()),
-                                to: Recipient::User(UserId(addr)),
-                                text: Some(format!("from {}: {}\n", from, msg)),
-                                media: None,
-                            };
-                            peer.send(bincode::serialize(&msg).unwrap()).await
-                                .unwrap() // 6
+                Event::Connected { connection, addr, user, span } => {
+                    let _entered = span.enter();
+                    chat.handle(server::Event::Connected { connection, addr });
+                    drop(_entered);
+                    homes.insert(connection, Home { span, user });
+                }
+                Event::Chat(event) => {
+                    if matches!(
+                        event,
+                        server::Event::Request { request: Request { frame: ClientFrame::Message(..), .. }, .. }
+                            | server::Event::Remote { frame: LinkFrame::Route(..), .. }
+                    ) {
+                        METRICS.messages_routed.inc();
+                    }
+                    if let Some(storage) = &storage {
+                        if let Some(message) = stored(&chat, &event) {
+                            let _ = storage.send(message);
+                        }
+                    }
+                    // Logins end with the answer of the shard owning the name.
+                    let connection = match &event {
+                        server::Event::Relayed(Relay::Claimed { connection, .. }) => Some(*connection),
+                        event => event.connection(),
+                    };
+                    let home = connection.and_then(|connection| Some((connection, homes.get(&connection)?)));
+                    let Some((connection, home)) = home else {
+                        chat.handle(event);
+                        write_outputs(&mut chat, &shards, &mut links);
+                        continue;
+                    };
+                    let _entered = home.span.enter();
+                    let logged_in = chat.name(connection).is_some();
+                    let disconnected = matches!(event, server::Event::Disconnected { .. });
+                    chat.handle(event);
+                    match chat.name(connection) {
+                        Some(name) if !logged_in => {
+                            home.span.record("user", name);
+                            let _ = home.user.set(name.to_string());
+                            METRICS.connected_peers.inc();
+                        }
+                        None if logged_in || disconnected => {
+                            if logged_in {
+                                METRICS.connected_peers.dec();
+                            }
+                            drop(_entered);
+                            homes.remove(&connection);
                         }
+                        _ => {}
                     }
                 }
-                Event::NewPeer { name, stream, shutdown } => {
-                    match peers.entry(name.clone()) {
-                        Entry::Occupied(..) => (),
-                        Entry::Vacant(entry) => {
-                            let (client_sender, mut client_receiver) = mpsc::unbounded();
-                            entry.insert(client_sender);
-                            let mut disconnect_sender = disconnect_sender.clone();
-                            spawn_and_log_error(async move {
-                                let res = connection_writer_loop(&mut client_receiver, stream, shutdown).await;
-                                disconnect_sender.send((name, client_receiver)).await // 4
-                                    .unwrap();
-                                res
-                            });
+                Event::Shutdown { reason } => {
+                    let frame = ServerFrame::Shutdown { reason };
+                    let outboxes = shards.outboxes.read().unwrap();
+                    for outbox in homes.keys().filter_map(|connection| outboxes.get(connection)) {
+                        push_frame(outbox, &frame);
+                        outbox.close();
+                    }
+                    let unsent: usize = links.values().map(|link| link.pending.len()).sum();
+                    if unsent > 0 {
+                        warn!(unsent, "Dropping frames for unreachable servers");
+                    }
+                    break;
+                }
+                Event::Probe { sent } => METRICS.broker_lag.observe(sent.elapsed().as_secs_f64()),
+                Event::CollectQueues { depths } => {
+                    let outboxes = shards.outboxes.read().unwrap();
+                    for (name, connection, _) in chat.users() {
+                        if let Some(outbox) = outboxes.get(&connection) {
+                            let _ = depths.unbounded_send(("client", name.to_string(), outbox.len()));
                         }
                     }
+                    for (server, link) in &links {
+                        let len = link.messages.as_ref().map_or(link.pending.len(), |messages| messages.len());
+                        let _ = depths.unbounded_send(("link", server.clone(), len));
+                    }
+                }
+                Event::ListUsers { users } => {
+                    let outboxes = shards.outboxes.read().unwrap();
+                    for (name, connection, addr) in chat.users() {
+                        let _ = users.unbounded_send(UserInfo {
+                            name: name.to_string(),
+                            addr,
+                            queued: outboxes.get(&connection).map_or(0, |outbox| outbox.len()),
+                        });
+                    }
+                }
+                Event::ListGroups { name, groups } => {
+                    let hosted = chat.groups().filter(|(group, _)| name.as_deref().is_none_or(|name| name == *group));
+                    for (group, members) in hosted {
+                        let mut members: Vec<String> = members.into_iter().map(String::from).collect();
+                        members.sort();
+                        let _ = groups.unbounded_send(GroupInfo { name: group.to_string(), members });
+                    }
+                }
+                Event::Stats { stats } => {
+                    let _ = stats.unbounded_send(ShardStats {
+                        shard,
+                        queued_events: shards.senders[shard].len() + shards.internal[shard].len(),
+                        users: chat.users().count(),
+                        groups: chat.groups().count(),
+                        links: links.len(),
+                    });
+                }
+                Event::Kick { name, reason, kicked } => {
+                    let user = chat.user(&name);
+                    let outbox = user.and_then(|(connection, _)| shards.outboxes.read().unwrap().get(&connection).cloned());
+                    if let Some(outbox) = outbox {
+                        push_frame(&outbox, &ServerFrame::Error { request: None, error: ErrorCode::Kicked { reason } });
+                        // The writer closes the connection once the error
+                        // is out, and the reader then cleans up.
+                        outbox.close();
+                    }
+                    let _ = kicked.send(user.map(|(_, addr)| addr));
+                }
+                Event::ReportQueues => {
+                    info!(queued = shards.senders[shard].len(), connections = homes.len(), links = links.len(), "Queues");
+                    let all = shards.outboxes.read().unwrap();
+                    let outboxes = chat.users()
+                        .filter_map(|(name, connection, _)| Some((name, all.get(&connection)?)))
+                        .chain(links.iter().filter_map(|(server, link)| Some((server.as_str(), link.messages.as_ref()?))));
+                    for (name, outbox) in outboxes {
+                        if outbox.len() * 2 >= outbox.capacity() || outbox.dropped() > 0 {
+                            warn!(
+                                %name, len = outbox.len(), capacity = outbox.capacity(), dropped = outbox.dropped(),
+                                "Outbox filling up",
+                            );
+                        }
+                    }
+                }
+                Event::LinkUp { server, stream, shutdown, span } => {
+                    let limits = settings.get().limits.links;
+                    let (link_sender, mut link_receiver) = outbox(limits.capacity, limits.policy);
+                    let link = links.entry(server).or_default();
+                    for frame in link.pending.drain(..) {
+                        link_sender.push(frame, false);
+                    }
+                    link.messages = Some(link_sender);
+                    let mut disconnect_sender = disconnect_sender.clone();
+                    let writers = writers.clone();
+                    span.in_scope(|| spawn_and_log_error(async move {
+                        let res = connection_writer_loop(&mut link_receiver, &*stream, &stream, shutdown).await;
+                        // Nobody is left to requeue the frames once the
+                        // shard has stopped.
+                        let _ = disconnect_sender.send(link_receiver).await; // 4
+                        drop(writers);
+                        res
+                    }));
                 }
             }
+            write_outputs(&mut chat, &shards, &mut links);
         }
-        drop(peers); // 5
+        drop(links); // 5
         drop(disconnect_sender); // 6
-        while let Some((_name, _pending_messages)) = disconnect_receiver.next().await {
+        while let Some(_pending_messages) = disconnect_receiver.next().await {
+        }
+    }
+
+    /// What of `event` goes into the history: messages from logged in
+    /// users, signed with their name, and from federated servers.
+    fn stored(chat: &ChatServer, event: &server::Event) -> Option<Message> {
+        match event {
+            server::Event::Request { connection, request: Request { frame: ClientFrame::Message(message), .. } } => {
+                let name = chat.name(*connection)?;
+                Some(Message { from: UserId(name.to_string()), ..message.clone() })
+            }
+            server::Event::Remote { frame: LinkFrame::Route(message), .. } => Some(message.clone()),
+            _ => None,
+        }
+    }
+
+    /// Does what the shard's `ChatServer` asks for.
+    fn write_outputs(chat: &mut ChatServer, shards: &Shards, links: &mut HashMap<String, Link>) {
+        while let Some(output) = chat.poll_output() {
+            match output {
+                Output::Send { to, frame } => {
+                    match frame {
+                        ServerFrame::NameTaken { .. } => METRICS.names_taken.inc(),
+                        ServerFrame::Error { error: ErrorCode::NotAuthorized, .. } => METRICS.not_logged_in.inc(),
+                        _ => {}
+                    }
+                    let bytes = match encode_frame(&frame) {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            error!(frame = frame.kind(), "Can't encode frame: {}", e);
+                            continue;
+                        }
+                    };
+                    let outboxes = shards.outboxes.read().unwrap();
+                    for connection in to {
+                        if let Some(outbox) = outboxes.get(&connection) {
+                            outbox.push(bytes.clone(), frame.is_ephemeral());
+                        }
+                    }
+                }
+                Output::Close { connection } => {
+                    if let Some(outbox) = shards.outboxes.read().unwrap().get(&connection) {
+                        outbox.close();
+                    }
+                }
+                Output::Relay { shard, relay } => shards.relay_to(shard, Event::Chat(server::Event::Relayed(relay))),
+                Output::Forward { server, frame } => forward(links, &server, &frame),
+            }
+        }
+    }
+
+    /// Queues `frame` in `outbox`. Returns whether it was queued.
+    fn push_frame(outbox: &Outbox, frame: &ServerFrame) -> bool {
+        match encode_frame(frame) {
+            Ok(bytes) => outbox.push(bytes, frame.is_ephemeral()),
+            Err(e) => {
+                error!(frame = frame.kind(), "Can't encode frame: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Sends `frame` to a federated server, queueing it while the link is
+    /// down.
+    fn forward(links: &mut HashMap<String, Link>, server: &str, frame: &LinkFrame) {
+        let link = links.entry(server.to_string()).or_default();
+        let bytes = match encode_frame(frame) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!(%server, frame = frame.kind(), "Can't encode frame: {}", e);
+                return;
+            }
+        };
+        // A full outbox with the disconnect policy refuses the frame; the
+        // link is torn down and the frame resent once it reconnects.
+        if let Some(messages) = &link.messages {
+            if messages.push(bytes.clone(), false) {
+                return;
+            }
+        }
+        if link.pending.len() == MAX_PENDING_LINK_FRAMES {
+            link.pending.pop_front();
+        }
+        link.pending.push_back(bytes);
+    }
+
+    /// Current metrics in the Prometheus text format.
+    async fn render_metrics(shards: Shards) -> String {
+        let (depths_sender, depths_receiver) = mpsc::unbounded();
+        shards.relay_to_all(|| Event::CollectQueues { depths: depths_sender.clone() });
+        drop(depths_sender);
+        let depths = collect(depths_receiver).await;
+
+        let mut exposition = Exposition::new();
+        exposition
+            .gauge("chat_connected_peers", "Logged in clients.", &METRICS.connected_peers)
+            .counter("chat_connections_total", "Accepted client connections.", &METRICS.connections)
+            .counter("chat_messages_routed_total", "Messages routed by the broker.", &METRICS.messages_routed)
+            .counter("chat_received_bytes_total", "Bytes read from clients and servers.", &METRICS.bytes_received)
+            .counter("chat_sent_bytes_total", "Bytes written to clients and servers.", &METRICS.bytes_sent)
+            .counter("chat_rate_limited_total", "Requests refused for going over the rate limits.", &METRICS.rate_limited)
+            .describe("chat_auth_failures_total", "counter", "Requests and links refused for lack of authorization.")
+            .sample("chat_auth_failures_total", &[("reason", "not_logged_in")], METRICS.not_logged_in.get())
+            .sample("chat_auth_failures_total", &[("reason", "name_taken")], METRICS.names_taken.get())
+            .sample("chat_auth_failures_total", &[("reason", "federation")], METRICS.federation_denied.get())
+            .sample("chat_auth_failures_total", &[("reason", "admin")], METRICS.admin_denied.get())
+            .histogram("chat_broker_lag_seconds", "Time events wait for a broker shard.", &METRICS.broker_lag)
+            .histogram("chat_storage_write_seconds", "Time appending a message to the history takes.", &METRICS.storage_write)
+            .describe("chat_outbox_frames", "gauge", "Frames queued for a client or linked server.");
+        for (kind, name, len) in &depths {
+            exposition.sample("chat_outbox_frames", &[("kind", kind), ("name", name)], len);
+        }
+
+        exposition.into_text()
+    }
+
+    /// What the shards sent to `receiver`, or as much of it as arrived
+    /// within `COLLECT_TIMEOUT`. Every shard drops its sender once it has
+    /// answered.
+    async fn collect<T>(receiver: Receiver<T>) -> Vec<T> {
+        async_std::future::timeout(COLLECT_TIMEOUT, futures::StreamExt::collect::<Vec<_>>(receiver))
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Answers admin commands on the Unix socket `admin.socket`, which only
+    /// the user running the server can open.
+    async fn admin_loop(shards: Shards, reloader: Arc<Reloader>, admin: AdminSettings) -> Result<()> {
+        // A socket nobody answers on was left behind by a server that
+        // didn't shut down cleanly.
+        if std::os::unix::net::UnixStream::connect(&admin.socket).is_ok() {
+            Err(format!("Admin socket {} is in use by another server", admin.socket.display()))?
+        }
+        let _ = std::fs::remove_file(&admin.socket);
+        let listener = UnixListener::bind(&admin.socket).await?;
+        std::fs::set_permissions(&admin.socket, Permissions::from_mode(0o600))?;
+        info!(socket = %admin.socket.display(), "Waiting for admin commands");
+
+        let admin = Arc::new(admin);
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Some admin client could not connect: {}", e);
+                    backoff_if_exhausted(&e).await;
+                    continue;
+                }
+            };
+            let (shards, reloader, admin) = (shards.clone(), Arc::clone(&reloader), Arc::clone(&admin));
+            spawn_and_log_error(async move {
+                async_std::future::timeout(ADMIN_TIMEOUT, admin_connection(&shards, &reloader, &admin, stream))
+                    .await
+                    .map_err(|_| "Admin client timed out")?
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Answers the one command sent on `stream`.
+    async fn admin_connection(
+        shards: &Shards,
+        reloader: &Arc<Reloader>,
+        admin: &AdminSettings,
+        stream: UnixStream,
+    ) -> Result<()> {
+        let payload = match read_payload_async(&mut BufReader::new(&stream)).await? {
+            Some(payload) => payload,
+            None => return Ok(()),
+        };
+        let response = match decode_frame::<AdminRequest>(&payload) {
+            Err(e) => AdminResponse::Error(format!("malformed request: {}", e)),
+            Ok(request) if !admin.authorizes(&request.token) => {
+                METRICS.admin_denied.inc();
+                warn!("Refusing admin command with a wrong token");
+                AdminResponse::Error(String::from("wrong token"))
+            }
+            Ok(AdminRequest { command, .. }) => {
+                info!(?command, "Admin command");
+                admin_command(shards, reloader, command).await
+            }
+        };
+        (&stream).write_all(&encode_frame(&response)?).await?;
+
+        Ok(())
+    }
+
+    async fn admin_command(shards: &Shards, reloader: &Arc<Reloader>, command: AdminCommand) -> AdminResponse {
+        match command {
+            AdminCommand::Users => {
+                let (users_sender, users_receiver) = mpsc::unbounded();
+                shards.relay_to_all(|| Event::ListUsers { users: users_sender.clone() });
+                drop(users_sender);
+                let mut users = collect(users_receiver).await;
+                users.sort_by(|a, b| a.name.cmp(&b.name));
+                AdminResponse::Users(users)
+            }
+            AdminCommand::Kick { name } => match kick(shards, &name).await {
+                Some(addr) => {
+                    info!(user = %name, peer = %addr, "Kicked user");
+                    AdminResponse::Done
+                }
+                None => AdminResponse::Error(format!("no user named {}", name)),
+            },
+            AdminCommand::Ban { name, duration } => match kick(shards, &name).await {
+                Some(addr) => {
+                    reloader.guard.ban(addr.ip(), duration);
+                    warn!(user = %name, peer = %addr, ban = ?duration, "Banning address");
+                    AdminResponse::Done
+                }
+                None => AdminResponse::Error(format!("no user named {}", name)),
+            },
+            AdminCommand::Announce { text } => {
+                shards.relay_to_all(|| {
+                    let frame = ServerFrame::Announcement { text: text.clone() };
+                    Event::Chat(server::Event::Relayed(Relay::Broadcast { frame, except: None }))
+                });
+                AdminResponse::Done
+            }
+            AdminCommand::Groups { name } => {
+                let (groups_sender, groups_receiver) = mpsc::unbounded();
+                match &name {
+                    Some(group) => shards.relay(group, Event::ListGroups { name: name.clone(), groups: groups_sender }),
+                    None => {
+                        shards.relay_to_all(|| Event::ListGroups { name: None, groups: groups_sender.clone() });
+                        drop(groups_sender);
+                    }
+                }
+                let mut groups = collect(groups_receiver).await;
+                match name {
+                    Some(name) if groups.is_empty() => AdminResponse::Error(format!("no group named {}", name)),
+                    _ => {
+                        groups.sort_by(|a, b| a.name.cmp(&b.name));
+                        AdminResponse::Groups(groups)
+                    }
+                }
+            }
+            AdminCommand::Stats => {
+                let (stats_sender, stats_receiver) = mpsc::unbounded();
+                shards.relay_to_all(|| Event::Stats { stats: stats_sender.clone() });
+                drop(stats_sender);
+                let mut stats = collect(stats_receiver).await;
+                stats.sort_by_key(|stats| stats.shard);
+                AdminResponse::Stats(stats)
+            }
+            AdminCommand::Reload => {
+                // Reads the configuration file.
+                let reloader = Arc::clone(reloader);
+                match task::spawn_blocking(move || reloader.reload().map_err(|e| e.to_string())).await {
+                    Ok(()) => AdminResponse::Done,
+                    Err(e) => {
+                        error!("Not reloading configuration: {}", e);
+                        AdminResponse::Error(e)
+                    }
+                }
+            }
         }
     }
 
+    /// Disconnects user `name`. Returns where it connected from, or `None`
+    /// if it isn't connected here.
+    async fn kick(shards: &Shards, name: &str) -> Option<SocketAddr> {
+        let (kicked_sender, kicked_receiver) = oneshot::channel();
+        shards.relay(name, Event::Kick {
+            name: name.to_string(),
+            reason: String::from(KICK_REASON),
+            kicked: kicked_sender,
+        });
+
+        kicked_receiver.await.ok().flatten()
+    }
+
     fn spawn_and_log_error<F>(fut: F) -> task::JoinHandle<()>
         where
             F: Future<Output = Result<()>> + Send + 'static,
     {
         task::spawn(async move {
             if let Err(e) = fut.await {
-                eprintln!("{}", e)
+                warn!("{}", e)
             }
-        })
+        }.in_current_span())
     }
 
-    run();
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,273 @@
+//! Load generator for the servers. Opens many simulated clients over
+//! loopback, drives one traffic pattern for a while and reports throughput
+//! and end-to-end latency, see `chat_rs::bench`.
+
+use async_std::task;
+use chat_rs::bench::{self, Client, Load, Outcome};
+use chat_rs::config::Config;
+use chat_rs::protocol::{encode_frame, GroupId, Message, Recipient, ServerFrame, UserId};
+use std::error::Error;
+use std::io;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage: chat-bench PATTERN [OPTIONS] [ADDR | --spawn PROGRAM]
+
+Patterns:
+  fan-in                every client sends direct messages to the first one
+  broadcast             every client joins one group, --senders of them send to it
+  burst                 clients in pairs, each sends --burst messages at once, as often as --rate allows
+
+Options:
+  --clients N           simulated clients [1000]
+  --duration SECS       how long to send for [10]
+  --rate N              messages per second per sender, 0 sends as fast as the server takes them [10]
+  --size BYTES          text of each message [64]
+  --senders N           clients sending to the group in broadcast [10]
+  --burst N             messages per burst in burst [50]
+  --spawn PROGRAM       start this server on a free loopback port, and stop it afterwards
+
+Without --spawn, the server at ADDR or server.addr [127.0.0.1:8000] has to let
+every client in from one address and take messages as fast as they come, e.g.
+started with CHAT_CONNECTIONS_PER_IP=0 CHAT_CONNECTION_RATE=0 CHAT_MESSAGE_RATE=0
+CHAT_BYTE_RATE=0.
+";
+
+/// Group every client joins in `broadcast`.
+const GROUP: &str = "bench";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pattern {
+    FanIn,
+    Broadcast,
+    Burst,
+}
+
+#[derive(Debug, Clone)]
+struct Options {
+    pattern: Pattern,
+    clients: usize,
+    duration: Duration,
+    /// Messages per second per sender, 0 is unlimited.
+    rate: f64,
+    size: usize,
+    senders: usize,
+    burst: usize,
+    spawn: Option<String>,
+}
+
+impl Options {
+    /// Takes the options of this program out of `args` and leaves the rest
+    /// to `Config`. Returns them with the address of the server.
+    fn parse(args: Vec<String>) -> Result<(Options, String), Box<dyn Error>> {
+        let mut options = Options {
+            pattern: Pattern::FanIn,
+            clients: 1000,
+            duration: Duration::from_secs(10),
+            rate: 10.0,
+            size: 64,
+            senders: 10,
+            burst: 50,
+            spawn: None,
+        };
+        let mut rest = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg.clone(), None),
+            };
+            let known = ["--clients", "--duration", "--rate", "--size", "--senders", "--burst", "--spawn"];
+            if !known.contains(&name.as_str()) {
+                rest.push(arg);
+                continue;
+            }
+            let value = value.or_else(|| args.next()).ok_or_else(|| format!("{} needs a value", name))?;
+            let invalid = |e: &dyn std::fmt::Display| format!("{} {:?}: {}", name, value, e);
+            match name.as_str() {
+                "--clients" => options.clients = value.parse().map_err(|e| invalid(&e))?,
+                "--duration" => options.duration = Duration::from_secs(value.parse().map_err(|e| invalid(&e))?),
+                "--rate" => options.rate = value.parse().map_err(|e| invalid(&e))?,
+                "--size" => options.size = value.parse().map_err(|e| invalid(&e))?,
+                "--senders" => options.senders = value.parse().map_err(|e| invalid(&e))?,
+                "--burst" => options.burst = value.parse().map_err(|e| invalid(&e))?,
+                _ => options.spawn = Some(value),
+            }
+        }
+
+        let (config, words) = Config::with_positionals(rest, |var| std::env::var(var).ok())?;
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        let addr = match words.as_slice() {
+            [pattern] | [pattern, _] => {
+                options.pattern = match *pattern {
+                    "fan-in" => Pattern::FanIn,
+                    "broadcast" => Pattern::Broadcast,
+                    "burst" => Pattern::Burst,
+                    pattern => Err(format!("unknown pattern {:?}, see --help", pattern))?,
+                };
+                words.get(1)
+            }
+            [] => Err("no pattern given, see --help")?,
+            [_, _, arg, ..] => Err(format!("unexpected argument {:?}", arg))?,
+        };
+        let addr = addr.copied().or(config.get_str("server.addr")).unwrap_or("127.0.0.1:8000").to_string();
+
+        if options.clients < 2 {
+            Err("--clients: at least 2 are needed")?;
+        }
+        if !(options.rate >= 0.0 && options.rate.is_finite()) {
+            Err("--rate: must be 0 or positive")?;
+        }
+        options.senders = options.senders.clamp(1, options.clients);
+        options.burst = options.burst.max(1);
+
+        Ok((options, addr))
+    }
+
+    /// Who each client sends to, if anyone, by client index.
+    fn recipient(&self, client: usize) -> Option<Recipient> {
+        match self.pattern {
+            Pattern::FanIn if client > 0 => Some(Recipient::User(UserId(name(0)))),
+            Pattern::Broadcast if client < self.senders => Some(Recipient::Group(GroupId(GROUP.to_string()))),
+            Pattern::Burst if client.is_multiple_of(2) && client + 1 < self.clients => {
+                Some(Recipient::User(UserId(name(client + 1))))
+            }
+            _ => None,
+        }
+    }
+
+    /// How many clients receive each message.
+    fn fan_out(&self) -> usize {
+        match self.pattern {
+            Pattern::Broadcast => self.clients,
+            Pattern::FanIn | Pattern::Burst => 1,
+        }
+    }
+
+    /// Messages a sender writes at once.
+    fn batch(&self) -> usize {
+        match self.pattern {
+            Pattern::Burst => self.burst,
+            Pattern::FanIn | Pattern::Broadcast => 1,
+        }
+    }
+}
+
+fn name(client: usize) -> String {
+    format!("bench{}", client)
+}
+
+fn main() {
+    fn run() -> Result<(), Box<dyn Error>> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+            print!("{}", USAGE);
+            return Ok(());
+        }
+        let (options, addr) = Options::parse(args)?;
+        // A client takes a file here and one in a spawned server.
+        if let Some(limit) = bench::raise_file_limit(options.clients * 2 + 64) {
+            eprintln!("chat-bench: only {} files may be open, clients may fail to connect", limit);
+        }
+
+        let mut server = None;
+        let addr = match &options.spawn {
+            Some(program) => {
+                let addr = bench::free_addr()?;
+                server = Some(bench::spawn(Command::new(program), &addr)?);
+                addr
+            }
+            None => addr,
+        };
+
+        let res = task::block_on(bench(&addr, &options));
+        if let Some(mut server) = server {
+            let _ = server.kill();
+            let _ = server.wait();
+        }
+
+        res
+    }
+
+    if let Err(e) = run() {
+        eprintln!("chat-bench: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn bench(addr: &str, options: &Options) -> Result<(), Box<dyn Error>> {
+    let connecting = Instant::now();
+    let mut clients = Vec::with_capacity(options.clients);
+    for client in 0..options.clients {
+        let connected = connect(addr, &name(client), options.pattern == Pattern::Broadcast).await
+            .map_err(|e| format!("client {} of {} could not log in: {}", client + 1, options.clients, e))?;
+        clients.push(connected);
+    }
+    println!("{} clients logged in in {:.2}s", options.clients, connecting.elapsed().as_secs_f64());
+
+    let load = Load {
+        duration: Some(options.duration),
+        rate: options.rate,
+        batch: options.batch(),
+        size: options.size,
+        fan_out: options.fan_out(),
+        ..Load::default()
+    };
+    let outcome = bench::drive(clients, |client| options.recipient(client), &load).await;
+    report(options, &outcome);
+
+    Ok(())
+}
+
+/// Logs in as `name`, then joins `GROUP` if `joins`.
+async fn connect(addr: &str, name: &str, joins: bool) -> io::Result<Client> {
+    let mut client = Client::login(addr, name).await?;
+    if joins {
+        client.join(GROUP).await?;
+    }
+
+    Ok(client)
+}
+
+fn report(options: &Options, outcome: &Outcome) {
+    let pattern = match options.pattern {
+        Pattern::FanIn => "fan-in",
+        Pattern::Broadcast => "broadcast",
+        Pattern::Burst => "burst",
+    };
+    let frame_len = encode_frame(&ServerFrame::Message(Message {
+        from: UserId(name(options.clients)),
+        to: Recipient::User(UserId(name(options.clients))),
+        text: Some("x".repeat(options.size)),
+        media: Some(vec![0; 8]),
+    })).map_or(0, |frame| frame.len());
+
+    println!(
+        "{}: {} clients, {} bytes per message, {} per sender for {}s",
+        pattern,
+        options.clients,
+        options.size,
+        match options.rate {
+            0.0 => String::from("as fast as possible"),
+            rate => format!("{}/s", rate),
+        },
+        options.duration.as_secs(),
+    );
+    println!(
+        "sent {} messages, received {} of {} deliveries, {} errors, {} clients dropped",
+        outcome.sent, outcome.delivered, outcome.expected, outcome.errors, outcome.dropped,
+    );
+    println!(
+        "throughput: {:.0} messages/s, {:.0} deliveries/s, {:.1} MB/s delivered",
+        outcome.per_second(outcome.sent),
+        outcome.per_second(outcome.delivered),
+        outcome.per_second(outcome.delivered * frame_len) / 1e6,
+    );
+    let (Some(p50), Some(p99), Some(p999), Some(max)) =
+        (outcome.percentile(0.5), outcome.percentile(0.99), outcome.percentile(0.999), outcome.percentile(1.0))
+    else {
+        return;
+    };
+    println!("latency us: p50 {}  p99 {}  p999 {}  max {}", p50, p99, p999, max);
+}
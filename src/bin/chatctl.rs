@@ -0,0 +1,69 @@
+//! Operates a running server over its admin socket, see `chat_rs::admin`.
+//! Reads `admin.socket` and `admin.token` from the same settings as the
+//! server.
+
+use chat_rs::admin::{self, AdminResponse, AdminSettings};
+use chat_rs::config::Config;
+use std::error::Error;
+
+const USAGE: &str = "\
+Usage: chatctl [--config FILE] [--admin.socket PATH] [--admin.token TOKEN] COMMAND
+
+Commands:
+  users                 connected users, where they connect from and their queued frames
+  kick NAME             disconnect a user
+  ban NAME [SECS]       disconnect a user and refuse its address for a while [300]
+  announce TEXT...      send a notice to everyone connected
+  groups [NAME]         members of every group, or of one
+  stats                 queues and counts of each broker shard
+  reload                re-read the server's configuration, like SIGHUP
+
+Settings can also come from CHAT_CONFIG, CHAT_ADMIN_SOCKET and CHAT_ADMIN_TOKEN.
+";
+
+fn main() {
+    fn run() -> Result<(), Box<dyn Error>> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+            print!("{}", USAGE);
+            return Ok(());
+        }
+        let (config, words) = Config::with_positionals(args, |var| std::env::var(var).ok())?;
+        let admin = AdminSettings::from_config(&config)?
+            .ok_or("admin.socket is not set, see --help")?;
+        let command = admin::parse_command(&words)?;
+
+        match admin::call(&admin.socket, &admin.token, command)
+            .map_err(|e| format!("{}: {}", admin.socket.display(), e))?
+        {
+            AdminResponse::Users(users) => {
+                for user in users {
+                    println!("{:<20} {:<24} {:>6} queued", user.name, user.addr, user.queued);
+                }
+            }
+            AdminResponse::Groups(groups) => {
+                for group in groups {
+                    println!("#{}: {}", group.name, group.members.join(", "));
+                }
+            }
+            AdminResponse::Stats(shards) => {
+                println!("{:>5} {:>8} {:>6} {:>6} {:>5}", "shard", "queued", "users", "groups", "links");
+                for shard in shards {
+                    println!(
+                        "{:>5} {:>8} {:>6} {:>6} {:>5}",
+                        shard.shard, shard.queued_events, shard.users, shard.groups, shard.links,
+                    );
+                }
+            }
+            AdminResponse::Done => (),
+            AdminResponse::Error(e) => Err(e)?,
+        }
+
+        Ok(())
+    }
+
+    if let Err(e) = run() {
+        eprintln!("chatctl: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -1,8 +1,17 @@
-use std::io::{BufRead, BufReader, BufWriter, Stdout, Write};
-use std::net::TcpStream;
+use std::collections::{HashSet, VecDeque};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpStream};
 use std::thread;
-use bincode::{self, DefaultOptions, Serializer};
+use std::time::{Duration, Instant};
 
+use chat_rs::config::{Config, ConfigError};
+use chat_rs::direct::{DirectLinks, PeerEvent};
+use chat_rs::lan;
+use chat_rs::logging;
+use chat_rs::protocol::{
+    read_frame, write_frame, ClientFrame, GroupId, Message, Recipient, Request, ServerFrame, UserId,
+};
+use chat_rs::tls::ClientTls;
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
     execute,
@@ -20,26 +29,57 @@ use ratatui::{
     widgets::{Block, Borders, List, ListItem, Paragraph},
     Frame, Terminal,
 };
-use serde::{Deserialize, Serialize};
 use tui_input::backend::crossterm::EventHandler;
+use tracing::{debug, error, info, warn};
 use tui_input::Input;
+use rustls::ClientConnection;
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// Requests kept around to explain the server's errors.
+const REMEMBERED_REQUESTS: usize = 64;
+
+/// The connection to the server, over TLS if it is on.
+type ServerReader = BufReader<Box<dyn Read + Send>>;
+type ServerStream = BufWriter<Box<dyn Write + Send>>;
+
+/// Settings of the client, see `client.*` and `heartbeat.*` in the config.
+struct Settings {
+    /// In LAN mode there is no server, peers find each other via multicast
+    /// and every message goes over a direct link.
+    lan: bool,
+    /// Where direct links are accepted.
+    direct_addr: SocketAddr,
+    heartbeat: Heartbeat,
+    /// Set when the server is reached over TLS.
+    tls: Option<ClientTls>,
+}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct UserId(String);
-#[derive(Serialize, Deserialize, Debug)]
-struct GroupId(String);
-#[derive(Serialize, Deserialize, Debug)]
-enum Recepient {
-    User(UserId),
-    Group(GroupId)
+#[derive(Clone, Copy)]
+struct Heartbeat {
+    /// How often the server is pinged.
+    interval: Duration,
+    /// The server pings us as well, so silence for this long means it is
+    /// gone.
+    timeout: Duration,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Message {
-    from: UserId,
-    to: Recepient,
-    text: Option<String>,
-    media: Option<Vec<u8>>
+impl Settings {
+    fn from_config(config: &Config) -> Result<Settings, ConfigError> {
+        let settings = Settings {
+            lan: config.get("client.lan", false)?,
+            direct_addr: config.get("client.direct_addr", SocketAddr::from(([0, 0, 0, 0], 0)))?,
+            heartbeat: Heartbeat {
+                interval: config.secs("heartbeat.interval", 10)?,
+                timeout: config.secs("heartbeat.idle_timeout", 30)?,
+            },
+            tls: ClientTls::from_config(config, config.get_str("server.addr").unwrap_or("127.0.0.1:8000"))?,
+        };
+        if settings.heartbeat.interval.is_zero() || settings.heartbeat.interval >= settings.heartbeat.timeout {
+            return Err(config.invalid("heartbeat.interval", "must be positive and shorter than heartbeat.idle_timeout"));
+        }
+
+        Ok(settings)
+    }
 }
 
 enum InputMode {
@@ -54,6 +94,10 @@ struct App {
     input_mode: InputMode,
     /// History of recorded messages
     messages: Arc<Mutex<Vec<String>>>,
+    /// Nickname accepted by the server, `None` until logged in
+    name: Arc<Mutex<Option<String>>>,
+    /// Last measured round trip to the server
+    round_trip: Arc<Mutex<Option<Duration>>>,
 }
 
 impl Default for App {
@@ -62,11 +106,27 @@ impl Default for App {
             input: Input::default(),
             input_mode: InputMode::Normal,
             messages: Arc::new(Mutex::new(Vec::new())),
+            name: Arc::new(Mutex::new(None)),
+            round_trip: Arc::new(Mutex::new(None)),
         }
     }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
+    // The terminal belongs to the interface, log to a file.
+    let settings = Config::load().and_then(|config| {
+        logging::init(&config, Some("chat-client.log"))?;
+        Ok((Settings::from_config(&config)?, config))
+    });
+    let (settings, config) = match settings {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let addr = config.get_str("server.addr").unwrap_or("127.0.0.1:8000").to_string();
+
     // setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -76,7 +136,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     // create app and run it
     let app = App::default();
-    let res = run_app(&mut terminal, app);
+    let res = run_app(&mut terminal, app, &settings, &addr);
 
     // restore terminal
     disable_raw_mode()?;
@@ -88,33 +148,47 @@ fn main() -> Result<(), Box<dyn Error>> {
     terminal.show_cursor()?;
 
     if let Err(err) = res {
+        error!("{}", err);
         println!("{:?}", err)
     }
 
     Ok(())
 }
 
-fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Result<()> {
-    let (mut reader, mut writer) =
-        connect_to_server("");
-
-    let mut messages = Arc::clone(&app.messages);
-
-    thread::spawn(move || {
-        loop {
-            let mut line = String::new();
-
-            if reader.read_line(&mut line).unwrap() > 0 {
-                let l = line.chars().filter(|c| *c != '\n').collect::<String>();
-                messages.lock().unwrap().push(l);
-                line.clear();
+fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App, settings: &Settings, addr: &str) -> io::Result<()> {
+    let messages = Arc::clone(&app.messages);
+    let (direct, direct_addr) = DirectLinks::listen(settings.direct_addr, move |event| {
+        let line = match event {
+            PeerEvent::Connected(peer) => {
+                info!(%peer, "Direct link established");
+                format!("Direct link with {} established", peer)
             }
-        }
-    });
+            PeerEvent::Message(message) => {
+                let text = message.text.unwrap_or_default();
+                debug!(from = %message.from.0, text = %logging::contents(&text), "Direct message");
+                format!("{} (direct): {}", message.from.0, text)
+            }
+            PeerEvent::Disconnected(peer) => {
+                info!(%peer, "Direct link closed");
+                format!("Direct link with {} closed", peer)
+            }
+        };
+        messages.lock().unwrap().push(line);
+    })?;
+
+    let mut server = if settings.lan {
+        None
+    } else {
+        Some(ServerLink::connect(addr, &app, &direct, direct_addr, settings.heartbeat, settings.tls.clone())?)
+    };
 
     loop {
-        terminal.draw(|f| ui::<CrosstermBackend<Stdout>>(f, &app))?;
+        terminal.draw(|f| ui(f, &app))?;
 
+        // Redraw now and then for messages and round trips measured meanwhile.
+        if !event::poll(Duration::from_millis(250))? {
+            continue;
+        }
         if let Event::Key(key) = event::read()? {
             match app.input_mode {
                 InputMode::Normal => match key.code {
@@ -128,21 +202,17 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Result<(
                 },
                 InputMode::Editing => match key.code {
                     KeyCode::Enter => {
-                        // app.messages.push(app.input.value().into());
-
-                        let message = Message {
-                            from: UserId(String::from("Client")),
-                            to: Recepient::User(UserId(String::from("Server"))),
-                            text: Some(app.input.value().to_string()),
-                            media: None,
-                        };
-
-                        let ser_message = bincode::serialize(&message).unwrap();
-                        let len = ser_message.len() as u32;
-                        writer.write(&len.to_be_bytes())?;
-                        message.serialize(&mut Serializer::new(&mut writer, DefaultOptions::new())).unwrap();
-                        // writer.write(b"\n").unwrap();
-                        writer.flush().unwrap();
+                        let input = parse_input(app.input.value(), app.name.lock().unwrap().is_some());
+                        match (input, &mut server) {
+                            (Ok(frame), Some(server)) => {
+                                if let Err(e) = server.submit(&app, &direct, frame) {
+                                    warn!("Could not send: {}", e);
+                                    app.messages.lock().unwrap().push(format!("Could not send: {}", e));
+                                }
+                            }
+                            (Ok(frame), None) => submit_lan(&app, &direct, direct_addr.port(), frame)?,
+                            (Err(hint), _) => app.messages.lock().unwrap().push(hint),
+                        }
                         app.input.reset();
                     }
                     KeyCode::Esc => {
@@ -157,7 +227,385 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Result<(
     }
 }
 
-fn ui<B: Backend>(f: &mut Frame, app: &App) {
+/// Numbers requests to the server and remembers the recent ones, so that
+/// errors the server answers with can be explained.
+struct ServerWriter {
+    /// Replaced by the reader thread after reconnecting.
+    writer: ServerStream,
+    next_id: u64,
+    sent: VecDeque<(u64, String)>,
+}
+
+impl ServerWriter {
+    fn new(writer: ServerStream) -> ServerWriter {
+        ServerWriter { writer, next_id: 1, sent: VecDeque::new() }
+    }
+
+    fn send(&mut self, frame: ClientFrame) -> io::Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+        if !frame.is_heartbeat() {
+            if self.sent.len() == REMEMBERED_REQUESTS {
+                self.sent.pop_front();
+            }
+            self.sent.push_back((id, describe(&frame)));
+        }
+
+        write_frame(&mut self.writer, &Request { id, frame })
+    }
+
+    /// What request `id` was, if it is recent enough.
+    fn describe(&self, id: u64) -> Option<&str> {
+        self.sent.iter().find(|(sent, _)| *sent == id).map(|(_, request)| request.as_str())
+    }
+}
+
+/// Connection to the chat server, re-established whenever it drops.
+struct ServerLink {
+    writer: Arc<Mutex<ServerWriter>>,
+    /// Where we accept direct links, announced to the server on login.
+    direct_addrs: Vec<SocketAddr>,
+    /// Groups to join again after reconnecting.
+    groups: Arc<Mutex<HashSet<String>>>,
+    /// Peers we already asked the server for direct link candidates.
+    rendezvous: HashSet<String>,
+}
+
+impl ServerLink {
+    fn connect(
+        addr: &str,
+        app: &App,
+        direct: &DirectLinks,
+        direct_addr: SocketAddr,
+        heartbeat: Heartbeat,
+        tls: Option<ClientTls>,
+    ) -> io::Result<ServerLink> {
+        let (mut reader, writer, local_addr) = connect_to_server(addr, heartbeat.timeout, tls.as_ref())?;
+        info!(%addr, "Connected to server");
+
+        // Unless bound to one address, accept direct links on the interface
+        // we reach the server through. The server hands this address out on
+        // rendezvous.
+        let direct_addrs = match direct_addr.ip().is_unspecified() {
+            true => vec![SocketAddr::new(local_addr.ip(), direct_addr.port())],
+            false => vec![direct_addr],
+        };
+        let writer = Arc::new(Mutex::new(ServerWriter::new(writer)));
+        let groups: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+
+        let messages = Arc::clone(&app.messages);
+        let name = Arc::clone(&app.name);
+        let links = direct.clone();
+        let server = Arc::clone(&writer);
+        let joined = Arc::clone(&groups);
+        let login_addrs = direct_addrs.clone();
+        let round_trip = Arc::clone(&app.round_trip);
+        let addr = addr.to_string();
+        // Ping tokens are microseconds since we started.
+        let started = Instant::now();
+
+        let pinger = Arc::clone(&writer);
+        thread::spawn(move || loop {
+            thread::sleep(heartbeat.interval);
+            let ping = ClientFrame::Ping { token: started.elapsed().as_micros() as u64 };
+            // Fails while reconnecting, the next ping goes to the new connection.
+            let _ = pinger.lock().unwrap().send(ping);
+        });
+
+        thread::spawn(move || loop {
+            let reason = loop {
+                let frame = match read_frame::<_, ServerFrame>(&mut reader) {
+                    Ok(Some(frame)) => frame,
+                    Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                        break "Server stopped responding";
+                    }
+                    Ok(None) | Err(_) => break "Disconnected from server",
+                };
+                debug!(frame = frame.kind(), "Received frame");
+                let line = match frame {
+                    ServerFrame::Welcome { name: accepted } => {
+                        info!(name = %accepted, "Logged in");
+                        *name.lock().unwrap() = Some(accepted.clone());
+                        let mut server = server.lock().unwrap();
+                        for group in joined.lock().unwrap().iter() {
+                            let _ = server.send(ClientFrame::Join { group: group.clone() });
+                        }
+                        format!("Logged in as {}", accepted)
+                    }
+                    ServerFrame::NameTaken { name: taken } => {
+                        format!("Nickname {} is taken, choose another", taken)
+                    }
+                    ServerFrame::NickAccepted { name: accepted, .. } => {
+                        info!(name = %accepted, "Renamed");
+                        *name.lock().unwrap() = Some(accepted.clone());
+                        format!("You are now known as {}", accepted)
+                    }
+                    ServerFrame::Renamed { old, new } => {
+                        links.rename(&old, &new);
+                        format!("{} is now known as {}", old, new)
+                    }
+                    ServerFrame::Message(message) => match message.to {
+                        Recipient::Group(GroupId(group)) => format!(
+                            "{} in #{}: {}",
+                            message.from.0,
+                            group,
+                            message.text.unwrap_or_default()
+                        ),
+                        Recipient::User(_) => {
+                            format!("{}: {}", message.from.0, message.text.unwrap_or_default())
+                        }
+                    },
+                    ServerFrame::Candidates { name: peer, addrs } => {
+                        let me = name.lock().unwrap().clone().unwrap_or_default();
+                        links.expect(&peer, &addrs);
+                        // Both ends get candidates, like on the local network
+                        // only the smaller name dials.
+                        if peer <= me {
+                            continue;
+                        }
+                        let links = links.clone();
+                        let messages = Arc::clone(&messages);
+                        // Connecting may time out, don't hold up server frames meanwhile.
+                        thread::spawn(move || {
+                            if let Err(e) = links.connect(&me, &peer, &addrs) {
+                                warn!(%peer, "Direct link failed: {}", e);
+                                messages.lock().unwrap().push(format!(
+                                    "Direct link with {} failed ({}), relaying through server",
+                                    peer, e
+                                ));
+                            }
+                        });
+                        continue;
+                    }
+                    ServerFrame::Shutdown { reason } => {
+                        info!(%reason, "Server closing the connection");
+                        format!("Server closing the connection: {}", reason)
+                    }
+                    ServerFrame::Error { request, error } => {
+                        warn!(?request, %error, "Server refused request");
+                        let server = server.lock().unwrap();
+                        match request.and_then(|id| server.describe(id)) {
+                            Some(request) => format!("Failed {}: {}", request, error),
+                            None => format!("Server error: {}", error),
+                        }
+                    }
+                    ServerFrame::Announcement { text } => format!("Announcement: {}", text),
+                    ServerFrame::Ping { token } => {
+                        let _ = server.lock().unwrap().send(ClientFrame::Pong { token });
+                        continue;
+                    }
+                    ServerFrame::Pong { token } => {
+                        let elapsed = started.elapsed().saturating_sub(Duration::from_micros(token));
+                        *round_trip.lock().unwrap() = Some(elapsed);
+                        continue;
+                    }
+                };
+                messages.lock().unwrap().push(line);
+            };
+            *round_trip.lock().unwrap() = None;
+            warn!(reason, "Lost the server, reconnecting");
+            messages.lock().unwrap().push(format!("{}, reconnecting...", reason));
+
+            let mut delay = Duration::from_secs(1);
+            reader = loop {
+                thread::sleep(delay);
+                match connect_to_server(&addr, heartbeat.timeout, tls.as_ref()) {
+                    Ok((reader, writer, _)) => {
+                        info!(%addr, "Reconnected to server");
+                        server.lock().unwrap().writer = writer;
+                        break reader;
+                    }
+                    Err(e) => {
+                        debug!(?delay, "Could not reconnect: {}", e);
+                        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    }
+                }
+            };
+
+            // Log in again under the last accepted name, until the server
+            // welcomes us the input box asks for a nickname.
+            let last_name = name.lock().unwrap().take();
+            let line = match last_name {
+                Some(last_name) => {
+                    let login = ClientFrame::Login { name: last_name, direct_addrs: login_addrs.clone() };
+                    let _ = server.lock().unwrap().send(login);
+                    String::from("Reconnected to server")
+                }
+                None => String::from("Reconnected to server, choose a nickname"),
+            };
+            messages.lock().unwrap().push(line);
+        });
+
+        Ok(ServerLink {
+            writer,
+            direct_addrs,
+            groups,
+            rendezvous: HashSet::new(),
+        })
+    }
+
+    fn submit(&mut self, app: &App, direct: &DirectLinks, frame: ClientFrame) -> io::Result<()> {
+        match frame {
+            ClientFrame::Login { name, .. } => {
+                let login = ClientFrame::Login { name, direct_addrs: self.direct_addrs.clone() };
+                self.writer.lock().unwrap().send(login)
+            }
+            ClientFrame::Message(message) => send_message(
+                &self.writer,
+                direct,
+                &mut self.rendezvous,
+                app.name.lock().unwrap().clone().unwrap_or_default(),
+                message,
+            ),
+            frame => {
+                match &frame {
+                    ClientFrame::Join { group } => self.groups.lock().unwrap().insert(group.clone()),
+                    ClientFrame::Leave { group } => self.groups.lock().unwrap().remove(group),
+                    _ => false,
+                };
+                self.writer.lock().unwrap().send(frame)
+            }
+        }
+    }
+}
+
+/// Handles input in LAN mode, where only direct links are available.
+fn submit_lan(app: &App, direct: &DirectLinks, direct_port: u16, frame: ClientFrame) -> io::Result<()> {
+    let line = match frame {
+        ClientFrame::Login { name, .. } => {
+            lan::discover(name.clone(), direct_port, direct.clone())?;
+            *app.name.lock().unwrap() = Some(name.clone());
+            format!("Looking for peers on the local network as {}", name)
+        }
+        ClientFrame::Message(message) => match message.to.clone() {
+            Recipient::User(UserId(peer)) => {
+                let me = app.name.lock().unwrap().clone().unwrap_or_default();
+                if direct.send(&peer, Message { from: UserId(me), ..message }) {
+                    return Ok(());
+                }
+                format!("{} is not on the local network", peer)
+            }
+            Recipient::Group(GroupId(group)) => {
+                format!("Groups are not available in LAN mode, nothing sent to #{}", group)
+            }
+        },
+        ClientFrame::Nick { .. }
+        | ClientFrame::Rendezvous { .. }
+        | ClientFrame::Join { .. }
+        | ClientFrame::Leave { .. }
+        | ClientFrame::Ping { .. }
+        | ClientFrame::Pong { .. } => {
+            String::from("Not available in LAN mode")
+        }
+    };
+    app.messages.lock().unwrap().push(line);
+
+    Ok(())
+}
+
+/// Turns the input box contents into a frame for the server. Until the
+/// server accepts a nickname everything typed is treated as one.
+fn parse_input(input: &str, logged_in: bool) -> Result<ClientFrame, String> {
+    let input = input.trim();
+
+    if !logged_in {
+        return match input {
+            "" => Err(String::from("Nickname can't be empty")),
+            name => Ok(ClientFrame::Login {
+                name: name.to_string(),
+                // Filled in by the caller.
+                direct_addrs: Vec::new(),
+            }),
+        };
+    }
+
+    if let Some(name) = input.strip_prefix("/nick") {
+        return match name.trim() {
+            "" => Err(String::from("Usage: /nick <new name>")),
+            name => Ok(ClientFrame::Nick { name: name.to_string() }),
+        };
+    }
+
+    if let Some(group) = input.strip_prefix("/join") {
+        return match group.trim().trim_start_matches('#') {
+            "" => Err(String::from("Usage: /join <group>")),
+            group => Ok(ClientFrame::Join { group: group.to_string() }),
+        };
+    }
+
+    if let Some(group) = input.strip_prefix("/leave") {
+        return match group.trim().trim_start_matches('#') {
+            "" => Err(String::from("Usage: /leave <group>")),
+            group => Ok(ClientFrame::Leave { group: group.to_string() }),
+        };
+    }
+
+    // Users and groups on other servers are addressed as `name@server`.
+    let (to, text) = match input.split_once(':') {
+        Some((to, text)) if !to.trim().is_empty() => match to.trim().strip_prefix('#') {
+            Some(group) => (Recipient::Group(GroupId(group.to_string())), text),
+            None => (Recipient::User(UserId(to.trim().to_string())), text),
+        },
+        _ => return Err(String::from(
+            "Usage: <user>: <message>, #<group>: <message>, /join, /leave or /nick",
+        )),
+    };
+
+    Ok(ClientFrame::Message(Message {
+        // Filled in by the server.
+        from: UserId(String::new()),
+        to,
+        text: Some(text.trim().to_string()),
+        media: None,
+    }))
+}
+
+/// Sends a direct message over a direct link if there is one, otherwise
+/// relays it through the server and asks for the recipient's direct link
+/// candidates so that later messages can skip the server.
+fn send_message(
+    server: &Mutex<ServerWriter>,
+    direct: &DirectLinks,
+    rendezvous: &mut HashSet<String>,
+    me: String,
+    message: Message,
+) -> io::Result<()> {
+    let peer = match &message.to {
+        Recipient::User(UserId(peer)) => peer.clone(),
+        Recipient::Group(_) => return server.lock().unwrap().send(ClientFrame::Message(message)),
+    };
+
+    // The server writer isn't held meanwhile, a peer that stopped reading
+    // mustn't hold up pings and pongs.
+    let direct_message = Message { from: UserId(me), ..message.clone() };
+    if direct.send(&peer, direct_message) {
+        return Ok(());
+    }
+
+    let mut server = server.lock().unwrap();
+    server.send(ClientFrame::Message(message))?;
+    if !direct.is_connected(&peer) && rendezvous.insert(peer.clone()) {
+        server.send(ClientFrame::Rendezvous { with: peer })?;
+    }
+
+    Ok(())
+}
+
+/// Short description of a request, for error messages.
+fn describe(frame: &ClientFrame) -> String {
+    match frame {
+        ClientFrame::Login { name, .. } => format!("logging in as {}", name),
+        ClientFrame::Nick { name } => format!("renaming to {}", name),
+        ClientFrame::Message(Message { to: Recipient::User(UserId(to)), .. }) => format!("message to {}", to),
+        ClientFrame::Message(Message { to: Recipient::Group(GroupId(to)), .. }) => format!("message to #{}", to),
+        ClientFrame::Rendezvous { with } => format!("looking up {}", with),
+        ClientFrame::Join { group } => format!("joining #{}", group),
+        ClientFrame::Leave { group } => format!("leaving #{}", group),
+        ClientFrame::Ping { .. } | ClientFrame::Pong { .. } => String::from("heartbeat"),
+    }
+}
+
+fn ui(f: &mut Frame, app: &App) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .margin(2)
@@ -195,6 +643,9 @@ fn ui<B: Backend>(f: &mut Frame, app: &App) {
     };
     let mut text = Text::from(Line::from(msg));
     text = text.patch_style(style);
+    if let Some(round_trip) = *app.round_trip.lock().unwrap() {
+        text.lines[0].spans.push(Span::raw(format!(" Round trip: {} ms", round_trip.as_millis())));
+    }
     let help_message = Paragraph::new(text);
     f.render_widget(help_message, chunks[0]);
 
@@ -207,7 +658,10 @@ fn ui<B: Backend>(f: &mut Frame, app: &App) {
             InputMode::Editing => Style::default().fg(Color::Yellow),
         })
         .scroll((0, scroll as u16))
-        .block(Block::default().borders(Borders::ALL).title("Input"));
+        .block(Block::default().borders(Borders::ALL).title(match &*app.name.lock().unwrap() {
+            Some(name) => format!("Input ({})", name),
+            None => String::from("Nickname"),
+        }));
     f.render_widget(input, chunks[1]);
     match app.input_mode {
         InputMode::Normal =>
@@ -241,10 +695,86 @@ fn ui<B: Backend>(f: &mut Frame, app: &App) {
     f.render_widget(messages, chunks[2]);
 }
 
-fn connect_to_server(addr: &str) -> (BufReader<TcpStream>, BufWriter<TcpStream>) {
-    let mut server_socket = TcpStream::connect("127.0.0.1:8000").unwrap();
-    let mut bufwriter = BufWriter::new(server_socket.try_clone().unwrap());
-    let mut bufreader = BufReader::new(server_socket);
+/// Connects to the server, over TLS if `tls` is set. Reads fail once it
+/// has been silent for `timeout`. Also returns our end's address.
+fn connect_to_server(
+    addr: &str,
+    timeout: Duration,
+    tls: Option<&ClientTls>,
+) -> io::Result<(ServerReader, ServerStream, SocketAddr)> {
+    let server_socket = TcpStream::connect(addr)?;
+    server_socket.set_read_timeout(Some(timeout))?;
+    let local_addr = server_socket.local_addr()?;
+    let Some(tls) = tls else {
+        let bufwriter = BufWriter::new(Box::new(server_socket.try_clone()?) as Box<dyn Write + Send>);
+        let bufreader = BufReader::new(Box::new(server_socket) as Box<dyn Read + Send>);
+        return Ok((bufreader, bufwriter, local_addr));
+    };
+
+    let mut connection = ClientConnection::new(Arc::clone(&tls.config), tls.server_name.clone())
+        .map_err(io::Error::other)?;
+    let mut socket = server_socket;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut socket)?;
+    }
+    let connection = Arc::new(Mutex::new(connection));
+    let writer = TlsHalf { connection: Arc::clone(&connection), socket: socket.try_clone()? };
+    let reader = TlsHalf { connection, socket };
+
+    Ok((BufReader::new(Box::new(reader)), BufWriter::new(Box::new(writer)), local_addr))
+}
+
+/// One end of a TLS connection, shared by the thread reading from the
+/// server and those writing to it. Neither holds the lock while waiting for
+/// the socket.
+struct TlsHalf {
+    connection: Arc<Mutex<ClientConnection>>,
+    socket: TcpStream,
+}
+
+impl Read for TlsHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut connection = self.connection.lock().unwrap();
+            match connection.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+            drop(connection);
 
-    (bufreader, bufwriter)
+            // Nothing decrypted is left, wait for more from the server.
+            // Reading nothing tells the connection the server hung up.
+            let mut incoming = [0u8; 4096];
+            let len = self.socket.read(&mut incoming)?;
+            let mut incoming = &incoming[..len];
+            let mut connection = self.connection.lock().unwrap();
+            loop {
+                connection.read_tls(&mut incoming)?;
+                connection.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if incoming.is_empty() {
+                    break;
+                }
+            }
+            // Alerts and key updates are answered right away.
+            while connection.wants_write() {
+                connection.write_tls(&mut self.socket)?;
+            }
+        }
+    }
+}
+
+impl Write for TlsHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = self.connection.lock().unwrap();
+        let len = connection.writer().write(buf)?;
+        while connection.wants_write() {
+            connection.write_tls(&mut self.socket)?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
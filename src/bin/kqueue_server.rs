@@ -1,283 +1,701 @@
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::os::fd::{AsFd, AsRawFd};
-use std::ptr::null_mut;
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+//! Broadcast server built on the OS readiness queue, kqueue on BSD and
+//! macOS and epoll on Linux. See `chat_rs::poller`.
+//!
+//! A single thread runs the reactor: it accepts clients, reads their
+//! requests, routes them and writes them out as sockets become ready. With
+//! `server.workers` set, decoding requests moves to a pool of threads.
+//! That only helps when decoding is a large part of the work, e.g. with
+//! media attached, and there are cores to spare. For small messages the
+//! handoff costs more than the decoding, see `benches/event_loop.rs`.
+//!
+//! Requests are routed by `chat_rs::server::ChatServer`, so clients speak
+//! the protocol of `async_std_server`, without federation, rate limits or
+//! heartbeats.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::thread;
-use libc::{kevent, kqueue};
-
-#[macro_export]
-macro_rules! kevents {
-    ( $kq:expr, $eq:expr ) => {
-        {
-           let e = unsafe {
-                libc::kevent(
-                    $kq as libc::c_int,
-                    null_mut(),
-                    0,
-                    $eq.as_mut_ptr(),
-                    256,
-                    null_mut()
-                )
-            };
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span, trace, warn, Span};
+use chat_rs::config::Config;
+use chat_rs::logging;
+use chat_rs::poller::{DefaultPoller, Event, Interest, Poller, Token};
+use chat_rs::protocol::{decode_frame, encode_frame, Request, MAX_FRAME_LEN};
+use chat_rs::server::{self, ChatServer, ConnectionId, Output};
+
+type SocketRawFileDescriptor = usize;
+
+/// Token of the listening socket. Clients are registered with their
+/// descriptor as token.
+const LISTENER: Token = usize::MAX;
+/// Token of the socket workers wake the reactor with.
+const WAKER: Token = usize::MAX - 1;
+/// Bytes queued for a client before it is dropped as too slow. Always
+/// fits a message of `MAX_FRAME_LEN`.
+const MAX_OUTBOUND_LEN: usize = 2 * MAX_FRAME_LEN;
+/// Bytes read from one client per event, so that a fast sender can't keep
+/// the others waiting. The rest is reported again on the next wait.
+const MAX_READ_PER_EVENT: usize = 64 * 1024;
+/// Connections accepted per event, so that a burst of them can't keep the
+/// clients waiting. The rest is reported again on the next wait.
+const MAX_ACCEPTS_PER_EVENT: usize = 128;
+/// How long to stop accepting when out of file descriptors, unless a
+/// client leaves first.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A connected client and what is in flight to and from it.
+struct Client {
+    socket: TcpStream,
+    ip: IpAddr,
+    /// Tells this connection from earlier ones on the same descriptor.
+    connection: u64,
+    /// Bytes read that don't make up a whole message yet.
+    inbound: Vec<u8>,
+    /// Bytes the socket didn't take yet.
+    outbound: VecDeque<u8>,
+    /// Nothing more is read, the client is dropped once `outbound` is
+    /// written.
+    closing: bool,
+    /// Requests of this client the workers haven't handed back yet.
+    jobs: usize,
+    /// The client hung up while `jobs` were out. Its socket isn't watched
+    /// anymore and it is dropped once they are handled.
+    hung_up: bool,
+    /// Length of a frame over `MAX_FRAME_LEN` the client announced. Nothing
+    /// is read after it, and the `ChatServer` hears about it once the
+    /// `jobs` before it are handled.
+    too_large: Option<usize>,
+}
+
+impl Client {
+    fn new(socket: TcpStream, ip: IpAddr, connection: u64) -> Client {
+        Client {
+            socket,
+            ip,
+            connection,
+            inbound: Vec::new(),
+            outbound: VecDeque::new(),
+            closing: false,
+            jobs: 0,
+            hung_up: false,
+            too_large: None,
+        }
+    }
+
+    /// Whether requests are still read from the socket.
+    fn reading(&self) -> bool {
+        !self.closing && self.too_large.is_none()
+    }
+
+    /// What to watch the socket for while frames are queued.
+    fn flush_interest(&self) -> Interest {
+        Interest { readable: self.reading(), writable: true }
+    }
+
+    /// What to watch the socket for once the queue is written.
+    fn idle_interest(&self) -> Interest {
+        Interest { readable: self.reading(), writable: false }
+    }
 
-            if e == -1 {
-                panic!("Can't fetch events from kqueue: {}", std::io::Error::last_os_error());
+    /// Reads what the socket has without blocking and takes the payloads of
+    /// the complete frames out of it. Returns them, the length of a frame
+    /// over `MAX_FRAME_LEN` if one follows them, and whether the peer
+    /// closed the connection. Nothing after a frame over the limit can be
+    /// read.
+    fn read_messages(&mut self) -> io::Result<(Vec<Vec<u8>>, Option<usize>, bool)> {
+        let mut buf = [0u8; 4096];
+        let mut read = 0;
+        let mut closed = false;
+        while read < MAX_READ_PER_EVENT {
+            match self.socket.read(&mut buf) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(n) => {
+                    self.inbound.extend_from_slice(&buf[..n]);
+                    read += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
             }
+        }
 
-            unsafe {
-                 $eq.set_len(e as usize);
+        let mut messages = Vec::new();
+        let mut start = 0;
+        let mut too_large = None;
+        while let Some(len_buf) = self.inbound.get(start..start + 4) {
+            let len = u32::from_be_bytes(len_buf.try_into().unwrap()) as usize;
+            if len > MAX_FRAME_LEN {
+                too_large = Some(len);
+                break;
             }
+            let Some(message) = self.inbound.get(start + 4..start + 4 + len) else {
+                break;
+            };
+            messages.push(message.to_vec());
+            start += 4 + len;
+        }
+        self.inbound.drain(..start);
 
-            e
+        Ok((messages, too_large, closed))
+    }
+
+    /// Queues the encoded `frame` behind what is already waiting. Returns
+    /// false if that is more than the client may have queued.
+    fn queue(&mut self, frame: &[u8]) -> bool {
+        if self.outbound.len() + frame.len() > MAX_OUTBOUND_LEN {
+            return false;
         }
+        self.outbound.extend(frame);
 
+        true
     }
-}
 
-type SocketRawFileDescriptor = usize;
+    /// Writes as much of the queue as the socket takes without blocking.
+    /// Returns whether the queue is empty.
+    fn flush(&mut self) -> io::Result<bool> {
+        while !self.outbound.is_empty() {
+            let (front, _) = self.outbound.as_slices();
+            match self.socket.write(front) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.outbound.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
 
-struct Message {
-    pub from: SocketRawFileDescriptor,
-    pub to: Option<SocketRawFileDescriptor>,
-    pub message: String,
+        Ok(self.outbound.is_empty())
+    }
 }
 
-enum BroadcastRequest {
-    AddSocket(TcpStream),
-    RemoveSocket(SocketRawFileDescriptor),
-    BroadcastMessage(SocketRawFileDescriptor)
+/// A frame read from the client on `from`, decoded into `request` by a
+/// worker.
+struct Job<T> {
+    from: SocketRawFileDescriptor,
+    connection: u64,
+    request: T,
 }
 
-enum BroadcastResponse {
-    MessageAcknowledged(SocketRawFileDescriptor)
+/// Threads that decode requests off the reactor.
+struct Workers {
+    /// One queue per worker. A client's requests all go to the same one,
+    /// so that they are handled in the order they were sent.
+    jobs: Vec<Sender<Job<Vec<u8>>>>,
+    done: Receiver<Job<io::Result<Request>>>,
+    /// Readable once a job is done.
+    wake: UnixStream,
 }
 
-fn main() {
-    let (
-        sender,
-        receiver
-    ) = mpsc::channel::<BroadcastRequest>();
+impl Workers {
+    fn spawn(count: usize) -> io::Result<Workers> {
+        let (wake, waker) = UnixStream::pair()?;
+        wake.set_nonblocking(true)?;
+        waker.set_nonblocking(true)?;
+        let (done_sender, done) = mpsc::channel();
+
+        let jobs = (0..count)
+            .map(|worker| {
+                let (sender, receiver) = mpsc::channel::<Job<Vec<u8>>>();
+                let done = done_sender.clone();
+                let mut waker = waker.try_clone()?;
+                thread::Builder::new().name(format!("worker-{}", worker)).spawn(move || {
+                    for job in receiver {
+                        let job = Job { from: job.from, connection: job.connection, request: prepare(&job.request) };
+                        if done.send(job).is_err() {
+                            break;
+                        }
+                        // A full socket wakes the reactor just as well.
+                        let _ = waker.write(&[1]);
+                    }
+                })?;
 
-    let (
-        ack_sender,
-        ack_receiver
-    ) = mpsc::channel::<BroadcastResponse>();
+                Ok(sender)
+            })
+            .collect::<io::Result<_>>()?;
 
-    let kq_fd = unsafe { kqueue() } as usize;
+        Ok(Workers { jobs, done, wake })
+    }
 
-    spawn_kqueue_thread(kq_fd, sender.clone(), ack_receiver);
-    spawn_broadcast_thread(kq_fd, receiver, ack_sender);
-    accept_clients(sender);
+    fn submit(&self, job: Job<Vec<u8>>) {
+        let worker = job.from % self.jobs.len();
+        if self.jobs[worker].send(job).is_err() {
+            error!(worker, "Worker is gone, dropping request");
+        }
+    }
 }
 
-fn spawn_kqueue_thread(
-    kq_fd: usize,
-    sender: Sender<BroadcastRequest>,
-    ack_receiver: Receiver<BroadcastResponse>
-) {
-    thread::spawn(move || {
-        let mut event_queue = Vec::<libc::kevent>::with_capacity(256);
+/// Turns a frame read from a client into a request. This is where
+/// CPU-heavy work on requests belongs, it runs on a worker when there are
+/// any.
+fn prepare(payload: &[u8]) -> io::Result<Request> {
+    decode_frame(payload)
+}
+
+/// Everything the event loop owns.
+struct Reactor {
+    poller: DefaultPoller,
+    listener: TcpListener,
+    // Map raw socket file descriptor to the client on that socket.
+    clients: HashMap<SocketRawFileDescriptor, Client>,
+    /// Routes the requests. Clients are known to it by descriptor.
+    server: ChatServer,
+    /// Connections accepted so far, numbers the next one.
+    connections: u64,
+    /// Clients with messages queued since the last wait. Writing once per
+    /// batch of events saves a system call per message and client.
+    unflushed: Vec<SocketRawFileDescriptor>,
+    workers: Option<Workers>,
+    /// Clients connected from each address.
+    per_ip: HashMap<IpAddr, usize>,
+    /// Most clients one address may have connected, 0 is unlimited.
+    max_per_ip: usize,
+    /// Set while the listener is out of the poller because descriptors ran
+    /// out, to when accepting resumes.
+    accept_resumes: Option<Instant>,
+}
+
+impl Reactor {
+    fn new(listener: TcpListener, workers: Option<Workers>, max_per_ip: usize) -> io::Result<Reactor> {
+        let poller = DefaultPoller::new()?;
+        listener.set_nonblocking(true)?;
+        poller.register(listener.as_raw_fd(), LISTENER, Interest::READABLE)?;
+        if let Some(workers) = &workers {
+            poller.register(workers.wake.as_raw_fd(), WAKER, Interest::READABLE)?;
+        }
+
+        Ok(Reactor {
+            poller,
+            listener,
+            clients: HashMap::new(),
+            server: ChatServer::new(),
+            connections: 0,
+            unflushed: Vec::new(),
+            workers,
+            per_ip: HashMap::new(),
+            max_per_ip,
+            accept_resumes: None,
+        })
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        let mut events = Vec::new();
 
         loop {
-            let n = kevents!(kq_fd as libc::c_int, event_queue);
-            // let n = unsafe {
-            //     libc::kevent(
-            //         kq_fd as libc::c_int,
-            //         null_mut(),
-            //         0,
-            //         event_queue.as_mut_ptr(),
-            //         256,
-            //         null_mut()
-            //     )
-            // };
-
-            // if n == -1 {
-            //     panic!("Can't fetch events from kqueue: {}", std::io::Error::last_os_error());
-            // }
-
-            // Vector length is not updated automatically in unsafe mode, so we do it manually.
-            // unsafe {
-            //     event_queue.set_len(n as usize);
-            // }
-
-            while let event = event_queue.pop() {
-                match event {
-                    Some(k) => {
-                        if k.flags as i16 & libc::EVFILT_READ != 0 {
-                            sender.send(BroadcastRequest::BroadcastMessage(k.ident)).unwrap();
-                        }
+            let timeout = self.accept_resumes.map(|at| at.saturating_duration_since(Instant::now()));
+            self.poller.wait(&mut events, timeout)?;
+
+            for event in &events {
+                match event.token {
+                    LISTENER => self.accept_clients(),
+                    WAKER => self.finish_jobs(),
+                    fd => self.client_ready(fd, event),
+                }
+            }
+            self.flush_queued();
+            if self.accept_resumes.is_some_and(|at| at <= Instant::now()) {
+                self.resume_accepting();
+            }
+        }
+    }
 
-                        if k.flags & libc::EV_EOF != 0 {
-                            sender.send(BroadcastRequest::RemoveSocket(k.ident)).unwrap()
-                        }
+    /// Accepts the pending connections, up to `MAX_ACCEPTS_PER_EVENT`.
+    fn accept_clients(&mut self) {
+        for _ in 0..MAX_ACCEPTS_PER_EVENT {
+            match self.listener.accept() {
+                Ok((socket, addr)) => self.add_client(socket, addr),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) => {
+                    self.pause_accepting(e);
+                    return;
+                }
+                // The connection failed before it was accepted, the next
+                // one may not.
+                Err(e) => warn!("Some client could not connect: {}", e),
+            }
+        }
+    }
 
-                        match ack_receiver.recv() {
-                            Ok(res) => {
-                                match res {
-                                    BroadcastResponse::MessageAcknowledged(fd) => {
-                                        if fd != k.ident {
-                                            println!("Wrong file descriptor acknowledgement received!");
-
-                                            continue;
-                                        }
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                println!("Could not acknowledge message because of error: {}", e);
-
-                                continue;
-                            }
-                        }
-                    },
-                    None => {}
+    /// Takes the listener out of the poller for `ACCEPT_BACKOFF`. The
+    /// pending connection can't be accepted and would be reported ready
+    /// over and over.
+    fn pause_accepting(&mut self, e: io::Error) {
+        warn!(backoff = ?ACCEPT_BACKOFF, "Not accepting clients for now: {}", e);
+        if let Err(e) = self.poller.deregister(self.listener.as_raw_fd()) {
+            error!("Could not deregister listener from the poller: {}", e);
+        }
+        self.accept_resumes = Some(Instant::now() + ACCEPT_BACKOFF);
+    }
+
+    fn resume_accepting(&mut self) {
+        match self.poller.register(self.listener.as_raw_fd(), LISTENER, Interest::READABLE) {
+            Ok(()) => {
+                info!("Accepting clients again");
+                self.accept_resumes = None;
+            }
+            Err(e) => {
+                error!("Could not register listener in the poller: {}", e);
+                self.accept_resumes = Some(Instant::now() + ACCEPT_BACKOFF);
+            }
+        }
+    }
+
+    fn add_client(&mut self, socket: TcpStream, addr: SocketAddr) {
+        let fd = socket.as_raw_fd() as usize;
+        let ip = addr.ip();
+        let _span = connection_span(fd, Some(&socket)).entered();
+        let connected = self.per_ip.get(&ip).copied().unwrap_or(0);
+        if self.max_per_ip != 0 && connected >= self.max_per_ip {
+            warn!(connected, "Too many clients from this address, refusing");
+
+            return;
+        }
+        if let Err(e) = socket.set_nonblocking(true) {
+            warn!("Could not make the socket non-blocking: {}", e);
+
+            return;
+        }
+        if let Err(e) = self.poller.register(fd as RawFd, fd, Interest::READABLE) {
+            error!("Could not register socket in the poller: {}", e);
+
+            return;
+        }
+        trace!("Socket registered in the poller");
+        self.connections += 1;
+        self.clients.insert(fd, Client::new(socket, ip, self.connections));
+        *self.per_ip.entry(ip).or_default() += 1;
+        self.server.handle(server::Event::Connected { connection: fd as ConnectionId, addr });
+        info!("Client connected");
+    }
+
+    fn client_ready(&mut self, fd: SocketRawFileDescriptor, event: &Event) {
+        let Some(client) = self.clients.get_mut(&fd).filter(|client| !client.hung_up) else {
+            // Dropped, or hung up and no longer watched, earlier in this
+            // batch of events.
+            trace!(fd, "Socket not found");
+
+            return;
+        };
+        let _span = connection_span(fd, Some(&client.socket)).entered();
+        // What the client sent before hanging up is still to be read, the
+        // read below ends at EOF then.
+        if event.closed && !event.readable {
+            self.hang_up(fd);
+
+            return;
+        }
+
+        if event.writable {
+            match client.flush() {
+                Ok(true) if client.closing => {
+                    self.remove_client(fd);
+
+                    return;
+                }
+                Ok(true) => {
+                    let interest = client.idle_interest();
+                    self.watch(fd, interest);
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    warn!("Could not write to the client: {}", e);
+                    self.remove_client(fd);
+
+                    return;
                 }
             }
         }
-    });
-}
 
-fn spawn_broadcast_thread(kq_fd: usize, receiver: Receiver<BroadcastRequest>, ack_sender: Sender<BroadcastResponse>) {
-    // Map raw socket file descriptor to socket tcp stream.
-    let mut clients = HashMap::<usize, TcpStream>::new();
+        if event.readable {
+            let Some(client) = self.clients.get_mut(&fd).filter(|client| client.reading()) else {
+                return;
+            };
+            let connection = client.connection;
+            let (frames, too_large, closed) = match client.read_messages() {
+                Ok(read) => read,
+                Err(e) => {
+                    warn!("Could not read a request: {}", e);
+                    self.remove_client(fd);
+
+                    return;
+                }
+            };
 
-    thread::spawn(move || {
-        loop {
-            match receiver.recv().unwrap() {
-                BroadcastRequest::AddSocket(socket) => {
-                    add_socket_listener(kq_fd, &socket);
-                    clients.insert(socket.as_raw_fd() as usize, socket);
-                    println!("Client connected...");
+            if self.workers.is_some() {
+                client.jobs += frames.len();
+            }
+            client.too_large = too_large;
+            for frame in frames {
+                match &self.workers {
+                    Some(workers) => workers.submit(Job { from: fd, connection, request: frame }),
+                    None => self.handle(fd, prepare(&frame)),
                 }
-                BroadcastRequest::RemoveSocket(fd) => {
-                    // Removing dead socket.
-                    clients.remove(&(fd as usize));
-                    println!("Client disconnected...");
+            }
+            // The requests before go first, then the connection closes.
+            if too_large.is_some() {
+                self.refuse_too_large(fd);
+            } else if closed {
+                self.hang_up(fd);
+            }
+        }
+    }
+
+    /// Handles the requests the workers have decoded.
+    fn finish_jobs(&mut self) {
+        let Some(workers) = &mut self.workers else {
+            return;
+        };
+        let mut buf = [0u8; 256];
+        while matches!(workers.wake.read(&mut buf), Ok(n) if n > 0) {}
+        let done: Vec<_> = workers.done.try_iter().collect();
+
+        for job in done {
+            let Some(client) = self.clients.get_mut(&job.from) else {
+                continue;
+            };
+            if client.connection != job.connection {
+                // The client left and another one got its descriptor.
+                continue;
+            }
+            client.jobs -= 1;
+            let _span = connection_span(job.from, Some(&client.socket)).entered();
+            self.handle(job.from, job.request);
+            match self.clients.get(&job.from) {
+                Some(client) if client.jobs == 0 && client.hung_up => {
+                    self.remove_client(job.from);
+                    info!("Client disconnected");
                 }
-                BroadcastRequest::BroadcastMessage(fd) => {
-                    let mut message_sender = match clients.get(&fd) {
-                        Some(socket) => { BufReader::new(socket) }
-                        None => {
-                            println!("Socket with file descriptor {} is not found...", &fd);
+                Some(client) if client.jobs == 0 && client.too_large.is_some() => self.refuse_too_large(job.from),
+                _ => {}
+            }
+        }
+    }
 
-                            continue;
-                        }
-                    };
+    /// Drops the client on `fd` after it closed the connection, or, while
+    /// workers still have requests it sent before, stops watching it and
+    /// leaves dropping it to `finish_jobs`.
+    fn hang_up(&mut self, fd: SocketRawFileDescriptor) {
+        let Some(client) = self.clients.get_mut(&fd).filter(|client| !client.hung_up) else {
+            return;
+        };
+        if client.jobs == 0 {
+            self.remove_client(fd);
+            info!("Client disconnected");
 
+            return;
+        }
+        if let Err(e) = self.poller.deregister(client.socket.as_raw_fd()) {
+            warn!("Could not deregister socket from the poller: {}", e);
+        }
+        client.hung_up = true;
+    }
 
-                    let mut len_buf = [0u8; 4];
-                    message_sender.read_exact(&mut len_buf).unwrap();
-
-                    let mut message = vec![0u8; u32::from_be_bytes(len_buf) as usize];
-                    message_sender.read_exact(&mut message).unwrap();
-                    println!("{:?}", &message);
-
-                        // Ok(_) => {}
-                        // Err(e) => {
-                        //     println!("Could not read a message from socket {}", e);
-                        //
-                        //     continue;
-                        // }
-                    // };
-                    for (_, mut socket) in &clients {
-                        match socket.write(&message) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                println!("Could not broadcast a message {}", e);
-
-                                continue;
-                            }
-                        }
-                    }
+    /// Tells the `ChatServer` that the client on `fd` sent a frame over the
+    /// limit, once the workers handed back the requests it sent before.
+    /// Until then its socket is only watched for writing.
+    fn refuse_too_large(&mut self, fd: SocketRawFileDescriptor) {
+        let Some(client) = self.clients.get(&fd) else {
+            return;
+        };
+        let Some(len) = client.too_large else {
+            return;
+        };
+        if client.jobs > 0 {
+            let interest = match client.outbound.is_empty() {
+                true => client.idle_interest(),
+                false => client.flush_interest(),
+            };
+            self.watch(fd, interest);
 
-                    match ack_sender.send(BroadcastResponse::MessageAcknowledged(fd)) {
-                        Ok(_) => {}
+            return;
+        }
+        self.server.handle(server::Event::TooLarge { connection: fd as ConnectionId, len });
+        self.write_outputs();
+    }
+
+    /// Hands a request of the client on `fd` to the `ChatServer`.
+    fn handle(&mut self, fd: SocketRawFileDescriptor, request: io::Result<Request>) {
+        let connection = fd as ConnectionId;
+        let event = match request {
+            Ok(request) => server::Event::Request { connection, request },
+            Err(e) => {
+                debug!("Malformed frame: {}", e);
+                server::Event::Malformed { connection }
+            }
+        };
+        self.server.handle(event);
+        self.write_outputs();
+    }
+
+    /// Queues the frames the `ChatServer` sends, see `flush_queued`, and
+    /// closes the clients it is done with. Clients that can't keep up are
+    /// dropped.
+    fn write_outputs(&mut self) {
+        while let Some(output) = self.server.poll_output() {
+            match output {
+                Output::Send { to, frame } => {
+                    let encoded = match encode_frame(&frame) {
+                        Ok(encoded) => encoded,
                         Err(e) => {
-                            println!("Could not send message acknowledgement {}", e);
+                            error!(kind = frame.kind(), "Could not encode a frame: {}", e);
+                            continue;
                         }
                     };
+                    trace!(kind = frame.kind(), len = encoded.len(), clients = to.len(), "Queueing frame");
+                    for connection in to {
+                        let fd = connection as SocketRawFileDescriptor;
+                        if !queue_for(&mut self.clients, &mut self.unflushed, fd, &encoded) {
+                            self.remove_client(fd);
+                        }
+                    }
                 }
+                Output::Close { connection } => self.close_client(connection as SocketRawFileDescriptor),
+                // One shard without federation has nobody to relay to.
+                Output::Relay { .. } | Output::Forward { .. } => {}
             }
-        };
-    });
-}
-
-fn accept_clients(sender: Sender<BroadcastRequest>) {
-    let server_addr = std::env::args().nth(1);
+        }
+    }
 
-    let server_addr = match server_addr {
-        Some(addr) => addr,
-        None => {
-            panic!("Please, provide server address and port in the first arg e.g. 127.0.0.1:8000");
+    /// Stops reading from the client on `fd` and drops it once what is
+    /// queued for it is written.
+    fn close_client(&mut self, fd: SocketRawFileDescriptor) {
+        let Some(client) = self.clients.get_mut(&fd) else {
+            return;
+        };
+        client.closing = true;
+        match client.outbound.is_empty() {
+            true => self.remove_client(fd),
+            false => self.watch(fd, Interest::WRITABLE),
         }
-    };
+    }
 
-    let listener = match TcpListener::bind(server_addr) {
-        Ok(l) => l,
-        Err(e) => {
-            println!("Could not start a server: {}", e);
+    /// Writes what the sockets of clients with new messages take, and
+    /// waits for the rest to become writable.
+    fn flush_queued(&mut self) {
+        for fd in std::mem::take(&mut self.unflushed) {
+            let Some(client) = self.clients.get_mut(&fd) else {
+                continue;
+            };
+            let interest = client.flush_interest();
+            match client.flush() {
+                Ok(true) if client.closing => self.remove_client(fd),
+                Ok(true) => {}
+                Ok(false) => self.watch(fd, interest),
+                Err(e) => {
+                    warn!(to = fd, "Could not broadcast a message: {}", e);
+                    self.remove_client(fd);
+                }
+            }
+        }
+    }
 
+    /// Changes what the client on `fd` is watched for.
+    fn watch(&self, fd: SocketRawFileDescriptor, interest: Interest) {
+        if self.clients.get(&fd).is_some_and(|client| client.hung_up) {
             return;
         }
-    };
+        if let Err(e) = self.poller.modify(fd as RawFd, fd, interest) {
+            warn!(fd, "Could not change what the poller watches: {}", e);
+        }
+    }
 
-    // Accept new clients.
-    loop {
-        match listener.accept() {
-            Ok((socket, addr)) => {
-                sender.send(BroadcastRequest::AddSocket(socket)).unwrap();
-            },
-            Err(e) => println!("Some client could not connect: {}", e.to_string()),
-        };
+    /// Stops watching the client on `fd` and closes its socket. That frees
+    /// a descriptor, so accepting resumes if it was paused for lack of them.
+    fn remove_client(&mut self, fd: SocketRawFileDescriptor) {
+        if let Some(client) = self.clients.remove(&fd) {
+            // A client that hung up isn't watched anymore, see `hang_up`.
+            if !client.hung_up {
+                if let Err(e) = self.poller.deregister(client.socket.as_raw_fd()) {
+                    warn!("Could not deregister socket from the poller: {}", e);
+                }
+            }
+            self.server.handle(server::Event::Disconnected { connection: fd as ConnectionId });
+            if let Some(connected) = self.per_ip.get_mut(&client.ip) {
+                *connected -= 1;
+                if *connected == 0 {
+                    self.per_ip.remove(&client.ip);
+                }
+            }
+            if let Some(at) = &mut self.accept_resumes {
+                *at = Instant::now();
+            }
+        }
     }
 }
 
-fn add_socket_listener(kq_fd: usize, socket: &TcpStream) {
-    let mut ke = libc::kevent {
-        // Raw file descriptor of the socket we want to listen.
-        ident: socket.as_fd().as_raw_fd() as libc::uintptr_t,
-        // Filter events we are interested in.
-        filter: libc::EVFILT_READ,
-        // Operation that we want to do with our kevent.
-        // In this case we add it to kqueue.
-        flags: libc::EV_ADD | libc::EV_EOF,
-        fflags: 0,
-        data: 0,
-        udata: null_mut(),
+/// Queues the encoded `frame` for the client on `fd`, to be written by
+/// `Reactor::flush_queued`. Returns false if the client can't keep up and
+/// has to be dropped.
+fn queue_for(
+    clients: &mut HashMap<SocketRawFileDescriptor, Client>,
+    unflushed: &mut Vec<SocketRawFileDescriptor>,
+    fd: SocketRawFileDescriptor,
+    frame: &[u8],
+) -> bool {
+    let Some(client) = clients.get_mut(&fd) else {
+        return true;
     };
+    let was_empty = client.outbound.is_empty();
+    if !client.queue(frame) {
+        warn!(to = fd, queued = client.outbound.len(), "Dropping slow client");
 
-    unsafe {
-        // Here we want only to register our socket for listening in kqueue.
-        // The actual listening will happen in a dedicated thread.
-        let n = libc::kevent(
-            // kqueue file descriptor where we want to add an event listener.
-            kq_fd as libc::c_int,
-            // kevent struct that specifies details of the event we are registering.
-            &mut ke as *mut libc::kevent,
-            // Number of changes. We only want to listen to one socket here.
-            1,
-            // We don't want to listen for events here so we don't define the place
-            // where events should be put. This would block the thread.
-            null_mut(),
-            // Consequently, we don't need to define how many events max we expect.
-            0,
-            // Timeout. We don't need to specify it anywhere since we want to wait
-            // for new socket events indefinitely.
-            null_mut()
-        );
-
-        if n == -1 {
-            println!(
-                "Error occurred when registering socket in kqueue: {}",
-                std::io::Error::last_os_error()
-            );
+        return false;
+    }
+    if was_empty {
+        // Clients with older frames still queued are already waiting for
+        // their socket to become writable.
+        unflushed.push(fd);
+    }
 
-            return;
+    true
+}
+
+fn main() {
+    let settings = Config::load().and_then(|config| {
+        logging::init(&config, None)?;
+        config.unsupported(&["storage.path", "tls.cert", "tls.key"])?;
+        let workers = config.get("server.workers", 0usize)?;
+        let max_per_ip = config.get("limits.connections_per_ip", 64usize)?;
+        Ok((workers, max_per_ip, config))
+    });
+    let (workers, max_per_ip, config) = match settings {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+    };
+    let server_addr = config.get_str("server.addr").unwrap_or("127.0.0.1:8000").to_string();
 
-        println!("New socket listener has been added to kqueue. Socket fd: {}", ke.ident as usize);
+    if let Err(e) = run(&server_addr, workers, max_per_ip) {
+        error!("Could not run the server: {}", e);
+        std::process::exit(1);
     }
 }
+
+fn run(server_addr: &str, workers: usize, max_per_ip: usize) -> io::Result<()> {
+    let listener = TcpListener::bind(server_addr)?;
+    let pool = match workers {
+        0 => None,
+        count => Some(Workers::spawn(count)?),
+    };
+    let mut reactor = Reactor::new(listener, pool, max_per_ip)?;
+
+    info!(addr = server_addr, workers, "Waiting for connections");
+    reactor.run()
+}
+
+/// Span for everything about the client on `fd`.
+fn connection_span(fd: SocketRawFileDescriptor, socket: Option<&TcpStream>) -> Span {
+    let peer = socket.and_then(|socket| socket.peer_addr().ok());
+    info_span!("connection", fd, peer = ?peer)
+}
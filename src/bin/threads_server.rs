@@ -0,0 +1,310 @@
+//! Thread-per-connection chat server with blocking I/O, the simple baseline
+//! for the other servers.
+//!
+//! Speaks the client protocol of `async_std_server`, without federation or
+//! rate limits. Each connection is read by one of `server.threads` pooled
+//! threads, further connections wait to be accepted until one is free, and
+//! written by a thread of its own. Requests go to a single broker thread,
+//! which routes them with `chat_rs::server::ChatServer` and queues the
+//! frames each client is sent.
+
+use chat_rs::config::{Config, ConfigError};
+use chat_rs::logging;
+use chat_rs::protocol::{decode_frame, encode_frame, read_payload, FrameTooLarge, ServerFrame};
+use chat_rs::server::{self, ChatServer, ConnectionId, Output};
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span, warn, Span};
+
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    /// Connections served at once.
+    threads: usize,
+    /// Frames waiting for each client.
+    client_queue: usize,
+    /// How often clients are pinged.
+    heartbeat_interval: Duration,
+    /// Clients that send nothing for this long are evicted.
+    idle_timeout: Duration,
+}
+
+impl Settings {
+    fn from_config(config: &Config) -> Result<Settings, ConfigError> {
+        let settings = Settings {
+            threads: config.get("server.threads", 256)?,
+            client_queue: config.get("limits.client_queue", 1024)?,
+            heartbeat_interval: config.secs("heartbeat.interval", 10)?,
+            idle_timeout: config.secs("heartbeat.idle_timeout", 30)?,
+        };
+        if settings.threads == 0 {
+            return Err(config.invalid("server.threads", "must be positive"));
+        }
+        if settings.heartbeat_interval.is_zero() || settings.heartbeat_interval >= settings.idle_timeout {
+            return Err(config.invalid("heartbeat.interval", "must be positive and shorter than heartbeat.idle_timeout"));
+        }
+
+        Ok(settings)
+    }
+}
+
+/// What connections tell the broker.
+enum Event {
+    /// A client connected, its frames go to `frames`.
+    Connected {
+        id: ConnectionId,
+        addr: SocketAddr,
+        frames: SyncSender<Arc<[u8]>>,
+    },
+    /// Anything else, for the `ChatServer`.
+    Server(server::Event),
+}
+
+/// A connection as the broker knows it.
+struct Client {
+    addr: SocketAddr,
+    /// Read by the connection's writer thread. Dropping it ends the thread
+    /// once the frames in it are written.
+    frames: SyncSender<Arc<[u8]>>,
+}
+
+/// Everything the broker thread owns.
+#[derive(Default)]
+struct Broker {
+    server: ChatServer,
+    clients: HashMap<ConnectionId, Client>,
+}
+
+impl Broker {
+    fn run(mut self, events: Receiver<Event>) {
+        for event in events {
+            let event = match event {
+                Event::Connected { id, addr, frames } => {
+                    self.clients.insert(id, Client { addr, frames });
+                    server::Event::Connected { connection: id, addr }
+                }
+                Event::Server(event) => {
+                    if let server::Event::Disconnected { connection } = event {
+                        self.clients.remove(&connection);
+                    }
+                    event
+                }
+            };
+            let id = event.connection();
+            let _span = id
+                .and_then(|id| Some(connection_span(id, self.clients.get(&id)?.addr)))
+                .map(|span| span.entered());
+            self.server.handle(event);
+            self.write_outputs();
+        }
+    }
+
+    /// Does what the `ChatServer` asks for.
+    fn write_outputs(&mut self) {
+        while let Some(output) = self.server.poll_output() {
+            match output {
+                Output::Send { to, frame } => {
+                    let encoded: Arc<[u8]> = match encode_frame(&frame) {
+                        Ok(encoded) => Arc::from(encoded),
+                        Err(e) => {
+                            error!(frame = frame.kind(), "Could not encode a frame: {}", e);
+                            continue;
+                        }
+                    };
+                    for id in to {
+                        self.queue(id, &encoded);
+                    }
+                }
+                Output::Close { connection } => {
+                    self.clients.remove(&connection);
+                }
+                // One shard without federation has nobody to relay to.
+                Output::Relay { .. } | Output::Forward { .. } => {}
+            }
+        }
+    }
+
+    /// Queues the encoded `frame` for client `id`, which is disconnected
+    /// if its queue is full.
+    fn queue(&mut self, id: ConnectionId, frame: &Arc<[u8]>) {
+        let Some(client) = self.clients.get(&id) else {
+            return;
+        };
+        match client.frames.try_send(Arc::clone(frame)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!(to = id, "Dropping slow client");
+                self.clients.remove(&id);
+                self.server.handle(server::Event::Disconnected { connection: id });
+            }
+            // The writer stopped and the reader reports the disconnect.
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+/// Threads serving one connection each.
+struct Pool {
+    /// Hands an accepted connection to a free thread, waiting for one.
+    connections: SyncSender<(ConnectionId, TcpStream)>,
+}
+
+impl Pool {
+    fn spawn(settings: Settings, broker: Sender<Event>) -> io::Result<Pool> {
+        let (connections, receiver) = mpsc::sync_channel::<(ConnectionId, TcpStream)>(0);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for thread in 0..settings.threads {
+            let receiver = Arc::clone(&receiver);
+            let broker = broker.clone();
+            thread::Builder::new().name(format!("connection-{}", thread)).spawn(move || loop {
+                let connection = receiver.lock().unwrap().recv();
+                let Ok((id, socket)) = connection else {
+                    break;
+                };
+                if let Err(e) = serve(id, socket, &broker, settings) {
+                    debug!(id, "Connection ended: {}", e);
+                }
+            })?;
+        }
+
+        Ok(Pool { connections })
+    }
+}
+
+/// Reads the requests of a client until it leaves, while a scoped thread
+/// writes what the broker queues for it.
+fn serve(id: ConnectionId, socket: TcpStream, broker: &Sender<Event>, settings: Settings) -> io::Result<()> {
+    let addr = socket.peer_addr()?;
+    let span = connection_span(id, addr);
+    let _span = span.enter();
+    socket.set_read_timeout(Some(settings.idle_timeout))?;
+    // A client that reads nothing for as long would hold this thread.
+    socket.set_write_timeout(Some(settings.idle_timeout))?;
+    let (frames, queue) = mpsc::sync_channel(settings.client_queue);
+    if broker.send(Event::Connected { id, addr, frames }).is_err() {
+        return Err(io::Error::other("broker stopped"));
+    }
+    info!("Client connected");
+
+    thread::scope(|scope| {
+        thread::Builder::new().name(format!("writer-{}", id)).spawn_scoped(scope, || {
+            let _span = span.enter();
+            if let Err(e) = write_frames(&socket, queue, settings.heartbeat_interval) {
+                debug!("Could not write to the client: {}", e);
+            }
+            // Wakes the reader up if the client is still connected.
+            let _ = socket.shutdown(Shutdown::Both);
+        })?;
+
+        let res = read_requests(id, &socket, broker);
+        let _ = broker.send(Event::Server(server::Event::Disconnected { connection: id }));
+        info!("Client disconnected");
+
+        res
+    })
+}
+
+fn read_requests(id: ConnectionId, socket: &TcpStream, broker: &Sender<Event>) -> io::Result<()> {
+    let mut reader = BufReader::new(socket);
+    loop {
+        let payload = match read_payload(&mut reader) {
+            Ok(Some(payload)) => payload,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                if let Some(&FrameTooLarge { len }) = FrameTooLarge::find(&e) {
+                    let _ = broker.send(Event::Server(server::Event::TooLarge { connection: id, len }));
+                } else if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) {
+                    info!("Evicting idle client");
+                }
+                return Err(e);
+            }
+        };
+        let event = match decode_frame(&payload) {
+            Ok(request) => server::Event::Request { connection: id, request },
+            Err(e) => {
+                debug!("Malformed frame: {}", e);
+                server::Event::Malformed { connection: id }
+            }
+        };
+        if broker.send(Event::Server(event)).is_err() {
+            return Err(io::Error::other("broker stopped"));
+        }
+    }
+}
+
+/// Writes the frames queued for a client as they come, and pings it
+/// whenever nothing was queued for `interval`. Returns once the broker
+/// dropped the client and its queue is empty.
+fn write_frames(socket: &TcpStream, queue: Receiver<Arc<[u8]>>, interval: Duration) -> io::Result<()> {
+    // Ping tokens are microseconds since the connection started.
+    let started = Instant::now();
+    let mut writer = BufWriter::new(socket);
+    loop {
+        let frame = match queue.recv_timeout(interval) {
+            Ok(frame) => frame,
+            Err(RecvTimeoutError::Timeout) => {
+                Arc::from(encode_frame(&ServerFrame::Ping { token: started.elapsed().as_micros() as u64 })?)
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        writer.write_all(&frame)?;
+        // Whatever else is queued goes out with it.
+        for frame in queue.try_iter() {
+            writer.write_all(&frame)?;
+        }
+        writer.flush()?;
+    }
+}
+
+/// Span for everything about connection `id`.
+fn connection_span(id: ConnectionId, peer: SocketAddr) -> Span {
+    info_span!("connection", id, %peer)
+}
+
+fn main() {
+    let settings = Config::load().and_then(|config| {
+        logging::init(&config, None)?;
+        config.unsupported(&["storage.path", "tls.cert", "tls.key"])?;
+        Ok((Settings::from_config(&config)?, config))
+    });
+    let (settings, config) = match settings {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let server_addr = config.get_str("server.addr").unwrap_or("127.0.0.1:8000").to_string();
+
+    if let Err(e) = run(&server_addr, settings) {
+        error!("Could not run the server: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(server_addr: &str, settings: Settings) -> io::Result<()> {
+    let listener = TcpListener::bind(server_addr)?;
+    let (broker, events) = mpsc::channel();
+    thread::Builder::new().name(String::from("broker")).spawn(move || Broker::default().run(events))?;
+    let pool = Pool::spawn(settings, broker)?;
+
+    info!(addr = server_addr, threads = settings.threads, "Waiting for connections");
+    let mut next_id = 0;
+    for socket in listener.incoming() {
+        match socket {
+            Ok(socket) => {
+                next_id += 1;
+                if pool.connections.send((next_id, socket)).is_err() {
+                    return Err(io::Error::other("every connection thread stopped"));
+                }
+            }
+            Err(e) => warn!("Some client could not connect: {}", e),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,620 @@
+//! Chat server built on io_uring, Linux only.
+//!
+//! Speaks the client protocol of `async_std_server` on a single thread,
+//! without federation, rate limits or heartbeats, and routes requests with
+//! `chat_rs::server::ChatServer`. Every socket operation is
+//! submitted to the ring and finished when its completion comes back:
+//!
+//! - one multishot accept produces all connections,
+//! - each connection has a multishot receive that fills buffers from a pool
+//!   provided to the kernel up front, handed back once their bytes are read,
+//! - queued frames go out as a chain of linked sends, which the kernel runs
+//!   in order from a single submission.
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("uring_server needs io_uring, which only Linux has");
+    std::process::exit(1);
+}
+
+#[cfg(target_os = "linux")]
+fn main() {
+    server::main()
+}
+
+#[cfg(target_os = "linux")]
+mod server {
+    use chat_rs::config::Config;
+    use chat_rs::logging;
+    use chat_rs::protocol::{decode_frame, encode_frame, MAX_FRAME_LEN};
+    use chat_rs::server::{self, ChatServer, ConnectionId, Output};
+    use io_uring::{cqueue, opcode, squeue, types, IoUring};
+    use std::collections::VecDeque;
+    use std::io;
+    use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::rc::Rc;
+    use std::time::Duration;
+    use tracing::{debug, error, info, info_span, trace, warn, Span};
+
+    /// Submission queue entries, the completion queue gets twice as many.
+    const RING_ENTRIES: u32 = 4096;
+    /// Id of the receive buffer pool.
+    const BUFFER_GROUP: u16 = 0;
+    const BUFFER_COUNT: u16 = 1024;
+    const BUFFER_LEN: usize = 16 * 1024;
+    /// Sends linked into one chain at most.
+    const MAX_CHAIN_LEN: usize = 64;
+    /// Frames shorter than this are copied together into one send, a send
+    /// per frame costs more than the copy.
+    const COALESCE_LEN: usize = 64 * 1024;
+    /// Bytes queued for a client before it is dropped as too slow. Always
+    /// fits a message of `MAX_FRAME_LEN`.
+    const MAX_OUTBOUND_LEN: usize = 2 * MAX_FRAME_LEN;
+    /// How long to stop accepting when out of file descriptors, unless a
+    /// client leaves first.
+    const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+    // What an operation is, in the low byte of its user data. Receives and
+    // sends have their connection's slot in the bytes above the second,
+    // sends also their place in the chain in the second.
+    const ACCEPT: u64 = 0;
+    const RESUME_ACCEPT: u64 = 1;
+    const CANCEL: u64 = 2;
+    const PROVIDE: u64 = 3;
+    const RECV: u64 = 4;
+    const SEND: u64 = 5;
+
+    fn user_data(op: u64, slot: usize, index: usize) -> u64 {
+        op | (index as u64) << 8 | (slot as u64) << 16
+    }
+
+    /// A frame, or frames copied together, in a chain of sends.
+    struct Chunk {
+        frame: Rc<[u8]>,
+        /// Where in the frame the send starts.
+        offset: usize,
+        /// Bytes sent, once the send completed.
+        sent: Option<usize>,
+    }
+
+    /// A connected client.
+    struct Connection {
+        socket: TcpStream,
+        addr: SocketAddr,
+        /// Bytes received that don't make up a whole frame yet.
+        inbound: Vec<u8>,
+        /// Frames for the next chain of sends, with where the rest of each
+        /// starts. Frames for several clients are shared.
+        outbound: VecDeque<(Rc<[u8]>, usize)>,
+        /// Bytes left in `outbound`.
+        queued: usize,
+        /// The chain of sends in flight.
+        sending: Vec<Chunk>,
+        /// Operations in flight. They point into the socket and `sending`,
+        /// so the connection is only dropped once they are all finished.
+        in_flight: usize,
+        /// No more requests are read, the connection closes once what is
+        /// queued has been sent.
+        draining: bool,
+        closing: bool,
+    }
+
+    impl Connection {
+        fn new(socket: TcpStream, addr: SocketAddr) -> Connection {
+            Connection {
+                socket,
+                addr,
+                inbound: Vec::new(),
+                outbound: VecDeque::new(),
+                queued: 0,
+                sending: Vec::new(),
+                in_flight: 0,
+                draining: false,
+                closing: false,
+            }
+        }
+
+        /// Takes the payloads of the complete frames out of `inbound`.
+        /// Also returns the length of a frame over `MAX_FRAME_LEN` if one
+        /// follows them, nothing after it can be read.
+        fn take_frames(&mut self) -> (Vec<Vec<u8>>, Option<usize>) {
+            let mut frames = Vec::new();
+            let mut start = 0;
+            let mut too_large = None;
+            while let Some(len_buf) = self.inbound.get(start..start + 4) {
+                let len = u32::from_be_bytes(len_buf.try_into().unwrap()) as usize;
+                if len > MAX_FRAME_LEN {
+                    too_large = Some(len);
+                    break;
+                }
+                let Some(payload) = self.inbound.get(start + 4..start + 4 + len) else {
+                    break;
+                };
+                frames.push(payload.to_vec());
+                start += 4 + len;
+            }
+            self.inbound.drain(..start);
+
+            (frames, too_large)
+        }
+    }
+
+    struct Server {
+        // Dropped first, the kernel stops using what the operations point
+        // to with it.
+        ring: IoUring,
+        listener: TcpListener,
+        /// The receive buffer pool, `BUFFER_COUNT` buffers of `BUFFER_LEN`.
+        buffers: Box<[u8]>,
+        /// Connections by slot.
+        connections: Vec<Option<Connection>>,
+        free_slots: Vec<usize>,
+        /// Routes the requests. Connections are known to it by slot.
+        server: ChatServer,
+        /// Connections with frames queued since the last submission. Their
+        /// chains start once the completions at hand are handled, so that
+        /// one chain carries all of them.
+        unflushed: Vec<usize>,
+        /// Whether the multishot accept is in flight.
+        accepting: bool,
+        /// Set while out of file descriptors.
+        accept_paused: bool,
+        /// Read by the kernel while the timeout resuming accepts is in
+        /// flight.
+        backoff: Box<types::Timespec>,
+    }
+
+    impl Server {
+        fn new(listener: TcpListener) -> io::Result<Server> {
+            Ok(Server {
+                ring: IoUring::new(RING_ENTRIES)?,
+                listener,
+                buffers: vec![0u8; BUFFER_COUNT as usize * BUFFER_LEN].into_boxed_slice(),
+                connections: Vec::new(),
+                free_slots: Vec::new(),
+                server: ChatServer::new(),
+                unflushed: Vec::new(),
+                accepting: false,
+                accept_paused: false,
+                backoff: Box::new(types::Timespec::from(ACCEPT_BACKOFF)),
+            })
+        }
+
+        fn run(&mut self) -> io::Result<()> {
+            self.provide_buffers(0, BUFFER_COUNT)?;
+            let mut completions = Vec::new();
+
+            loop {
+                self.accept()?;
+                match self.ring.submit_and_wait(1) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    // The completion queue is full, make room first.
+                    Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {}
+                    Err(e) => return Err(e),
+                }
+
+                completions.extend(self.ring.completion().map(|cqe| (cqe.user_data(), cqe.result(), cqe.flags())));
+                for (user_data, result, flags) in completions.drain(..) {
+                    let slot = (user_data >> 16) as usize;
+                    let index = (user_data >> 8 & 0xff) as usize;
+                    match user_data & 0xff {
+                        ACCEPT => self.accepted(result, flags)?,
+                        RESUME_ACCEPT => self.accept_paused = false,
+                        CANCEL => {}
+                        PROVIDE if result < 0 => {
+                            error!("Could not provide receive buffers: {}", io::Error::from_raw_os_error(-result));
+                        }
+                        PROVIDE => {}
+                        RECV => self.received(slot, result, flags)?,
+                        SEND => self.sent(slot, index, result)?,
+                        op => unreachable!("unknown operation {}", op),
+                    }
+                }
+                for slot in std::mem::take(&mut self.unflushed) {
+                    self.start_sending(slot)?;
+                }
+            }
+        }
+
+        /// Queues `entries` for the next submission, back to back so that a
+        /// chain isn't split between two submissions.
+        fn push(&mut self, entries: &[squeue::Entry]) -> io::Result<()> {
+            loop {
+                // SAFETY: the operations only point to memory that outlives
+                // them: the buffer pool and the backoff live as long as the
+                // ring, connections and their chains stay until their last
+                // operation is finished.
+                if unsafe { self.ring.submission().push_multiple(entries) }.is_ok() {
+                    return Ok(());
+                }
+                match self.ring.submit() {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        /// Hands `count` buffers starting with `first` to the kernel.
+        fn provide_buffers(&mut self, first: u16, count: u16) -> io::Result<()> {
+            let addr = self.buffers[first as usize * BUFFER_LEN..].as_mut_ptr();
+            let entry = opcode::ProvideBuffers::new(addr, BUFFER_LEN as i32, count, BUFFER_GROUP, first)
+                .build()
+                .user_data(user_data(PROVIDE, 0, 0));
+            self.push(&[entry])
+        }
+
+        /// Starts accepting unless already accepting or paused.
+        fn accept(&mut self) -> io::Result<()> {
+            if self.accepting || self.accept_paused {
+                return Ok(());
+            }
+            self.accepting = true;
+            let entry = opcode::AcceptMulti::new(types::Fd(self.listener.as_raw_fd()))
+                .build()
+                .user_data(user_data(ACCEPT, 0, 0));
+            self.push(&[entry])
+        }
+
+        fn accepted(&mut self, result: i32, flags: u32) -> io::Result<()> {
+            if !cqueue::more(flags) {
+                // Started again by the loop unless paused.
+                self.accepting = false;
+            }
+            if result >= 0 {
+                // SAFETY: the kernel opened the descriptor for us.
+                let socket = unsafe { TcpStream::from_raw_fd(result) };
+                return self.add_connection(socket);
+            }
+
+            let e = io::Error::from_raw_os_error(-result);
+            match -result {
+                libc::EMFILE | libc::ENFILE => self.pause_accepting(e)?,
+                libc::ECANCELED => {}
+                libc::EINVAL => {
+                    return Err(io::Error::new(e.kind(), format!("multishot accept needs Linux 5.19 or later: {}", e)));
+                }
+                // The connection failed before it was accepted, the next
+                // one may not.
+                _ => warn!("Some client could not connect: {}", e),
+            }
+
+            Ok(())
+        }
+
+        /// Stops accepting for `ACCEPT_BACKOFF`. The pending connection
+        /// can't be accepted and would fail over and over.
+        fn pause_accepting(&mut self, e: io::Error) -> io::Result<()> {
+            if self.accept_paused {
+                return Ok(());
+            }
+            warn!(backoff = ?ACCEPT_BACKOFF, "Not accepting clients for now: {}", e);
+            self.accept_paused = true;
+            let timeout = opcode::Timeout::new(&*self.backoff).build().user_data(user_data(RESUME_ACCEPT, 0, 0));
+            self.push(&[timeout])?;
+            if self.accepting {
+                let cancel = opcode::AsyncCancel::new(user_data(ACCEPT, 0, 0)).build().user_data(user_data(CANCEL, 0, 0));
+                self.push(&[cancel])?;
+            }
+
+            Ok(())
+        }
+
+        fn add_connection(&mut self, socket: TcpStream) -> io::Result<()> {
+            let addr = match socket.peer_addr() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    debug!("Client left before it was accepted: {}", e);
+                    return Ok(());
+                }
+            };
+            let slot = self.free_slots.pop().unwrap_or_else(|| {
+                self.connections.push(None);
+                self.connections.len() - 1
+            });
+            let _span = connection_span(slot, addr).entered();
+            self.connections[slot] = Some(Connection::new(socket, addr));
+            self.server.handle(server::Event::Connected { connection: slot as ConnectionId, addr });
+            info!("Client connected");
+
+            self.receive(slot)
+        }
+
+        /// Starts receiving from the connection in `slot`.
+        fn receive(&mut self, slot: usize) -> io::Result<()> {
+            let Some(connection) = self.connections[slot].as_mut() else {
+                return Ok(());
+            };
+            connection.in_flight += 1;
+            let entry = opcode::RecvMulti::new(types::Fd(connection.socket.as_raw_fd()), BUFFER_GROUP)
+                .build()
+                .user_data(user_data(RECV, slot, 0));
+            self.push(&[entry])
+        }
+
+        fn received(&mut self, slot: usize, result: i32, flags: u32) -> io::Result<()> {
+            let Some(connection) = self.connections[slot].as_mut() else {
+                return Ok(());
+            };
+            let _span = connection_span(slot, connection.addr).entered();
+            let more = cqueue::more(flags);
+            if !more {
+                connection.in_flight -= 1;
+            }
+            let closing = connection.closing;
+            if let Some(buffer) = cqueue::buffer_select(flags) {
+                if result > 0 && !connection.closing && !connection.draining {
+                    let start = buffer as usize * BUFFER_LEN;
+                    connection.inbound.extend_from_slice(&self.buffers[start..start + result as usize]);
+                }
+                self.provide_buffers(buffer, 1)?;
+            }
+
+            match result {
+                1.. => self.read_requests(slot),
+                0 => {
+                    info!("Client disconnected");
+                    self.close(slot);
+                }
+                // The receive ends, it starts again below once the pool
+                // has buffers back.
+                _ if -result == libc::ENOBUFS => debug!("Out of receive buffers"),
+                _ => {
+                    if !closing {
+                        warn!("Could not receive: {}", io::Error::from_raw_os_error(-result));
+                    }
+                    self.close(slot);
+                }
+            }
+
+            match &self.connections[slot] {
+                Some(connection) if !more && !connection.closing => self.receive(slot),
+                _ => {
+                    self.release(slot);
+                    Ok(())
+                }
+            }
+        }
+
+        /// Handles the requests received complete from the connection in
+        /// `slot`.
+        fn read_requests(&mut self, slot: usize) {
+            let Some(connection) = self.connections[slot].as_mut() else {
+                return;
+            };
+            if connection.closing || connection.draining {
+                return;
+            }
+            let (frames, too_large) = connection.take_frames();
+
+            let connection = slot as ConnectionId;
+            for payload in frames {
+                let event = match decode_frame(&payload) {
+                    Ok(request) => server::Event::Request { connection, request },
+                    Err(e) => {
+                        debug!("Malformed frame: {}", e);
+                        server::Event::Malformed { connection }
+                    }
+                };
+                self.server.handle(event);
+            }
+            if let Some(len) = too_large {
+                self.server.handle(server::Event::TooLarge { connection, len });
+            }
+            self.write_outputs();
+        }
+
+        /// Queues the frames the `ChatServer` sends and closes the
+        /// connections it is done with.
+        fn write_outputs(&mut self) {
+            while let Some(output) = self.server.poll_output() {
+                match output {
+                    Output::Send { to, frame } => {
+                        let encoded: Rc<[u8]> = match encode_frame(&frame) {
+                            Ok(encoded) => Rc::from(encoded),
+                            Err(e) => {
+                                error!(frame = frame.kind(), "Could not encode a frame: {}", e);
+                                continue;
+                            }
+                        };
+                        for connection in to {
+                            self.queue(connection as usize, &encoded);
+                        }
+                    }
+                    Output::Close { connection } => self.drain(connection as usize),
+                    // One shard without federation has nobody to relay to.
+                    Output::Relay { .. } | Output::Forward { .. } => {}
+                }
+            }
+        }
+
+        /// Stops reading from the connection in `slot` and closes it once
+        /// what is queued has been sent.
+        fn drain(&mut self, slot: usize) {
+            let Some(connection) = self.connections[slot].as_mut() else {
+                return;
+            };
+            connection.draining = true;
+            if connection.outbound.is_empty() && connection.sending.is_empty() {
+                self.close(slot);
+            }
+        }
+
+        /// Queues the encoded `frame` for the connection in `slot`, which
+        /// is dropped if it can't keep up.
+        fn queue(&mut self, slot: usize, frame: &Rc<[u8]>) {
+            let Some(connection) = self.connections[slot].as_mut() else {
+                return;
+            };
+            if connection.closing {
+                return;
+            }
+            if connection.queued + frame.len() > MAX_OUTBOUND_LEN {
+                warn!(to = slot, queued = connection.queued, "Dropping slow client");
+                self.close(slot);
+
+                return;
+            }
+            // A chain in flight starts the next one when it's finished.
+            if connection.outbound.is_empty() && connection.sending.is_empty() {
+                self.unflushed.push(slot);
+            }
+            connection.queued += frame.len();
+            connection.outbound.push_back((Rc::clone(frame), 0));
+        }
+
+        /// Submits what is queued for the connection in `slot` as a chain
+        /// of linked sends, unless a chain is in flight already.
+        fn start_sending(&mut self, slot: usize) -> io::Result<()> {
+            let Some(connection) = self.connections[slot].as_mut() else {
+                return Ok(());
+            };
+            if connection.closing || !connection.sending.is_empty() || connection.outbound.is_empty() {
+                return Ok(());
+            }
+            while connection.sending.len() < MAX_CHAIN_LEN {
+                let Some((frame, offset)) = connection.outbound.pop_front() else {
+                    break;
+                };
+                connection.queued -= frame.len() - offset;
+                if frame.len() - offset >= COALESCE_LEN {
+                    connection.sending.push(Chunk { frame, offset, sent: None });
+                    continue;
+                }
+                let mut batch = frame[offset..].to_vec();
+                while let Some((next, offset)) = connection.outbound.front() {
+                    if batch.len() + next.len() - offset > COALESCE_LEN {
+                        break;
+                    }
+                    batch.extend_from_slice(&next[*offset..]);
+                    connection.queued -= next.len() - offset;
+                    connection.outbound.pop_front();
+                }
+                connection.sending.push(Chunk { frame: Rc::from(batch), offset: 0, sent: None });
+            }
+            let count = connection.sending.len();
+            connection.in_flight += count;
+
+            let fd = types::Fd(connection.socket.as_raw_fd());
+            let entries: Vec<squeue::Entry> = connection.sending.iter().enumerate()
+                .map(|(index, chunk)| {
+                    let bytes = &chunk.frame[chunk.offset..];
+                    // Waiting for all of it makes a short send fail the
+                    // rest of the chain rather than leave a gap.
+                    let send = opcode::Send::new(fd, bytes.as_ptr(), bytes.len() as u32)
+                        .flags(libc::MSG_NOSIGNAL | libc::MSG_WAITALL)
+                        .build()
+                        .user_data(user_data(SEND, slot, index));
+                    match index + 1 < count {
+                        true => send.flags(squeue::Flags::IO_LINK),
+                        false => send,
+                    }
+                })
+                .collect();
+            trace!(to = slot, sends = count, "Sending");
+
+            self.push(&entries)
+        }
+
+        fn sent(&mut self, slot: usize, index: usize, result: i32) -> io::Result<()> {
+            let Some(connection) = self.connections[slot].as_mut() else {
+                return Ok(());
+            };
+            connection.in_flight -= 1;
+            connection.sending[index].sent = Some(result.max(0) as usize);
+            if result < 0 && result != -libc::ECANCELED && !connection.closing {
+                let _span = connection_span(slot, connection.addr).entered();
+                warn!("Could not send: {}", io::Error::from_raw_os_error(-result));
+                self.close(slot);
+            }
+            let Some(connection) = self.connections[slot].as_mut() else {
+                return Ok(());
+            };
+            if connection.sending.iter().any(|chunk| chunk.sent.is_none()) {
+                return Ok(());
+            }
+
+            // A failed send cancels the rest of its chain, what is left
+            // goes ahead of newer frames.
+            for chunk in std::mem::take(&mut connection.sending).into_iter().rev() {
+                let offset = chunk.offset + chunk.sent.unwrap_or_default();
+                if offset < chunk.frame.len() && !connection.closing {
+                    connection.queued += chunk.frame.len() - offset;
+                    connection.outbound.push_front((chunk.frame, offset));
+                }
+            }
+            if connection.closing {
+                self.release(slot);
+            } else if !connection.outbound.is_empty() {
+                self.start_sending(slot)?;
+            } else if connection.draining {
+                self.close(slot);
+            }
+
+            Ok(())
+        }
+
+        /// Stops using the connection in `slot`. Shutting its socket down
+        /// fails what is in flight, it is dropped once that is finished.
+        fn close(&mut self, slot: usize) {
+            let Some(connection) = self.connections[slot].as_mut() else {
+                return;
+            };
+            if connection.closing {
+                return;
+            }
+            connection.closing = true;
+            connection.outbound.clear();
+            connection.queued = 0;
+            let _ = connection.socket.shutdown(Shutdown::Both);
+            self.server.handle(server::Event::Disconnected { connection: slot as ConnectionId });
+            // That frees a descriptor.
+            self.accept_paused = false;
+
+            self.release(slot);
+        }
+
+        /// Frees the slot of a closed connection once nothing is in flight.
+        fn release(&mut self, slot: usize) {
+            if matches!(&self.connections[slot], Some(connection) if connection.closing && connection.in_flight == 0) {
+                self.connections[slot] = None;
+                self.free_slots.push(slot);
+            }
+        }
+    }
+
+    /// Span for everything about the connection in `slot`.
+    fn connection_span(slot: usize, peer: SocketAddr) -> Span {
+        info_span!("connection", slot, %peer)
+    }
+
+    pub fn main() {
+        let config = match Config::load().and_then(|config| {
+            logging::init(&config, None)?;
+            config.unsupported(&["storage.path", "tls.cert", "tls.key"])?;
+            Ok(config)
+        }) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        let server_addr = config.get_str("server.addr").unwrap_or("127.0.0.1:8000").to_string();
+
+        if let Err(e) = run(&server_addr) {
+            error!("Could not run the server: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    fn run(server_addr: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(server_addr)?;
+        let mut server = Server::new(listener)?;
+
+        info!(addr = server_addr, "Waiting for connections");
+        server.run()
+    }
+}
@@ -0,0 +1,337 @@
+//! Settings shared by the binaries.
+//!
+//! Every setting has a dotted key such as `limits.client_queue`, which is
+//! also its name in the TOML file and on the command line. Values are taken
+//! from, in increasing priority:
+//!
+//! * the built-in default,
+//! * a TOML file named by `--config` or `CHAT_CONFIG`,
+//! * the setting's `CHAT_*` environment variable,
+//! * a `--key=value` or `--key value` flag.
+//!
+//! A bare positional argument sets `server.addr`. Errors name the key and
+//! where its value came from.
+//!
+//! Only `async_std_server` stores history and speaks TLS, the other servers
+//! refuse `storage.*` and `tls.*` settings, see [`Config::unsupported`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Longest duration a setting may have, a year. Timers and bans add
+/// durations to the current time, which overflows for huge ones.
+pub const MAX_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// A setting known to the binaries.
+#[derive(Debug)]
+pub struct Key {
+    pub name: &'static str,
+    pub env: &'static str,
+    /// Shorter flag kept for compatibility, e.g. `--lan`.
+    pub alias: Option<&'static str>,
+    /// Given on the command line without a value, which means `true`.
+    pub switch: bool,
+    pub help: &'static str,
+}
+
+const fn key(name: &'static str, env: &'static str, help: &'static str) -> Key {
+    Key { name, env, alias: None, switch: false, help }
+}
+
+pub const KEYS: &[Key] = &[
+    key("server.addr", "CHAT_ADDR", "address the server listens on and clients connect to [127.0.0.1:8000]"),
+    key("server.shards", "CHAT_BROKER_SHARDS", "broker shards [number of CPUs]"),
+    key("server.threads", "CHAT_THREADS", "connections threads_server serves at once [256]"),
+    key("server.workers", "CHAT_WORKERS", "threads decoding requests in kqueue_server, only worth it for large requests with spare cores [0]"),
+    key("server.shutdown_timeout", "CHAT_SHUTDOWN_TIMEOUT", "seconds clients get to receive queued frames on shutdown [5]"),
+    key("limits.broker_queue", "CHAT_BROKER_QUEUE", "events waiting for each broker shard [4096]"),
+    key("limits.client_queue", "CHAT_CLIENT_QUEUE", "frames waiting for each client [1024]"),
+    key("limits.client_policy", "CHAT_CLIENT_POLICY", "what to do when a client's queue is full [drop-ephemeral]"),
+    key("limits.link_queue", "CHAT_LINK_QUEUE", "frames waiting for each federation link [8192]"),
+    key("limits.link_policy", "CHAT_LINK_POLICY", "what to do when a link's queue is full [disconnect]"),
+    key("limits.connections_per_ip", "CHAT_CONNECTIONS_PER_IP", "clients kqueue_server keeps open per address, 0 is unlimited [64]"),
+    key("heartbeat.interval", "CHAT_HEARTBEAT_INTERVAL", "seconds between pings, by the servers and the client [10]"),
+    key("heartbeat.idle_timeout", "CHAT_IDLE_TIMEOUT", "seconds of silence before a client is evicted or the client reconnects [30]"),
+    key("rates.messages", "CHAT_MESSAGE_RATE", "frames per second per client, 0 is unlimited [10]"),
+    key("rates.message_burst", "CHAT_MESSAGE_BURST", "frames a client may send at once [20]"),
+    key("rates.bytes", "CHAT_BYTE_RATE", "bytes per second per client, 0 is unlimited [65536]"),
+    key("rates.byte_burst", "CHAT_BYTE_BURST", "bytes a client may send at once [262144]"),
+    key("rates.connections", "CHAT_CONNECTION_RATE", "connections per second per address, 0 is unlimited [1]"),
+    key("rates.connection_burst", "CHAT_CONNECTION_BURST", "connections an address may open at once [10]"),
+    key("bans.strikes", "CHAT_BAN_STRIKES", "violations before an address is banned, 0 never bans [5]"),
+    key("bans.window", "CHAT_BAN_WINDOW", "seconds violations are counted for [60]"),
+    key("bans.duration", "CHAT_BAN_DURATION", "seconds a ban lasts [300]"),
+    key("federation.server_name", "CHAT_SERVER_NAME", "name other servers know this one by"),
+    key("federation.secret", "CHAT_FEDERATION_SECRET", "shared secret, federation is off without it"),
+    key("federation.addr", "CHAT_FEDERATION_ADDR", "where to accept links from other servers"),
+    key("federation.peers", "CHAT_FEDERATION_PEERS", "servers that may link, as name=addr to dial them or just name, comma-separated"),
+    key("metrics.addr", "CHAT_METRICS_ADDR", "where to serve Prometheus metrics, e.g. 127.0.0.1:9100 [off]"),
+    key("admin.socket", "CHAT_ADMIN_SOCKET", "Unix socket chatctl talks to, needs admin.token [off]"),
+    key("admin.token", "CHAT_ADMIN_TOKEN", "token chatctl has to present"),
+    key("storage.path", "CHAT_STORAGE_PATH", "file to append the chat history to [off]"),
+    key("tls.cert", "CHAT_TLS_CERT", "PEM certificate chain the server presents, TLS is off without it"),
+    key("tls.key", "CHAT_TLS_KEY", "PEM private key of tls.cert"),
+    key("tls.ca", "CHAT_TLS_CA", "PEM certificates the client trusts, it connects over TLS if set"),
+    key("tls.server_name", "CHAT_TLS_SERVER_NAME", "name the server's certificate has to be for [host of server.addr]"),
+    key("log.level", "CHAT_LOG", "levels to log, e.g. debug or info,chat_rs=trace [info]"),
+    key("log.format", "CHAT_LOG_FORMAT", "text or json [text]"),
+    key("log.file", "CHAT_LOG_FILE", "file to append the log to [stderr, chat-client.log for the client]"),
+    key("log.message_contents", "CHAT_LOG_MESSAGE_CONTENTS", "log what messages say instead of their length [false]"),
+    key("client.direct_addr", "CHAT_DIRECT_ADDR", "address the client accepts direct links on [0.0.0.0:0]"),
+    Key {
+        alias: Some("lan"),
+        switch: true,
+        ..key("client.lan", "CHAT_LAN", "find peers on the local network instead of using a server [false]")
+    },
+];
+
+/// Where a value came from.
+#[derive(Debug, Clone)]
+enum Source {
+    File(PathBuf),
+    Env(&'static str),
+    Flag,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "in {}", path.display()),
+            Source::Env(var) => write!(f, "from {}", var),
+            Source::Flag => write!(f, "on the command line"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    values: HashMap<&'static str, (String, Source)>,
+}
+
+impl Config {
+    /// Reads the settings of this process. Prints the known settings and
+    /// exits on `--help`.
+    pub fn load() -> Result<Config, ConfigError> {
+        let mut args = std::env::args();
+        let program = args.next().unwrap_or_default();
+        let args: Vec<String> = args.collect();
+        if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+            print!("{}", usage(&program));
+            std::process::exit(0);
+        }
+
+        Config::from_sources(args, |var| std::env::var(var).ok())
+    }
+
+    /// Reads settings from the given command line arguments, without the
+    /// program name, and environment.
+    pub fn from_sources(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, ConfigError> {
+        let (mut config, positionals) = Config::with_positionals(args, env)?;
+        let mut positionals = positionals.into_iter();
+        if let Some(addr) = positionals.next() {
+            if config.values.get("server.addr").is_some_and(|(_, source)| matches!(source, Source::Flag)) {
+                return Err(ConfigError(format!("unexpected argument {:?}", addr)));
+            }
+            config.values.insert("server.addr", (addr, Source::Flag));
+        }
+        if let Some(arg) = positionals.next() {
+            return Err(ConfigError(format!("unexpected argument {:?}", arg)));
+        }
+
+        Ok(config)
+    }
+
+    /// Like `from_sources`, but hands back the arguments that aren't
+    /// settings instead of taking the first one for `server.addr`.
+    /// Everything after `--` is one of them.
+    pub fn with_positionals(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<(Config, Vec<String>), ConfigError> {
+        let mut flags = Vec::new();
+        let mut positionals = Vec::new();
+        let mut file = env("CHAT_CONFIG").map(PathBuf::from);
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                positionals.extend(args.by_ref());
+                break;
+            }
+            let Some(flag) = arg.strip_prefix("--") else {
+                positionals.push(arg);
+                continue;
+            };
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (flag, None),
+            };
+            if name == "config" {
+                let path = value.or_else(|| args.next())
+                    .ok_or_else(|| ConfigError("--config needs a file name".to_string()))?;
+                file = Some(PathBuf::from(path));
+                continue;
+            }
+            let key = KEYS.iter().find(|key| key.name == name || key.alias == Some(name))
+                .ok_or_else(|| ConfigError(format!("--{}: unknown setting, see --help", name)))?;
+            let value = match value {
+                Some(value) => value,
+                None if key.switch => String::from("true"),
+                None => args.next().ok_or_else(|| ConfigError(format!("--{} needs a value", name)))?,
+            };
+            flags.push((key, value));
+        }
+
+        let mut values = HashMap::new();
+        if let Some(path) = file {
+            let text = std::fs::read_to_string(&path)
+                .map_err(|e| ConfigError(format!("{}: {}", path.display(), e)))?;
+            let table: toml::Table = text.parse()
+                .map_err(|e| ConfigError(format!("{}: {}", path.display(), e)))?;
+            flatten(&table, "", &Source::File(path.clone()), &mut values)?;
+        }
+        for key in KEYS {
+            if let Some(value) = env(key.env) {
+                values.insert(key.name, (value, Source::Env(key.env)));
+            }
+        }
+        for (key, value) in flags {
+            values.insert(key.name, (value, Source::Flag));
+        }
+
+        Ok((Config { values }, positionals))
+    }
+
+    /// The raw value of `key`, if set anywhere.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.values.get(known(key)).map(|(value, _)| value.as_str())
+    }
+
+    /// The value of `key` parsed as a `T`, or `default` if it is not set.
+    pub fn get<T>(&self, key: &str, default: T) -> Result<T, ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        Ok(self.get_opt(key)?.unwrap_or(default))
+    }
+
+    /// The value of `key` parsed as a `T`, if set.
+    pub fn get_opt<T>(&self, key: &str) -> Result<Option<T>, ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.get_str(key)
+            .map(|value| value.parse().map_err(|e| self.invalid(key, e)))
+            .transpose()
+    }
+
+    /// The value of `key` in whole seconds, at most `MAX_SECS`.
+    pub fn secs(&self, key: &str, default: u64) -> Result<Duration, ConfigError> {
+        let secs = self.get(key, default)?;
+        if secs > MAX_SECS {
+            return Err(self.invalid(key, format_args!("must be at most {} seconds, a year", MAX_SECS)));
+        }
+
+        Ok(Duration::from_secs(secs))
+    }
+
+    /// Keys whose value differs in `other`, including ones set in only one
+    /// of them.
+    pub fn changed(&self, other: &Config) -> Vec<&'static str> {
+        KEYS.iter()
+            .map(|key| key.name)
+            .filter(|key| self.get_str(key) != other.get_str(key))
+            .collect()
+    }
+
+    /// Fails on the first of `keys` that is set, for settings a binary
+    /// doesn't support and mustn't silently ignore.
+    pub fn unsupported(&self, keys: &[&str]) -> Result<(), ConfigError> {
+        match keys.iter().find(|key| self.get_str(key).is_some()) {
+            Some(key) => Err(self.invalid(key, "not supported by this server, use async_std_server")),
+            None => Ok(()),
+        }
+    }
+
+    /// An error about the value of `key`, for checks beyond parsing.
+    pub fn invalid(&self, key: &str, problem: impl fmt::Display) -> ConfigError {
+        match self.values.get(known(key)) {
+            Some((value, source)) => ConfigError(format!("{} = {:?} ({}): {}", key, value, source, problem)),
+            None => ConfigError(format!("{}: {}", key, problem)),
+        }
+    }
+}
+
+fn find(name: &str) -> Option<&'static Key> {
+    KEYS.iter().find(|key| key.name == name)
+}
+
+/// Looking up a key that is not in [`KEYS`] is a bug.
+fn known(name: &str) -> &'static str {
+    find(name).unwrap_or_else(|| panic!("unknown config key {}", name)).name
+}
+
+/// Turns nested tables into dotted keys. Lists and tables given for a
+/// single setting become `a,b` and `name=a,name=b`.
+fn flatten(
+    table: &toml::Table,
+    prefix: &str,
+    source: &Source,
+    values: &mut HashMap<&'static str, (String, Source)>,
+) -> Result<(), ConfigError> {
+    for (name, value) in table {
+        let path = format!("{}{}", prefix, name);
+        let Some(key) = find(&path) else {
+            match value {
+                toml::Value::Table(table) => flatten(table, &format!("{}.", path), source, values)?,
+                _ => return Err(ConfigError(format!("{} ({}): unknown setting", path, source))),
+            }
+            continue;
+        };
+        let value = match value {
+            toml::Value::Array(items) => items.iter().map(scalar).collect::<Vec<_>>().join(","),
+            toml::Value::Table(items) => items.iter()
+                .map(|(name, item)| format!("{}={}", name, scalar(item)))
+                .collect::<Vec<_>>()
+                .join(","),
+            value => scalar(value),
+        };
+        values.insert(key.name, (value, source.clone()));
+    }
+
+    Ok(())
+}
+
+fn scalar(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn usage(program: &str) -> String {
+    let mut usage = format!("Usage: {} [ADDR] [--config FILE] [--KEY VALUE]...\n\nSettings:\n", program);
+    for key in KEYS {
+        usage += &format!("  --{:<26}{:<26}{}\n", key.name, key.env, key.help);
+    }
+
+    usage
+}
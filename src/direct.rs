@@ -0,0 +1,262 @@
+//! Direct client-to-client links.
+//!
+//! The server only tells clients where their peers listen (see
+//! `ClientFrame::Rendezvous`). Messages then travel over a plain TCP
+//! connection between the two clients using [`PeerFrame`]s.
+//!
+//! Links are only accepted from peers the server or the local network
+//! announced (see [`DirectLinks::expect`]), and whatever a peer claims, its
+//! messages are from the name its link is known by.
+
+use crate::protocol::{read_frame, write_frame, Message, PeerFrame, UserId};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a peer that stopped reading may hold up a send. The link is
+/// dropped after that and messages go through the server again.
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long an accepted connection has to introduce itself.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+/// Accepted connections that may wait for their `Hello` at once, the rest
+/// is closed right away.
+const MAX_PENDING_HELLOS: usize = 16;
+
+pub enum PeerEvent {
+    Connected(String),
+    Message(Message),
+    Disconnected(String),
+}
+
+type Handler = Arc<dyn Fn(PeerEvent) + Send + Sync>;
+
+/// Open direct links keyed by the peer's nickname.
+#[derive(Clone)]
+pub struct DirectLinks {
+    links: Arc<Mutex<HashMap<String, Link>>>,
+    /// Peers that may link to us, with the addresses they were announced
+    /// on. No addresses means any.
+    expected: Arc<Mutex<HashMap<String, Vec<IpAddr>>>>,
+    /// Accepted connections that haven't said `Hello` yet.
+    pending: Arc<AtomicUsize>,
+    next_id: Arc<AtomicU64>,
+    handler: Handler,
+}
+
+struct Link {
+    /// Tells the link's reader which entry is its own after renames.
+    id: u64,
+    /// Locked apart from the links, so that a slow peer only holds up
+    /// sends to itself.
+    writer: Arc<Mutex<BufWriter<TcpStream>>>,
+}
+
+impl DirectLinks {
+    /// Starts accepting direct links on `addr`. Every event coming from
+    /// any link is passed to `handler` on the link's reader thread.
+    pub fn listen<A, F>(addr: A, handler: F) -> io::Result<(DirectLinks, SocketAddr)>
+    where
+        A: ToSocketAddrs,
+        F: Fn(PeerEvent) + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let links = DirectLinks {
+            links: Arc::new(Mutex::new(HashMap::new())),
+            expected: Arc::new(Mutex::new(HashMap::new())),
+            pending: Arc::new(AtomicUsize::new(0)),
+            next_id: Arc::new(AtomicU64::new(0)),
+            handler: Arc::new(handler),
+        };
+
+        let acceptor = links.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if acceptor.pending.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_HELLOS {
+                    acceptor.pending.fetch_sub(1, Ordering::SeqCst);
+                    debug!("Too many direct links introducing themselves, closing one");
+                    continue;
+                }
+                let links = acceptor.clone();
+                thread::spawn(move || {
+                    let accepted = links.accept(stream);
+                    links.pending.fetch_sub(1, Ordering::SeqCst);
+                    match accepted {
+                        Ok((id, reader)) => links.run(id, reader),
+                        Err(e) => warn!("Refused a direct link: {}", e),
+                    }
+                });
+            }
+        });
+
+        Ok((links, local_addr))
+    }
+
+    /// Lets `peer` link to us from any of `addrs`, as the server announced
+    /// it on rendezvous or the local network heard it.
+    pub fn expect(&self, peer: &str, addrs: &[SocketAddr]) {
+        let ips = addrs.iter().map(SocketAddr::ip).collect();
+        self.expected.lock().unwrap().insert(peer.to_string(), ips);
+    }
+
+    /// Tries every candidate address of `peer` in turn and introduces us
+    /// as `me` on the first one that accepts the connection.
+    pub fn connect(&self, me: &str, peer: &str, addrs: &[SocketAddr]) -> io::Result<()> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no candidate addresses");
+        if self.is_connected(peer) {
+            return Ok(());
+        }
+
+        for addr in addrs {
+            let stream = match TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    last_error = e;
+                    continue;
+                }
+            };
+            stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            let mut writer = BufWriter::new(stream.try_clone()?);
+            write_frame(&mut writer, &PeerFrame::Hello { name: me.to_string() })?;
+
+            let links = self.clone();
+            let reader = BufReader::new(stream.try_clone()?);
+            // The peer may have linked to us meanwhile, that link stays.
+            if let Some(id) = self.register(peer, stream) {
+                thread::spawn(move || links.run(id, reader));
+            }
+
+            return Ok(());
+        }
+
+        Err(last_error)
+    }
+
+    pub fn is_connected(&self, peer: &str) -> bool {
+        self.links.lock().unwrap().contains_key(peer)
+    }
+
+    /// Nicknames of every peer with an open link.
+    pub fn peers(&self) -> Vec<String> {
+        self.links.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Sends `message` over the direct link to `peer`. Returns `false` if
+    /// there is no link or it broke, in which case the caller should relay
+    /// the message through the server instead. A peer that doesn't take
+    /// the message within `WRITE_TIMEOUT` counts as broken.
+    pub fn send(&self, peer: &str, message: Message) -> bool {
+        let (id, writer) = match self.links.lock().unwrap().get(peer) {
+            Some(link) => (link.id, Arc::clone(&link.writer)),
+            None => return false,
+        };
+        let mut writer = writer.lock().unwrap();
+        if write_frame(&mut *writer, &PeerFrame::Message(message)).is_ok() {
+            return true;
+        }
+
+        // Part of the frame may be out, nothing more can go over the link.
+        debug!(peer, "Direct link broke, dropping it");
+        let _ = writer.get_ref().shutdown(Shutdown::Both);
+        let mut links = self.links.lock().unwrap();
+        if let Some(current) = peer_on(&links, id) {
+            links.remove(&current);
+        }
+
+        false
+    }
+
+    /// Keeps links reachable after the peer changed its nickname.
+    pub fn rename(&self, old: &str, new: &str) {
+        let mut links = self.links.lock().unwrap();
+        if let Some(link) = links.remove(old) {
+            links.insert(new.to_string(), link);
+        }
+        let mut expected = self.expected.lock().unwrap();
+        if let Some(ips) = expected.remove(old) {
+            expected.insert(new.to_string(), ips);
+        }
+    }
+
+    /// Waits for the `Hello` of a connection we accepted and registers the
+    /// link if it comes from an expected peer we have no link with yet.
+    fn accept(&self, stream: TcpStream) -> io::Result<(u64, BufReader<TcpStream>)> {
+        let ip = stream.peer_addr()?.ip();
+        stream.set_read_timeout(Some(HELLO_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let name = match read_frame(&mut reader)? {
+            Some(PeerFrame::Hello { name }) => name,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("no hello from {}", ip))),
+        };
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+        let expected = match self.expected.lock().unwrap().get(&name) {
+            Some(ips) => ips.is_empty() || ips.contains(&ip),
+            None => false,
+        };
+        if !expected {
+            let e = format!("{} is not expected to link from {}", name, ip);
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, e));
+        }
+        match self.register(&name, stream) {
+            Some(id) => Ok((id, reader)),
+            None => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("already linked with {}", name))),
+        }
+    }
+
+    /// Adds the link to `peer` unless there is one already. Returns the id
+    /// of the new link.
+    fn register(&self, peer: &str, stream: TcpStream) -> Option<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        match self.links.lock().unwrap().entry(peer.to_string()) {
+            Entry::Occupied(_) => None,
+            Entry::Vacant(entry) => {
+                entry.insert(Link { id, writer: Arc::new(Mutex::new(BufWriter::new(stream))) });
+                Some(id)
+            }
+        }
+    }
+
+    fn run(&self, id: u64, mut reader: BufReader<TcpStream>) {
+        let Some(mut peer) = peer_on(&self.links.lock().unwrap(), id) else {
+            return;
+        };
+        (self.handler)(PeerEvent::Connected(peer.clone()));
+
+        while let Ok(Some(frame)) = read_frame(&mut reader) {
+            match frame {
+                PeerFrame::Hello { .. } => continue,
+                PeerFrame::Message(message) => {
+                    // Dropped by `send` when writing to it failed.
+                    let Some(current) = peer_on(&self.links.lock().unwrap(), id) else {
+                        break;
+                    };
+                    peer = current;
+                    let from = UserId(peer.clone());
+                    (self.handler)(PeerEvent::Message(Message { from, ..message }));
+                }
+            }
+        }
+
+        let mut links = self.links.lock().unwrap();
+        if let Some(current) = peer_on(&links, id) {
+            links.remove(&current);
+            peer = current;
+        }
+        drop(links);
+        (self.handler)(PeerEvent::Disconnected(peer));
+    }
+}
+
+/// Name of the peer on link `id`, which follows renames.
+fn peer_on(links: &HashMap<String, Link>, id: u64) -> Option<String> {
+    links.iter().find(|(_, link)| link.id == id).map(|(peer, _)| peer.clone())
+}
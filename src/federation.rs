@@ -0,0 +1,129 @@
+//! Links between federated chat servers.
+//!
+//! Servers that share a secret can exchange messages for each other's users
+//! and groups, which are addressed as `name@server`. Links are plain TCP
+//! connections carrying [`LinkFrame`]s. Both ends authenticate each other
+//! by answering the other's random challenge with an HMAC over the shared
+//! secret. Only the servers listed in `federation.peers` may link.
+
+use crate::config::{Config, ConfigError};
+use crate::protocol::{encode_frame, read_frame_async, LinkFrame};
+use futures::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::io;
+use std::net::SocketAddr;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 32;
+
+/// Federation settings, see `federation.*` in [`crate::config`]. The link
+/// to other servers is off unless `federation.secret` is set.
+#[derive(Debug, Clone)]
+pub struct Federation {
+    pub server_name: String,
+    pub secret: Vec<u8>,
+    pub listen_addr: Option<SocketAddr>,
+    /// Servers that may link with this one, and where to dial them. Those
+    /// without an address dial us.
+    pub peers: Vec<(String, Option<SocketAddr>)>,
+}
+
+impl Federation {
+    pub fn from_config(config: &Config) -> Result<Option<Federation>, ConfigError> {
+        let secret = match config.get_str("federation.secret") {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => return Ok(None),
+        };
+        let server_name = config.get_str("federation.server_name")
+            .ok_or_else(|| config.invalid("federation.server_name", "required when federation is enabled"))?
+            .to_string();
+        if server_name.is_empty() || server_name.contains('@') {
+            return Err(config.invalid("federation.server_name", "not a valid server name"));
+        }
+
+        let listen_addr = config.get_opt("federation.addr")?;
+
+        let mut peers = Vec::new();
+        for peer in config.get_str("federation.peers").unwrap_or_default().split(',') {
+            if peer.trim().is_empty() {
+                continue;
+            }
+            let (name, addr) = match peer.split_once('=') {
+                Some((name, addr)) => {
+                    let addr = addr.trim().parse()
+                        .map_err(|e| config.invalid("federation.peers", format!("{}: {}", addr, e)))?;
+                    (name.trim(), Some(addr))
+                }
+                None => (peer.trim(), None),
+            };
+            if name.is_empty() || name.contains('@') || name == server_name {
+                return Err(config.invalid("federation.peers", format!("{:?} is not a valid server name", name)));
+            }
+            peers.push((name.to_string(), addr));
+        }
+
+        Ok(Some(Federation { server_name, secret, listen_addr, peers }))
+    }
+
+    /// Whether `server` may link with this one.
+    pub fn is_peer(&self, server: &str) -> bool {
+        self.peers.iter().any(|(name, _)| name == server)
+    }
+}
+
+/// Splits `name@server` into its parts. Returns `None` for the server part
+/// if the address is local to this server.
+pub fn split_address<'a>(address: &'a str, local_server: &str) -> (&'a str, Option<&'a str>) {
+    match address.rsplit_once('@') {
+        Some((name, server)) if server != local_server => (name, Some(server)),
+        Some((name, _)) => (name, None),
+        None => (address, None),
+    }
+}
+
+/// Authenticates both ends of a freshly opened link and returns the name
+/// of the server on the other end. Servers that aren't peers are refused
+/// before their challenge is answered.
+pub async fn handshake<S>(stream: &mut S, federation: &Federation) -> io::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (me, secret) = (federation.server_name.as_str(), federation.secret.as_slice());
+    let mut nonce = vec![0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let hello = LinkFrame::Hello { server: me.to_string(), nonce: nonce.clone() };
+    stream.write_all(&encode_frame(&hello)?).await?;
+
+    let (peer, peer_nonce) = match read_frame_async(stream).await? {
+        Some(LinkFrame::Hello { server, nonce }) if server != me => (server, nonce),
+        _ => return Err(denied("expected hello from another server")),
+    };
+    if !federation.is_peer(&peer) {
+        return Err(denied("not a federation peer"));
+    }
+    let auth = LinkFrame::Auth { mac: sign(secret, &peer_nonce, me).finalize().into_bytes().to_vec() };
+    stream.write_all(&encode_frame(&auth)?).await?;
+
+    match read_frame_async(stream).await? {
+        Some(LinkFrame::Auth { mac }) => sign(secret, &nonce, &peer)
+            .verify_slice(&mac)
+            .map_err(|_| denied("bad federation secret"))?,
+        _ => return Err(denied("expected auth")),
+    }
+
+    Ok(peer)
+}
+
+fn sign(secret: &[u8], nonce: &[u8], server: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(nonce);
+    mac.update(server.as_bytes());
+    mac
+}
+
+fn denied(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, reason)
+}
@@ -0,0 +1,82 @@
+//! Serverless chat on the local network.
+//!
+//! Every client periodically announces its nickname and direct link port
+//! to a multicast group. Whoever hears an announcement opens a direct link
+//! (see [`crate::direct`]) to the announcer, so messages never touch a
+//! server.
+
+use crate::direct::DirectLinks;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+/// Multicast group and port LAN clients announce themselves on.
+pub const LAN_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 70, 80), 8070);
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Announcement {
+    name: String,
+    /// Port of the announcer's direct link listener.
+    port: u16,
+}
+
+/// Starts announcing `me` and linking up with every other client heard on
+/// the local network segment.
+pub fn discover(me: String, direct_port: u16, links: DirectLinks) -> io::Result<()> {
+    let socket = multicast_socket()?;
+    let announcer = socket.try_clone()?;
+    let announcement = bincode::serialize(&Announcement {
+        name: me.clone(),
+        port: direct_port,
+    })
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    thread::spawn(move || loop {
+        if let Err(e) = announcer.send_to(&announcement, LAN_GROUP) {
+            warn!("Could not announce on the local network: {}", e);
+        }
+        thread::sleep(ANNOUNCE_INTERVAL);
+    });
+
+    thread::spawn(move || {
+        let mut buf = [0u8; 512];
+        while let Ok((n, from)) = socket.recv_from(&mut buf) {
+            let peer = match bincode::deserialize::<Announcement>(&buf[..n]) {
+                Ok(peer) => peer,
+                Err(_) => continue,
+            };
+            if peer.name == me {
+                continue;
+            }
+            let addrs = [SocketAddr::new(from.ip(), peer.port)];
+            links.expect(&peer.name, &addrs);
+            // Both sides hear each other, only the smaller name dials so
+            // that a pair of peers ends up with a single link.
+            if peer.name < me || links.is_connected(&peer.name) {
+                continue;
+            }
+            let _ = links.connect(&me, &peer.name, &addrs);
+        }
+    });
+
+    Ok(())
+}
+
+fn multicast_socket() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Several clients on one host all listen on the group port.
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LAN_GROUP.port())).into())?;
+    socket.join_multicast_v4(LAN_GROUP.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+
+    Ok(socket.into())
+}
@@ -0,0 +1,15 @@
+pub mod admin;
+pub mod bench;
+pub mod config;
+pub mod direct;
+pub mod federation;
+pub mod lan;
+pub mod logging;
+pub mod metrics;
+pub mod outbox;
+pub mod poller;
+pub mod protocol;
+pub mod ratelimit;
+pub mod server;
+pub mod storage;
+pub mod tls;
@@ -0,0 +1,86 @@
+//! Leveled, structured diagnostics for the binaries, built on `tracing`.
+//!
+//! See `log.*` in [`crate::config`]. What users write to each other is
+//! left out of the log unless `log.message_contents` is on, wrap it in
+//! [`contents`] before logging it.
+
+use crate::config::{Config, ConfigError};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+static MESSAGE_CONTENTS: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format {:?}, expected text or json", s)),
+        }
+    }
+}
+
+/// Installs the global subscriber. Logs go to `log.file`, or to
+/// `default_file` if that isn't set, or else to stderr.
+pub fn init(config: &Config, default_file: Option<&str>) -> Result<(), ConfigError> {
+    let level = config.get_str("log.level").unwrap_or("info");
+    let filter = EnvFilter::try_new(level).map_err(|e| config.invalid("log.level", e))?;
+    let format = config.get("log.format", Format::Text)?;
+    MESSAGE_CONTENTS.store(config.get("log.message_contents", false)?, Ordering::Relaxed);
+
+    let (writer, ansi) = match config.get_str("log.file").or(default_file) {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| config.invalid("log.file", format!("{}: {}", path, e)))?;
+            (BoxMakeWriter::new(Mutex::new(file)), false)
+        }
+        None => (BoxMakeWriter::new(std::io::stderr), std::io::stderr().is_terminal()),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(ansi);
+    // A subscriber installed earlier stays in place.
+    let _ = match format {
+        Format::Text => builder.try_init(),
+        Format::Json => builder.json().try_init(),
+    };
+
+    Ok(())
+}
+
+/// What a user wrote, logged as its length unless `log.message_contents`
+/// is on.
+pub fn contents(text: &str) -> Contents<'_> {
+    Contents(text)
+}
+
+pub struct Contents<'a>(&'a str);
+
+impl fmt::Display for Contents<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if MESSAGE_CONTENTS.load(Ordering::Relaxed) {
+            write!(f, "{:?}", self.0)
+        } else {
+            write!(f, "<{} bytes>", self.0.len())
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! Prometheus metrics.
+//!
+//! Servers keep [`Counter`]s, [`Gauge`]s and [`Histogram`]s, write them out
+//! with an [`Exposition`] when scraped and answer scrapes with [`serve`].
+//! Names follow the Prometheus conventions: counters end in `_total`,
+//! durations are in seconds.
+
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use futures::StreamExt;
+use std::fmt::{self, Write as _};
+use std::future::Future;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Longest scrape request we read before giving up on the client.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bounds of the buckets used for latencies, in seconds.
+pub const LATENCY_BUCKETS: &[f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// A value that only goes up.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub const fn new() -> Gauge {
+        Gauge(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts observations into buckets with the given upper bounds.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct HistogramState {
+    /// Observations per bucket, not cumulative. Sized on first use.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub const fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            state: Mutex::new(HistogramState { counts: Vec::new(), sum: 0.0, count: 0 }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.counts.resize(self.bounds.len(), 0);
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            state.counts[bucket] += 1;
+        }
+        state.sum += value;
+        state.count += 1;
+    }
+}
+
+/// Metrics in the Prometheus text format, version 0.0.4.
+#[derive(Debug, Default)]
+pub struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    pub fn new() -> Exposition {
+        Exposition::default()
+    }
+
+    /// Starts metric `name` of `kind`, e.g. `counter` or `gauge`. Its
+    /// samples follow with [`Exposition::sample`].
+    pub fn describe(&mut self, name: &str, kind: &str, help: &str) -> &mut Exposition {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+        self
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl fmt::Display) -> &mut Exposition {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            self.text.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.text.push(',');
+                }
+                let _ = write!(self.text, "{}=\"{}\"", label, escape(value));
+            }
+            self.text.push('}');
+        }
+        let _ = writeln!(self.text, " {}", value);
+        self
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, counter: &Counter) -> &mut Exposition {
+        self.describe(name, "counter", help).sample(name, &[], counter.get())
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, gauge: &Gauge) -> &mut Exposition {
+        self.describe(name, "gauge", help).sample(name, &[], gauge.get())
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) -> &mut Exposition {
+        let state = histogram.state.lock().unwrap().clone();
+        self.describe(name, "histogram", help);
+        let bucket = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (i, bound) in histogram.bounds.iter().enumerate() {
+            cumulative += state.counts.get(i).copied().unwrap_or(0);
+            self.sample(&bucket, &[("le", &bound.to_string())], cumulative);
+        }
+        self.sample(&bucket, &[("le", "+Inf")], state.count);
+        self.sample(&format!("{}_sum", name), &[], state.sum);
+        self.sample(&format!("{}_count", name), &[], state.count)
+    }
+
+    pub fn into_text(self) -> String {
+        self.text
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Answers `GET /metrics` on `addr` with whatever `render` returns. Other
+/// paths get a 404.
+pub async fn serve<A, F, R>(addr: A, render: F) -> std::io::Result<()>
+where
+    A: ToSocketAddrs,
+    F: Fn() -> R,
+    R: Future<Output = String>,
+{
+    let listener = TcpListener::bind(addr).await?;
+    info!(addr = %listener.local_addr()?, "Serving metrics");
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Could not accept a metrics scrape: {}", e);
+                continue;
+            }
+        };
+        // Scrapes are rare and small, answer them one at a time.
+        match async_std::future::timeout(SCRAPE_TIMEOUT, answer(stream, &render)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => warn!("Could not answer a metrics scrape: {}", e),
+            Err(_) => warn!("Metrics scrape timed out"),
+        }
+    }
+
+    Ok(())
+}
+
+async fn answer<F, R>(mut stream: TcpStream, render: &F) -> std::io::Result<()>
+where
+    F: Fn() -> R,
+    R: Future<Output = String>,
+{
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request_line = request.split(|b| *b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|b| *b == b' ');
+    let response = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            let body = render().await;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body,
+            )
+        }
+        _ => String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+    };
+    stream.write_all(response.as_bytes()).await
+}
@@ -0,0 +1,231 @@
+//! Bounded queues of outbound frames.
+//!
+//! Every connection gets an [`Outbox`] that the broker pushes encoded frames
+//! into and a writer task drains. When a consumer can't keep up and its
+//! outbox is full, the configured [`Policy`] decides what gives.
+
+use async_std::channel::{self, Receiver, Sender};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// What to do when a frame is pushed into a full outbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Drop the oldest queued frame.
+    DropOldest,
+    /// Drop the oldest queued ephemeral frame (see
+    /// `ServerFrame::is_ephemeral`), or the oldest frame if there is none.
+    DropEphemeral,
+    /// Stop delivering and disconnect the consumer.
+    Disconnect,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Policy::DropOldest),
+            "drop-ephemeral" => Ok(Policy::DropEphemeral),
+            "disconnect" => Ok(Policy::Disconnect),
+            _ => Err(format!(
+                "unknown slow consumer policy {:?}, expected drop-oldest, drop-ephemeral or disconnect",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Policy::DropOldest => "drop-oldest",
+            Policy::DropEphemeral => "drop-ephemeral",
+            Policy::Disconnect => "disconnect",
+        })
+    }
+}
+
+struct Frame {
+    bytes: Vec<u8>,
+    ephemeral: bool,
+}
+
+struct State {
+    frames: VecDeque<Frame>,
+    /// Set once a full outbox with `Policy::Disconnect` was pushed to. The
+    /// frames queued at that point stay around for `drain`.
+    overflowed: bool,
+    /// Set by `Outbox::close`, the consumer stops once the queue is empty.
+    closed: bool,
+    dropped: u64,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: Policy,
+}
+
+/// Producer side of an outbox, cheap to clone.
+#[derive(Clone)]
+pub struct Outbox {
+    shared: Arc<Shared>,
+    /// Wakes the consumer up. Dropping every `Outbox` closes it, which tells
+    /// the consumer there will be no more frames.
+    doorbell: Sender<()>,
+}
+
+/// Consumer side of an outbox.
+pub struct OutboxReceiver {
+    shared: Arc<Shared>,
+    doorbell: Receiver<()>,
+}
+
+pub fn outbox(capacity: usize, policy: Policy) -> (Outbox, OutboxReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            frames: VecDeque::new(),
+            overflowed: false,
+            closed: false,
+            dropped: 0,
+        }),
+        capacity: capacity.max(1),
+        policy,
+    });
+    let (doorbell_sender, doorbell_receiver) = channel::bounded(1);
+
+    (
+        Outbox { shared: Arc::clone(&shared), doorbell: doorbell_sender },
+        OutboxReceiver { shared, doorbell: doorbell_receiver },
+    )
+}
+
+impl fmt::Debug for Outbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Outbox")
+            .field("len", &self.len())
+            .field("capacity", &self.shared.capacity)
+            .field("policy", &self.shared.policy)
+            .finish()
+    }
+}
+
+impl fmt::Debug for OutboxReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutboxReceiver")
+            .field("overflowed", &self.overflowed())
+            .finish()
+    }
+}
+
+impl Outbox {
+    /// Queues `bytes`. Returns `false` if the consumer has been cut off for
+    /// falling behind or the outbox was closed, and nothing is delivered to
+    /// it anymore.
+    pub fn push(&self, bytes: Vec<u8>, ephemeral: bool) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.overflowed || state.closed {
+            return false;
+        }
+
+        if state.frames.len() >= self.shared.capacity {
+            match self.shared.policy {
+                Policy::DropOldest => {
+                    state.frames.pop_front();
+                }
+                Policy::DropEphemeral => {
+                    match state.frames.iter().position(|frame| frame.ephemeral) {
+                        Some(i) => state.frames.remove(i),
+                        None => state.frames.pop_front(),
+                    };
+                }
+                Policy::Disconnect => {
+                    state.overflowed = true;
+                    drop(state);
+                    self.ring();
+                    return false;
+                }
+            }
+            state.dropped += 1;
+        }
+        state.frames.push_back(Frame { bytes, ephemeral });
+        drop(state);
+        self.ring();
+
+        true
+    }
+
+    /// Number of frames waiting to be written.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    /// Frames dropped so far because the outbox was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.state.lock().unwrap().dropped
+    }
+
+    /// Stops accepting frames. The consumer still gets what is queued, even
+    /// while other clones of this `Outbox` are around.
+    pub fn close(&self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.ring();
+    }
+
+    pub fn is_connected_to(&self, receiver: &OutboxReceiver) -> bool {
+        Arc::ptr_eq(&self.shared, &receiver.shared)
+    }
+
+    fn ring(&self) {
+        // A full doorbell already has the consumer's attention.
+        let _ = self.doorbell.try_send(());
+    }
+}
+
+impl OutboxReceiver {
+    /// Waits for the next frame. Returns `None` once every `Outbox` is gone
+    /// or it was closed and the queue is empty, or as soon as the consumer
+    /// was cut off.
+    pub async fn next(&mut self) -> Option<Vec<u8>> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.overflowed {
+                    return None;
+                }
+                if let Some(frame) = state.frames.pop_front() {
+                    return Some(frame.bytes);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            if self.doorbell.recv().await.is_err() {
+                let mut state = self.shared.state.lock().unwrap();
+                return state.frames.pop_front().map(|frame| frame.bytes);
+            }
+        }
+    }
+
+    /// Whether the consumer was cut off for falling behind.
+    pub fn overflowed(&self) -> bool {
+        self.shared.state.lock().unwrap().overflowed
+    }
+
+    /// Takes every frame that hasn't been written yet.
+    pub fn drain(&mut self) -> Vec<Vec<u8>> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.frames.drain(..).map(|frame| frame.bytes).collect()
+    }
+}
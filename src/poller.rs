@@ -0,0 +1,424 @@
+//! Readiness polling for the hand-rolled servers.
+//!
+//! A [`Poller`] watches file descriptors for the [`Interest`]s they were
+//! registered with and reports them as [`Event`]s carrying the caller's
+//! [`Token`]. Every backend is level-triggered: a descriptor is reported
+//! again on each wait until whatever made it ready is consumed.
+//!
+//! [`Kqueue`] is used on BSD and macOS, [`Epoll`] on Linux and [`Poll`]
+//! works on any Unix. [`DefaultPoller`] is the best one for the target.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Identifies a registered descriptor in its events.
+pub type Token = usize;
+
+/// Events fetched by one `wait` at most.
+const MAX_EVENTS: usize = 256;
+
+/// Readiness a descriptor is watched for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interest {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl Interest {
+    pub const READABLE: Interest = Interest { readable: true, writable: false };
+    pub const WRITABLE: Interest = Interest { readable: false, writable: true };
+    pub const BOTH: Interest = Interest { readable: true, writable: true };
+}
+
+/// Readiness of a registered descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub token: Token,
+    pub readable: bool,
+    pub writable: bool,
+    /// The peer hung up or the descriptor failed. Backends that can't tell
+    /// report the descriptor as readable, and reading it returns EOF.
+    pub closed: bool,
+}
+
+/// A readiness queue. Descriptors may be registered from one thread while
+/// another waits; the waiting thread picks up the change.
+pub trait Poller: Send + Sync {
+    /// Starts watching `fd` for `interest`, reporting it as `token`.
+    fn register(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()>;
+
+    /// Changes the token and interest of an already registered `fd`.
+    fn modify(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()>;
+
+    /// Stops watching `fd`. Has to happen before `fd` is closed.
+    fn deregister(&self, fd: RawFd) -> io::Result<()>;
+
+    /// Replaces `events` with the descriptors that are ready, waiting up to
+    /// `timeout` for one, or forever if it is `None`. It may also return
+    /// early with no events, e.g. when interrupted by a signal.
+    fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+))]
+pub type DefaultPoller = Kqueue;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub type DefaultPoller = Epoll;
+#[cfg(not(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+    target_os = "linux",
+    target_os = "android",
+)))]
+pub type DefaultPoller = Poll;
+
+/// Turns a `-1` return of a libc call into the error it set.
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n),
+    }
+}
+
+/// `timeout` in milliseconds, rounded up so that short waits don't spin,
+/// or -1 for none.
+fn timeout_ms(timeout: Option<Duration>) -> libc::c_int {
+    match timeout {
+        Some(timeout) => {
+            let ms = timeout.as_nanos().div_ceil(1_000_000);
+            ms.min(libc::c_int::MAX as u128) as libc::c_int
+        }
+        None => -1,
+    }
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+))]
+pub use kqueue::Kqueue;
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+))]
+mod kqueue {
+    use super::{check, Event, Interest, Poller, Token, MAX_EVENTS};
+    use std::io;
+    use std::os::fd::RawFd;
+    use std::ptr::null_mut;
+    use std::time::Duration;
+
+    #[derive(Debug)]
+    pub struct Kqueue {
+        fd: RawFd,
+    }
+
+    impl Kqueue {
+        pub fn new() -> io::Result<Kqueue> {
+            let fd = check(unsafe { libc::kqueue() })?;
+            check(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+
+            Ok(Kqueue { fd })
+        }
+
+        /// Applies `flags` to the read and write filters of `fd`.
+        fn apply(&self, fd: RawFd, token: Token, read_flags: u16, write_flags: u16) -> io::Result<()> {
+            let change = |filter, flags| {
+                // Zeroed first, some BSDs have extra fields.
+                let mut change: libc::kevent = unsafe { std::mem::zeroed() };
+                change.ident = fd as libc::uintptr_t;
+                change.filter = filter;
+                change.flags = flags;
+                change.udata = token as _;
+                change
+            };
+            let changes = [change(libc::EVFILT_READ, read_flags), change(libc::EVFILT_WRITE, write_flags)];
+            check(unsafe {
+                libc::kevent(self.fd, changes.as_ptr(), changes.len() as _, null_mut(), 0, std::ptr::null())
+            })?;
+
+            Ok(())
+        }
+
+        /// Both filters are always added, the unwanted one disabled, so
+        /// that `modify` and `deregister` don't have to know which exist.
+        fn flags(wanted: bool) -> u16 {
+            libc::EV_ADD | if wanted { libc::EV_ENABLE } else { libc::EV_DISABLE }
+        }
+    }
+
+    impl Poller for Kqueue {
+        fn register(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+            self.apply(fd, token, Kqueue::flags(interest.readable), Kqueue::flags(interest.writable))
+        }
+
+        fn modify(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+            self.register(fd, token, interest)
+        }
+
+        fn deregister(&self, fd: RawFd) -> io::Result<()> {
+            self.apply(fd, 0, libc::EV_DELETE, libc::EV_DELETE)
+        }
+
+        fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+            events.clear();
+            let timeout = timeout.map(|timeout| libc::timespec {
+                tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+                tv_nsec: timeout.subsec_nanos() as _,
+            });
+            let mut ready = Vec::<libc::kevent>::with_capacity(MAX_EVENTS);
+            let n = unsafe {
+                libc::kevent(
+                    self.fd,
+                    std::ptr::null(),
+                    0,
+                    ready.as_mut_ptr(),
+                    MAX_EVENTS as _,
+                    timeout.as_ref().map_or(std::ptr::null(), |timeout| timeout as *const _),
+                )
+            };
+            let n = match check(n) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            // The kernel filled in the first `n`.
+            unsafe { ready.set_len(n as usize) };
+
+            // One event per filter, so a descriptor can show up twice.
+            events.extend(ready.iter().map(|k| Event {
+                token: k.udata as Token,
+                readable: k.filter == libc::EVFILT_READ,
+                writable: k.filter == libc::EVFILT_WRITE,
+                closed: k.flags & (libc::EV_EOF | libc::EV_ERROR) != 0,
+            }));
+
+            Ok(())
+        }
+    }
+
+    impl Drop for Kqueue {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use epoll::Epoll;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod epoll {
+    use super::{check, timeout_ms, Event, Interest, Poller, Token, MAX_EVENTS};
+    use std::io;
+    use std::os::fd::RawFd;
+    use std::time::Duration;
+
+    #[derive(Debug)]
+    pub struct Epoll {
+        fd: RawFd,
+    }
+
+    impl Epoll {
+        pub fn new() -> io::Result<Epoll> {
+            let fd = check(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+
+            Ok(Epoll { fd })
+        }
+
+        fn control(&self, op: libc::c_int, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+            // Hang-ups are always reported, `EPOLLRDHUP` also catches a
+            // peer that only shut down its writing half.
+            let mut flags = libc::EPOLLRDHUP;
+            if interest.readable {
+                flags |= libc::EPOLLIN;
+            }
+            if interest.writable {
+                flags |= libc::EPOLLOUT;
+            }
+            let mut event = libc::epoll_event { events: flags as u32, u64: token as u64 };
+            check(unsafe { libc::epoll_ctl(self.fd, op, fd, &mut event) })?;
+
+            Ok(())
+        }
+    }
+
+    impl Poller for Epoll {
+        fn register(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+            self.control(libc::EPOLL_CTL_ADD, fd, token, interest)
+        }
+
+        fn modify(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+            self.control(libc::EPOLL_CTL_MOD, fd, token, interest)
+        }
+
+        fn deregister(&self, fd: RawFd) -> io::Result<()> {
+            // Old kernels want an event even though it is ignored.
+            let mut event = libc::epoll_event { events: 0, u64: 0 };
+            check(unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_DEL, fd, &mut event) })?;
+
+            Ok(())
+        }
+
+        fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+            events.clear();
+            let mut ready = Vec::<libc::epoll_event>::with_capacity(MAX_EVENTS);
+            let n = unsafe { libc::epoll_wait(self.fd, ready.as_mut_ptr(), MAX_EVENTS as _, timeout_ms(timeout)) };
+            let n = match check(n) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            // The kernel filled in the first `n`.
+            unsafe { ready.set_len(n as usize) };
+
+            events.extend(ready.iter().map(|e| {
+                // Copied out, the struct is packed on some targets.
+                let (flags, token) = (e.events, e.u64);
+                Event {
+                    token: token as Token,
+                    readable: flags & libc::EPOLLIN as u32 != 0,
+                    writable: flags & libc::EPOLLOUT as u32 != 0,
+                    closed: flags & (libc::EPOLLRDHUP | libc::EPOLLHUP | libc::EPOLLERR) as u32 != 0,
+                }
+            }));
+
+            Ok(())
+        }
+    }
+
+    impl Drop for Epoll {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+/// `poll(2)`, for systems without a better queue. Every wait hands the
+/// kernel the whole set, so it gets slow with many descriptors.
+#[derive(Debug)]
+pub struct Poll {
+    registered: Mutex<HashMap<RawFd, (Token, Interest)>>,
+    /// Written to when the set changes, so that a wait in progress starts
+    /// over with the new one.
+    wake_reader: RawFd,
+    wake_writer: RawFd,
+}
+
+impl Poll {
+    pub fn new() -> io::Result<Poll> {
+        let mut fds = [0; 2];
+        check(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+        let poll = Poll { registered: Mutex::new(HashMap::new()), wake_reader: fds[0], wake_writer: fds[1] };
+        for fd in fds {
+            check(unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) })?;
+            check(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+        }
+
+        Ok(poll)
+    }
+
+    fn wake(&self) {
+        // A full pipe already wakes the waiter.
+        unsafe { libc::write(self.wake_writer, [1u8].as_ptr() as *const _, 1) };
+    }
+}
+
+impl Poller for Poll {
+    fn register(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+        let mut registered = self.registered.lock().unwrap();
+        if registered.contains_key(&fd) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        registered.insert(fd, (token, interest));
+        self.wake();
+
+        Ok(())
+    }
+
+    fn modify(&self, fd: RawFd, token: Token, interest: Interest) -> io::Result<()> {
+        match self.registered.lock().unwrap().get_mut(&fd) {
+            Some(entry) => *entry = (token, interest),
+            None => return Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        }
+        self.wake();
+
+        Ok(())
+    }
+
+    fn deregister(&self, fd: RawFd) -> io::Result<()> {
+        if self.registered.lock().unwrap().remove(&fd).is_none() {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        self.wake();
+
+        Ok(())
+    }
+
+    fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        events.clear();
+        let mut fds = vec![libc::pollfd { fd: self.wake_reader, events: libc::POLLIN, revents: 0 }];
+        let mut tokens = Vec::new();
+        for (fd, (token, interest)) in self.registered.lock().unwrap().iter() {
+            let mut flags = 0;
+            if interest.readable {
+                flags |= libc::POLLIN;
+            }
+            if interest.writable {
+                flags |= libc::POLLOUT;
+            }
+            fds.push(libc::pollfd { fd: *fd, events: flags, revents: 0 });
+            tokens.push(*token);
+        }
+
+        match check(unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, timeout_ms(timeout)) }) {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        if fds[0].revents != 0 {
+            let mut buf = [0u8; 64];
+            while unsafe { libc::read(self.wake_reader, buf.as_mut_ptr() as *mut _, buf.len()) } > 0 {}
+        }
+
+        events.extend(fds[1..].iter().zip(tokens).filter(|(fd, _)| fd.revents != 0).map(|(fd, token)| Event {
+            token,
+            readable: fd.revents & libc::POLLIN != 0,
+            writable: fd.revents & libc::POLLOUT != 0,
+            closed: fd.revents & (libc::POLLHUP | libc::POLLERR | libc::POLLNVAL) != 0,
+        }));
+
+        Ok(())
+    }
+}
+
+impl Drop for Poll {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.wake_reader);
+            libc::close(self.wake_writer);
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! Wire protocol shared by the client and the servers.
+//!
+//! Every frame is a big-endian `u32` payload length followed by the
+//! bincode-encoded payload.
+
+use futures::io::{AsyncRead, AsyncReadExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UserId(pub String);
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GroupId(pub String);
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Recipient {
+    User(UserId),
+    Group(GroupId),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub from: UserId,
+    pub to: Recipient,
+    pub text: Option<String>,
+    pub media: Option<Vec<u8>>,
+}
+
+/// Largest payload accepted in a frame.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Wraps every frame a client sends, `id` is what `ServerFrame::Error`
+/// refers to. Clients pick the ids, counting up is enough.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub id: u64,
+    pub frame: ClientFrame,
+}
+
+/// Frames sent by a client to the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ClientFrame {
+    /// Claims a nickname. Must be the first frame of a connection and is
+    /// repeated until the server answers with `Welcome`. `direct_addrs`
+    /// are the addresses this client accepts direct peer links on.
+    Login {
+        name: String,
+        direct_addrs: Vec<SocketAddr>,
+    },
+    /// Renames an already logged in user.
+    Nick { name: String },
+    /// A chat message. The server overwrites `from` with the sender's name.
+    Message(Message),
+    /// Asks for the direct link candidates of user `with`, who gets ours
+    /// in turn.
+    Rendezvous { with: String },
+    /// Starts receiving messages sent to `group`.
+    Join { group: String },
+    Leave { group: String },
+    /// Heartbeat, answered with `ServerFrame::Pong` carrying the same
+    /// `token`. The sender picks the token, usually its own clock reading,
+    /// to measure the round trip.
+    Ping { token: u64 },
+    /// Answers `ServerFrame::Ping`.
+    Pong { token: u64 },
+}
+
+impl ClientFrame {
+    /// Whether this is a `Ping` or `Pong`.
+    pub fn is_heartbeat(&self) -> bool {
+        matches!(self, ClientFrame::Ping { .. } | ClientFrame::Pong { .. })
+    }
+
+    /// Name of the variant, for logging frames without their contents.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientFrame::Login { .. } => "Login",
+            ClientFrame::Nick { .. } => "Nick",
+            ClientFrame::Message(..) => "Message",
+            ClientFrame::Rendezvous { .. } => "Rendezvous",
+            ClientFrame::Join { .. } => "Join",
+            ClientFrame::Leave { .. } => "Leave",
+            ClientFrame::Ping { .. } => "Ping",
+            ClientFrame::Pong { .. } => "Pong",
+        }
+    }
+}
+
+/// Frames sent by the server to a client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ServerFrame {
+    /// The connection is logged in as `name`.
+    Welcome { name: String },
+    /// The requested nickname is already in use, pick another one.
+    NameTaken { name: String },
+    /// User `old` is now known as `new`. Sent to everyone but the user,
+    /// who gets `NickAccepted`.
+    Renamed { old: String, new: String },
+    Message(Message),
+    /// Addresses user `name` can be reached on directly. Empty if the user
+    /// is unknown or doesn't accept direct links. Sent to both ends of a
+    /// rendezvous, the one with the smaller name dials.
+    Candidates { name: String, addrs: Vec<SocketAddr> },
+    /// The server is going away, the connection closes once everything
+    /// queued for the client has been sent.
+    Shutdown { reason: String },
+    /// Heartbeat, answered with `ClientFrame::Pong`.
+    Ping { token: u64 },
+    /// Answers `ClientFrame::Ping`.
+    Pong { token: u64 },
+    /// Request `request` was refused. `None` if the request was too
+    /// mangled to tell its id.
+    Error { request: Option<u64>, error: ErrorCode },
+    /// A notice from the server's operators to everyone connected.
+    Announcement { text: String },
+    /// `ClientFrame::Nick` request `request` succeeded, the connection is
+    /// now known as `name`.
+    NickAccepted { request: u64, name: String },
+}
+
+/// Why the server refused a request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// No user or group of that name is known.
+    UnknownRecipient { name: String },
+    /// The frame couldn't be decoded. It is skipped and the connection
+    /// stays usable.
+    MalformedFrame,
+    /// The request needs a logged in connection.
+    NotAuthorized,
+    /// `name` can't be used as a nickname, see `server::valid_name`.
+    InvalidName { name: String },
+    /// The frame is longer than `MAX_FRAME_LEN`. The connection is closed.
+    TooLarge { len: u64 },
+    /// The client sends too much and the request was dropped. The
+    /// connection is closed as well if the client is banned for a while.
+    RateLimited { retry_after: Duration },
+    /// An operator disconnected the client. The connection is closed.
+    Kicked { reason: String },
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::UnknownRecipient { name } => write!(f, "no user or group named {}", name),
+            ErrorCode::MalformedFrame => write!(f, "malformed frame"),
+            ErrorCode::NotAuthorized => write!(f, "log in first"),
+            ErrorCode::InvalidName { name } => write!(f, "{:?} is not a valid name", name),
+            ErrorCode::TooLarge { len } => write!(f, "{} bytes is more than the server accepts", len),
+            ErrorCode::RateLimited { retry_after } => {
+                write!(f, "rate limited, retry after {:.1}s", retry_after.as_secs_f64())
+            }
+            ErrorCode::Kicked { reason } => write!(f, "kicked: {}", reason),
+        }
+    }
+}
+
+impl ServerFrame {
+    /// Whether a client can do without this frame if it falls behind. Only
+    /// third parties learn about renames from `Renamed`, so losing one
+    /// costs at most a stale name in their view.
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(self, ServerFrame::Renamed { .. } | ServerFrame::Ping { .. } | ServerFrame::Pong { .. })
+    }
+
+    /// Name of the variant, for logging frames without their contents.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerFrame::Welcome { .. } => "Welcome",
+            ServerFrame::NameTaken { .. } => "NameTaken",
+            ServerFrame::Renamed { .. } => "Renamed",
+            ServerFrame::Message(..) => "Message",
+            ServerFrame::Candidates { .. } => "Candidates",
+            ServerFrame::Shutdown { .. } => "Shutdown",
+            ServerFrame::Ping { .. } => "Ping",
+            ServerFrame::Pong { .. } => "Pong",
+            ServerFrame::Error { .. } => "Error",
+            ServerFrame::Announcement { .. } => "Announcement",
+            ServerFrame::NickAccepted { .. } => "NickAccepted",
+        }
+    }
+}
+
+/// Frames exchanged between federated servers. Users and groups hosted on
+/// another server are addressed as `name@server`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum LinkFrame {
+    /// First frame in each direction, `nonce` is the challenge the other
+    /// side has to answer with `Auth`.
+    Hello { server: String, nonce: Vec<u8> },
+    /// Proves knowledge of the shared federation secret.
+    Auth { mac: Vec<u8> },
+    /// A message to be routed by the receiving server according to `to`.
+    Route(Message),
+    /// A group message to be handed verbatim to the receiving server's
+    /// local user `user`, who is a member of a group hosted by the sender.
+    Deliver { user: String, message: Message },
+    /// Remote user `user` (qualified with its server) joins a group hosted
+    /// by the receiving server.
+    Join { group: String, user: String },
+    Leave { group: String, user: String },
+}
+
+impl LinkFrame {
+    /// Name of the variant, for logging frames without their contents.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LinkFrame::Hello { .. } => "Hello",
+            LinkFrame::Auth { .. } => "Auth",
+            LinkFrame::Route(..) => "Route",
+            LinkFrame::Deliver { .. } => "Deliver",
+            LinkFrame::Join { .. } => "Join",
+            LinkFrame::Leave { .. } => "Leave",
+        }
+    }
+}
+
+/// Frames exchanged over a direct client-to-client link.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum PeerFrame {
+    /// First frame on a direct link, introduces the connecting user.
+    Hello { name: String },
+    Message(Message),
+}
+
+/// Serializes `frame` into a length-prefixed buffer ready to be written out.
+pub fn encode_frame<T: Serialize>(frame: &T) -> io::Result<Vec<u8>> {
+    let payload = bincode::serialize(frame).map_err(invalid_data)?;
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(invalid_data(FrameTooLarge { len: payload.len() }));
+    }
+    let len = payload.len() as u32;
+
+    let mut buf = Vec::with_capacity(4 + payload.len());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(&payload);
+
+    Ok(buf)
+}
+
+/// Length of the payload `frame` encodes to, without encoding it.
+pub fn payload_len<T: Serialize>(frame: &T) -> io::Result<usize> {
+    bincode::serialized_size(frame).map(|len| len as usize).map_err(invalid_data)
+}
+
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, frame: &T) -> io::Result<()> {
+    writer.write_all(&encode_frame(frame)?)?;
+    writer.flush()
+}
+
+/// Reads one frame. Returns `Ok(None)` if the peer closed the connection
+/// cleanly before the next frame started.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> io::Result<Option<T>> {
+    match read_payload(reader)? {
+        Some(payload) => decode_frame(&payload).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Blocking counterpart of [`read_payload_async`].
+pub fn read_payload<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 4];
+    match reader.read_exact(&mut len_buf) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut payload = vec![0u8; checked_len(len_buf)?];
+    reader.read_exact(&mut payload)?;
+
+    Ok(Some(payload))
+}
+
+/// Async counterpart of [`read_frame`].
+pub async fn read_frame_async<R, T>(reader: &mut R) -> io::Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    match read_payload_async(reader).await? {
+        Some(payload) => decode_frame(&payload).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Like [`read_frame_async`], but leaves decoding to [`decode_frame`] so
+/// that a frame that doesn't decode can be told apart from a broken
+/// stream. Frames longer than [`MAX_FRAME_LEN`] fail with [`FrameTooLarge`]
+/// before anything is allocated for them.
+pub async fn read_payload_async<R>(reader: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len_buf = [0u8; 4];
+    match reader.read_exact(&mut len_buf).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut payload = vec![0u8; checked_len(len_buf)?];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(Some(payload))
+}
+
+/// Decodes the payload of a frame, without its length prefix.
+pub fn decode_frame<T: DeserializeOwned>(payload: &[u8]) -> io::Result<T> {
+    bincode::deserialize(payload).map_err(invalid_data)
+}
+
+/// A frame announced a payload longer than [`MAX_FRAME_LEN`]. Nothing
+/// after the length prefix has been read, so the stream can't be resumed.
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub len: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame of {} bytes exceeds the limit of {} bytes", self.len, MAX_FRAME_LEN)
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+impl FrameTooLarge {
+    /// Finds the `FrameTooLarge` behind an I/O error from this module.
+    pub fn find(e: &io::Error) -> Option<&FrameTooLarge> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+fn checked_len(len_buf: [u8; 4]) -> io::Result<usize> {
+    match u32::from_be_bytes(len_buf) as usize {
+        len if len > MAX_FRAME_LEN => Err(invalid_data(FrameTooLarge { len })),
+        len => Ok(len),
+    }
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,286 @@
+//! Flood control: token buckets and temporary bans.
+//!
+//! Every client gets a [`Budget`] for the frames and bytes it may send, and
+//! a [`Guard`] shared by the whole server limits new connections per
+//! address. Addresses that keep running into limits are banned for a while.
+//! Once logged in, clients share the budget of their name with earlier
+//! connections under it, so reconnecting doesn't refill it.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Entries the guard keeps before it forgets idle addresses.
+const MIN_PRUNE_AT: usize = 1024;
+/// Longest ban. Bans end at the current time plus their length, which
+/// overflows for huge ones.
+const MAX_BAN: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Refill rate and size of a token bucket. A rate of zero means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Starts out full.
+    pub fn new(rate: Rate) -> TokenBucket {
+        TokenBucket { rate, tokens: rate.burst, updated: Instant::now() }
+    }
+
+    /// Takes `amount` tokens, or returns how long until they are available.
+    /// Amounts bigger than the burst are let through once the bucket is
+    /// full and leave it in debt.
+    pub fn take(&mut self, amount: f64) -> Result<(), Duration> {
+        self.check(amount)?;
+        if self.rate.per_second > 0.0 {
+            self.tokens -= amount;
+        }
+
+        Ok(())
+    }
+
+    /// Like `take`, without taking anything.
+    pub fn check(&mut self, amount: f64) -> Result<(), Duration> {
+        if self.rate.per_second <= 0.0 {
+            return Ok(());
+        }
+        self.refill();
+
+        let needed = amount.min(self.rate.burst);
+        if self.tokens < needed {
+            return Err(Duration::from_secs_f64((needed - self.tokens) / self.rate.per_second));
+        }
+
+        Ok(())
+    }
+
+    /// Switches to `rate`, keeping the tokens collected so far up to the
+    /// new burst.
+    pub fn set_rate(&mut self, rate: Rate) {
+        if rate == self.rate {
+            return;
+        }
+        self.refill();
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate.burst);
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_second).min(self.rate.burst);
+        self.updated = now;
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.rate.per_second <= 0.0 || {
+            self.refill();
+            self.tokens >= self.rate.burst
+        }
+    }
+}
+
+/// What a client may send: frames, heartbeats aside, and bytes.
+#[derive(Debug)]
+pub struct Budget {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl Budget {
+    pub fn new(messages: Rate, bytes: Rate) -> Budget {
+        Budget { messages: TokenBucket::new(messages), bytes: TokenBucket::new(bytes) }
+    }
+
+    /// Switches to new rates, see `TokenBucket::set_rate`.
+    pub fn set_rates(&mut self, messages: Rate, bytes: Rate) {
+        self.messages.set_rate(messages);
+        self.bytes.set_rate(bytes);
+    }
+
+    /// Takes what a frame of `len` bytes costs, or returns how long until
+    /// the client may send it. Takes nothing unless both buckets have
+    /// enough. Heartbeats only count against the byte limit.
+    pub fn charge(&mut self, len: usize, heartbeat: bool) -> Result<(), Duration> {
+        let messages = if heartbeat { 0.0 } else { 1.0 };
+        let waits = [self.messages.check(messages), self.bytes.check(len as f64)];
+        if let Some(wait) = waits.iter().filter_map(|wait| wait.err()).max() {
+            return Err(wait);
+        }
+        self.messages.take(messages)?;
+
+        self.bytes.take(len as f64)
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.messages.is_full() && self.bytes.is_full()
+    }
+}
+
+/// When to ban an address: after `strikes` violations within `window` it
+/// is banned for `duration`. Zero strikes never ban.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BanPolicy {
+    pub strikes: u32,
+    pub window: Duration,
+    pub duration: Duration,
+}
+
+#[derive(Debug)]
+struct Address {
+    connections: TokenBucket,
+    strikes: u32,
+    first_strike: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Limits shared by all connections, keyed by remote address or by the
+/// name a client logged in with.
+#[derive(Debug)]
+pub struct Guard {
+    addresses: Mutex<Addresses>,
+    users: Mutex<Users>,
+}
+
+#[derive(Debug)]
+struct Users {
+    by_name: HashMap<String, Budget>,
+    prune_at: usize,
+}
+
+#[derive(Debug)]
+struct Addresses {
+    connections: Rate,
+    bans: BanPolicy,
+    by_ip: HashMap<IpAddr, Address>,
+    prune_at: usize,
+}
+
+impl Guard {
+    pub fn new(connections: Rate, bans: BanPolicy) -> Guard {
+        Guard {
+            addresses: Mutex::new(Addresses { connections, bans, by_ip: HashMap::new(), prune_at: MIN_PRUNE_AT }),
+            users: Mutex::new(Users { by_name: HashMap::new(), prune_at: MIN_PRUNE_AT }),
+        }
+    }
+
+    /// Changes the limits. Addresses keep their strikes and bans.
+    pub fn set_limits(&self, connections: Rate, bans: BanPolicy) {
+        let mut addresses = self.addresses.lock().unwrap();
+        addresses.connections = connections;
+        addresses.bans = bans;
+    }
+
+    /// Admits a new connection from `ip`, or returns how long it has to
+    /// wait because it connects too often or is banned.
+    pub fn admit(&self, ip: IpAddr) -> Result<(), Duration> {
+        let mut addresses = self.addresses.lock().unwrap();
+        let now = Instant::now();
+        if addresses.by_ip.len() >= addresses.prune_at {
+            let window = addresses.bans.window;
+            addresses.by_ip.retain(|_, address| !address.is_idle(now, window));
+            addresses.prune_at = MIN_PRUNE_AT.max(addresses.by_ip.len() * 2);
+        }
+
+        let address = addresses.get(ip, now);
+        if let Some(remaining) = address.ban_remaining(now) {
+            return Err(remaining);
+        }
+
+        address.connections.take(1.0)
+    }
+
+    /// Records a violation by `ip`. Returns the ban length once it has too
+    /// many.
+    pub fn strike(&self, ip: IpAddr) -> Option<Duration> {
+        let mut addresses = self.addresses.lock().unwrap();
+        let bans = addresses.bans;
+        if bans.strikes == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let address = addresses.get(ip, now);
+        if let Some(remaining) = address.ban_remaining(now) {
+            return Some(remaining);
+        }
+
+        if now.duration_since(address.first_strike) > bans.window {
+            address.strikes = 0;
+            address.first_strike = now;
+        }
+        address.strikes += 1;
+        if address.strikes < bans.strikes {
+            return None;
+        }
+        address.strikes = 0;
+        let duration = bans.duration.min(MAX_BAN);
+        address.banned_until = Some(now + duration);
+
+        Some(duration)
+    }
+
+    /// Charges a frame of `len` bytes to the budget of `name` at the given
+    /// rates, see `Budget::charge`. Budgets are kept until they are full
+    /// again, whoever is logged in under the name.
+    pub fn charge(&self, name: &str, messages: Rate, bytes: Rate, len: usize, heartbeat: bool) -> Result<(), Duration> {
+        let mut users = self.users.lock().unwrap();
+        if users.by_name.len() >= users.prune_at {
+            users.by_name.retain(|_, budget| !budget.is_full());
+            users.prune_at = MIN_PRUNE_AT.max(users.by_name.len() * 2);
+        }
+
+        if !users.by_name.contains_key(name) {
+            users.by_name.insert(name.to_string(), Budget::new(messages, bytes));
+        }
+        let budget = users.by_name.get_mut(name).unwrap();
+        budget.set_rates(messages, bytes);
+        budget.charge(len, heartbeat)
+    }
+
+    /// Refuses connections from `ip` for `duration`, at most a year,
+    /// whatever its strikes. A longer ban already in place stays.
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        let mut addresses = self.addresses.lock().unwrap();
+        let now = Instant::now();
+        let address = addresses.get(ip, now);
+        address.banned_until = address.banned_until.max(Some(now + duration.min(MAX_BAN)));
+    }
+}
+
+impl Addresses {
+    fn get(&mut self, ip: IpAddr, now: Instant) -> &mut Address {
+        let connections = self.connections;
+        let address = self.by_ip.entry(ip).or_insert_with(|| Address {
+            connections: TokenBucket::new(connections),
+            strikes: 0,
+            first_strike: now,
+            banned_until: None,
+        });
+        address.connections.set_rate(connections);
+
+        address
+    }
+}
+
+impl Address {
+    fn ban_remaining(&self, now: Instant) -> Option<Duration> {
+        self.banned_until
+            .map(|until| until.saturating_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    fn is_idle(&mut self, now: Instant, window: Duration) -> bool {
+        let strikes_expired = self.strikes == 0 || now.duration_since(self.first_strike) > window;
+        self.ban_remaining(now).is_none() && strikes_expired && self.connections.is_full()
+    }
+}
@@ -0,0 +1,689 @@
+//! The chat server's routing rules without any I/O.
+//!
+//! [`ChatServer`] is told what happens on the connections as [`Event`]s and
+//! answers with [`Output`]s: frames to queue for connections and
+//! connections to close. Sockets, framing, heartbeats and limits are up to
+//! the server around it, which also tells it when a connection is gone,
+//! including the ones it gives up on itself.
+//!
+//! A server may also be split into shards with a `ChatServer` each, and be
+//! federated with other servers, see [`Topology`]. Every user name, group
+//! and federated server then belongs to one shard, and the others hand it
+//! what concerns them as [`Relay`]s. `threads_server`, `kqueue_server` and
+//! `uring_server` run a single shard without federation, which never
+//! relays, `async_std_server` runs one per broker shard.
+
+use crate::federation::split_address;
+use crate::protocol::{
+    payload_len, ClientFrame, ErrorCode, GroupId, LinkFrame, Message, Recipient, Request, ServerFrame, UserId,
+    MAX_FRAME_LEN,
+};
+use crate::logging;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use tracing::{debug, info, trace, warn};
+
+/// Picked by the server around [`ChatServer`] for each connection. It may
+/// be reused once the connection was reported disconnected.
+pub type ConnectionId = u64;
+
+/// What happened on a connection, or what another shard or server asks
+/// for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A client connected from `addr`.
+    Connected { connection: ConnectionId, addr: SocketAddr },
+    Request { connection: ConnectionId, request: Request },
+    /// The client sent a frame that doesn't decode. The connection stays
+    /// usable.
+    Malformed { connection: ConnectionId },
+    /// The client announced a frame longer than `MAX_FRAME_LEN`, nothing
+    /// after it can be read.
+    TooLarge { connection: ConnectionId, len: usize },
+    /// The connection is gone, or the server gave up on it. Nothing more
+    /// is sent to it.
+    Disconnected { connection: ConnectionId },
+    /// Handed over by another shard, see `Output::Relay`.
+    Relayed(Relay),
+    /// A frame from the federated server `server` about a user or group
+    /// this shard owns.
+    Remote { server: String, frame: LinkFrame },
+}
+
+impl Event {
+    /// The connection it happened on, if it happened on one.
+    pub fn connection(&self) -> Option<ConnectionId> {
+        match *self {
+            Event::Connected { connection, .. }
+            | Event::Request { connection, .. }
+            | Event::Malformed { connection }
+            | Event::TooLarge { connection, .. }
+            | Event::Disconnected { connection } => Some(connection),
+            Event::Relayed(..) | Event::Remote { .. } => None,
+        }
+    }
+}
+
+/// What the server around [`ChatServer`] has to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    /// Queue `frame` for each connection in `to`. Encoding it once for all
+    /// of them is enough. The connections may be served by any shard.
+    Send { to: Vec<ConnectionId>, frame: ServerFrame },
+    /// Close `connection` once what is queued for it has been sent. It is
+    /// already forgotten, a later `Event::Disconnected` for it is ignored.
+    Close { connection: ConnectionId },
+    /// Hand `relay` to the `ChatServer` of `shard` as `Event::Relayed`.
+    Relay { shard: usize, relay: Relay },
+    /// Send `frame` to the federated server `server`, queueing it while
+    /// the link is down. Only the shard owning `server` is asked to.
+    Forward { server: String, frame: LinkFrame },
+}
+
+/// What a shard asks of the shard owning a user name, group or federated
+/// server, or of the home shard of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Relay {
+    /// Registers user `name` for `connection` unless the name is taken.
+    /// Answered with `Claimed`.
+    Claim { connection: ConnectionId, name: String, addr: SocketAddr, direct_addrs: Vec<SocketAddr> },
+    Claimed { connection: ConnectionId, name: String, taken: bool },
+    /// Frees user `name` if `connection` still holds it.
+    Release { connection: ConnectionId, name: String },
+    /// `connection`, logged in as `name`, joins `group`. Joining again
+    /// changes the name it is listed under.
+    Join { group: String, connection: ConnectionId, name: String },
+    Leave { group: String, connection: ConnectionId, name: String },
+    /// User `from` of `connection`, who accepts direct links on `addrs`,
+    /// asks for the candidates of user `with`.
+    Rendezvous { from: String, connection: ConnectionId, addrs: Vec<SocketAddr>, with: String },
+    /// Delivers `message` to its recipient.
+    Route { message: Message, origin: Origin },
+    /// Sends `frame` to the logged in connections of the shard but
+    /// `except`.
+    Broadcast { frame: ServerFrame, except: Option<ConnectionId> },
+    /// Sends `frame` to the federated server `server`.
+    Forward { server: String, frame: LinkFrame },
+}
+
+/// Where a routed message comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    /// Request `request` of `connection`, which is told if the recipient
+    /// is unknown.
+    Client { connection: ConnectionId, request: u64 },
+    /// The federated server of that name. What it sends is never passed on
+    /// to a third server.
+    Server(String),
+}
+
+/// How the users, groups and federated servers of one server are spread
+/// over its shards.
+#[derive(Debug, Clone)]
+pub struct Topology {
+    /// Number of shards, each with its own `ChatServer`.
+    pub shards: usize,
+    /// Name of this server in `name@server` addresses, empty with
+    /// federation off.
+    pub server_name: String,
+    /// Federated servers that may link with this one.
+    pub peers: HashSet<String>,
+}
+
+impl Default for Topology {
+    /// A single shard without federation.
+    fn default() -> Topology {
+        Topology { shards: 1, server_name: String::new(), peers: HashSet::new() }
+    }
+}
+
+impl Topology {
+    /// Splits `name@server` like `federation::split_address`. With
+    /// federation off every address is local, `@` and all.
+    pub fn split<'a>(&self, address: &'a str) -> (&'a str, Option<&'a str>) {
+        match self.server_name.is_empty() {
+            true => (address, None),
+            false => split_address(address, &self.server_name),
+        }
+    }
+
+    /// The shard owning the user or group `address`. Addresses on other
+    /// servers belong to the shard owning the link to that server.
+    pub fn owner(&self, address: &str) -> usize {
+        let key = match self.split(address) {
+            (name, None) => name,
+            (_, Some(server)) => server,
+        };
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+
+        (hasher.finish() % self.shards as u64) as usize
+    }
+
+    /// The shard serving `connection`, which its events have to go to.
+    pub fn home(&self, connection: ConnectionId) -> usize {
+        (connection % self.shards as u64) as usize
+    }
+}
+
+/// A connection as its home shard knows it.
+#[derive(Debug)]
+struct Client {
+    addr: SocketAddr,
+    /// Set once logged in.
+    name: Option<String>,
+    groups: HashSet<String>,
+    /// Where the client accepts direct peer links.
+    direct_addrs: Vec<SocketAddr>,
+    /// Id of the request asking for a name, and the name, until the shard
+    /// owning it answers.
+    claim: Option<(u64, String)>,
+    /// Requests that came in meanwhile.
+    queued: VecDeque<Request>,
+}
+
+/// A logged in user, as the shard owning its name knows it.
+#[derive(Debug)]
+struct User {
+    connection: ConnectionId,
+    addr: SocketAddr,
+    direct_addrs: Vec<SocketAddr>,
+}
+
+/// A member of a group. Connections are members, so that groups follow
+/// renames, users of other servers are `name@server`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Member {
+    Local(ConnectionId),
+    Remote(String),
+}
+
+/// Who is connected, under which name and in which groups, and where
+/// their requests go.
+#[derive(Debug, Default)]
+pub struct ChatServer {
+    shard: usize,
+    topology: Topology,
+    /// Connections served by this shard.
+    clients: HashMap<ConnectionId, Client>,
+    /// Users whose names this shard owns.
+    users: HashMap<String, User>,
+    /// Members of the groups this shard owns, with the names they are
+    /// listed under.
+    groups: HashMap<String, HashMap<Member, String>>,
+    outputs: VecDeque<Output>,
+}
+
+impl ChatServer {
+    /// A server on its own, which is a single shard without federation.
+    pub fn new() -> ChatServer {
+        ChatServer::default()
+    }
+
+    /// Shard `shard` of `topology`. Events about a connection have to go
+    /// to its home shard, frames from federated servers to the shard
+    /// owning their target.
+    pub fn sharded(shard: usize, topology: Topology) -> ChatServer {
+        ChatServer { shard, topology, ..ChatServer::default() }
+    }
+
+    /// Updates the state with `event`. What it leads to is taken with
+    /// [`poll_output`](Self::poll_output).
+    pub fn handle(&mut self, event: Event) {
+        match event {
+            Event::Connected { connection, addr } => {
+                let client = Client {
+                    addr,
+                    name: None,
+                    groups: HashSet::new(),
+                    direct_addrs: Vec::new(),
+                    claim: None,
+                    queued: VecDeque::new(),
+                };
+                self.clients.insert(connection, client);
+            }
+            Event::Request { connection, request } => self.request(connection, request),
+            Event::Malformed { connection } => {
+                self.send(connection, ServerFrame::Error { request: None, error: ErrorCode::MalformedFrame });
+            }
+            Event::TooLarge { connection, len } => {
+                warn!(len, "Frame over the limit, closing");
+                self.send(connection, ServerFrame::Error { request: None, error: ErrorCode::TooLarge { len: len as u64 } });
+                if self.disconnect(connection) {
+                    self.outputs.push_back(Output::Close { connection });
+                }
+            }
+            Event::Disconnected { connection } => {
+                self.disconnect(connection);
+            }
+            Event::Relayed(relay) => self.relayed(relay),
+            Event::Remote { server, frame } => self.remote(server, frame),
+        }
+    }
+
+    /// The next thing to do, in the order they came up.
+    pub fn poll_output(&mut self) -> Option<Output> {
+        self.outputs.pop_front()
+    }
+
+    /// Name `connection` is logged in with, if this shard serves it.
+    pub fn name(&self, connection: ConnectionId) -> Option<&str> {
+        self.clients.get(&connection)?.name.as_deref()
+    }
+
+    /// The connection of user `name` and where it connected from, if this
+    /// shard owns the name.
+    pub fn user(&self, name: &str) -> Option<(ConnectionId, SocketAddr)> {
+        self.users.get(name).map(|user| (user.connection, user.addr))
+    }
+
+    /// The users whose names this shard owns, see [`user`](Self::user).
+    pub fn users(&self) -> impl Iterator<Item = (&str, ConnectionId, SocketAddr)> + '_ {
+        self.users.iter().map(|(name, user)| (name.as_str(), user.connection, user.addr))
+    }
+
+    /// The groups this shard owns and the names of their members, members
+    /// on other servers as `name@server`.
+    pub fn groups(&self) -> impl Iterator<Item = (&str, Vec<&str>)> + '_ {
+        self.groups.iter().map(|(group, members)| (group.as_str(), members.values().map(String::as_str).collect()))
+    }
+
+    /// Answers or routes a request of `connection`.
+    fn request(&mut self, connection: ConnectionId, request: Request) {
+        let Some(client) = self.clients.get_mut(&connection) else {
+            return;
+        };
+        // What comes after a login or rename waits for its answer.
+        if client.claim.is_some() {
+            client.queued.push_back(request);
+            return;
+        }
+        let Request { id, frame } = request;
+        trace!(request = id, frame = frame.kind(), "Received request");
+
+        let Some(name) = client.name.clone() else {
+            match frame {
+                ClientFrame::Login { name, direct_addrs } => {
+                    client.direct_addrs = with_observed_addrs(direct_addrs, client.addr);
+                    self.claim(connection, id, name);
+                }
+                // There is nowhere to send a pong yet.
+                frame if frame.is_heartbeat() => {}
+                _ => self.send(connection, ServerFrame::Error { request: Some(id), error: ErrorCode::NotAuthorized }),
+            }
+
+            return;
+        };
+        match frame {
+            ClientFrame::Login { .. } | ClientFrame::Pong { .. } => {}
+            ClientFrame::Ping { token } => self.send(connection, ServerFrame::Pong { token }),
+            ClientFrame::Nick { name: new_name } => self.claim(connection, id, new_name),
+            ClientFrame::Rendezvous { with } => {
+                let addrs = client.direct_addrs.clone();
+                self.relay(self.topology.owner(&with), Relay::Rendezvous { from: name, connection, addrs, with });
+            }
+            ClientFrame::Join { group } => {
+                client.groups.insert(group.clone());
+                self.relay(self.topology.owner(&group), Relay::Join { group, connection, name });
+            }
+            ClientFrame::Leave { group } => {
+                if client.groups.remove(&group) {
+                    self.relay(self.topology.owner(&group), Relay::Leave { group, connection, name });
+                }
+            }
+            ClientFrame::Message(message) => {
+                let text = message.text.as_deref().unwrap_or_default();
+                debug!(request = id, to = ?message.to, text = %logging::contents(text), "Message");
+                let (Recipient::User(UserId(to)) | Recipient::Group(GroupId(to))) = &message.to;
+                let shard = self.topology.owner(to);
+                let origin = Origin::Client { connection, request: id };
+                self.relay(shard, Relay::Route { message: Message { from: UserId(name), ..message }, origin });
+            }
+        }
+    }
+
+    /// Asks the shard owning `name` for it, to log `connection` in or to
+    /// rename it. Other requests of the connection wait for the answer.
+    fn claim(&mut self, connection: ConnectionId, id: u64, name: String) {
+        if !valid_name(&name) {
+            self.send(connection, ServerFrame::Error { request: Some(id), error: ErrorCode::InvalidName { name } });
+            return;
+        }
+        let Some(client) = self.clients.get_mut(&connection) else {
+            return;
+        };
+        client.claim = Some((id, name.clone()));
+        let (addr, direct_addrs) = (client.addr, client.direct_addrs.clone());
+        self.relay(self.topology.owner(&name), Relay::Claim { connection, name, addr, direct_addrs });
+    }
+
+    /// Handles the answer to a claim, then what `connection` sent while
+    /// waiting for it.
+    fn claimed(&mut self, connection: ConnectionId, name: String, taken: bool) {
+        let Some(client) = self.clients.get_mut(&connection) else {
+            // Gone while waiting, nobody is going to use the name.
+            if !taken {
+                self.relay(self.topology.owner(&name), Relay::Release { connection, name });
+            }
+            return;
+        };
+        let Some((id, _)) = client.claim.take() else {
+            return;
+        };
+        if taken {
+            self.send(connection, ServerFrame::NameTaken { name });
+        } else {
+            match client.name.replace(name.clone()) {
+                None => {
+                    info!(name, "Logged in");
+                    self.send(connection, ServerFrame::Welcome { name });
+                }
+                Some(old) => self.renamed(connection, id, old, name),
+            }
+        }
+
+        while let Some(client) = self.clients.get_mut(&connection) {
+            if client.claim.is_some() {
+                break;
+            }
+            let Some(request) = client.queued.pop_front() else {
+                break;
+            };
+            self.request(connection, request);
+        }
+    }
+
+    /// Frees the `old` name of `connection`, which got `new` with request
+    /// `id`, and tells everyone.
+    fn renamed(&mut self, connection: ConnectionId, id: u64, old: String, new: String) {
+        self.relay(self.topology.owner(&old), Relay::Release { connection, name: old.clone() });
+        info!(from = %old, to = %new, "Renamed");
+        self.send(connection, ServerFrame::NickAccepted { request: id, name: new.clone() });
+        // Groups on other servers know their members by name.
+        let groups: Vec<String> = self.clients[&connection].groups.iter().cloned().collect();
+        for group in groups {
+            let shard = self.topology.owner(&group);
+            self.relay(shard, Relay::Leave { group: group.clone(), connection, name: old.clone() });
+            self.relay(shard, Relay::Join { group, connection, name: new.clone() });
+        }
+        let frame = ServerFrame::Renamed { old, new };
+        for shard in 0..self.topology.shards {
+            self.relay(shard, Relay::Broadcast { frame: frame.clone(), except: Some(connection) });
+        }
+    }
+
+    /// Does what another shard, or this one, asks for.
+    fn relayed(&mut self, relay: Relay) {
+        match relay {
+            Relay::Claim { connection, name, addr, direct_addrs } => {
+                let taken = self.users.contains_key(&name);
+                if !taken {
+                    self.users.insert(name.clone(), User { connection, addr, direct_addrs });
+                }
+                self.relay(self.topology.home(connection), Relay::Claimed { connection, name, taken });
+            }
+            Relay::Claimed { connection, name, taken } => self.claimed(connection, name, taken),
+            Relay::Release { connection, name } => {
+                if self.users.get(&name).is_some_and(|user| user.connection == connection) {
+                    self.users.remove(&name);
+                }
+            }
+            Relay::Join { group, connection, name } => match self.topology.split(&group) {
+                (group, None) => {
+                    self.groups.entry(group.to_string()).or_default().insert(Member::Local(connection), name);
+                }
+                (group, Some(server)) => {
+                    let join = LinkFrame::Join { group: group.to_string(), user: self.qualify(&name) };
+                    self.forward(server, join);
+                }
+            },
+            Relay::Leave { group, connection, name } => match self.topology.split(&group) {
+                (group, None) => self.leave_group(group, &Member::Local(connection)),
+                (group, Some(server)) => {
+                    let leave = LinkFrame::Leave { group: group.to_string(), user: self.qualify(&name) };
+                    self.forward(server, leave);
+                }
+            },
+            Relay::Rendezvous { from, connection, addrs, with } => {
+                // The peer learns about us too, so that it accepts our link
+                // or dials us itself.
+                let peer = self.users.get(&with).map(|peer| (peer.connection, peer.direct_addrs.clone()));
+                let theirs = match peer {
+                    Some((peer, theirs)) => {
+                        self.send(peer, ServerFrame::Candidates { name: from, addrs });
+                        theirs
+                    }
+                    None => Vec::new(),
+                };
+                self.send(connection, ServerFrame::Candidates { name: with, addrs: theirs });
+            }
+            Relay::Route { message, origin } => self.route(message, origin),
+            Relay::Broadcast { frame, except } => {
+                let to: Vec<ConnectionId> = self.clients.iter()
+                    .filter(|&(&connection, client)| client.name.is_some() && except != Some(connection))
+                    .map(|(&connection, _)| connection)
+                    .collect();
+                if !to.is_empty() {
+                    self.outputs.push_back(Output::Send { to, frame });
+                }
+            }
+            Relay::Forward { server, frame } => self.outputs.push_back(Output::Forward { server, frame }),
+        }
+    }
+
+    /// Handles a frame from the federated server `server`, which may only
+    /// speak for its own users.
+    fn remote(&mut self, server: String, frame: LinkFrame) {
+        let suffix = format!("@{}", server);
+        match frame {
+            LinkFrame::Route(message) if message.from.0.ends_with(&suffix) => self.route(message, Origin::Server(server)),
+            // Groups are relayed by the server hosting them, for its own
+            // users and those of the other peers.
+            LinkFrame::Deliver { user, message } if self.delivered_by(&server, &message) => {
+                if let Some(connection) = self.users.get(&user).map(|user| user.connection) {
+                    self.send(connection, ServerFrame::Message(message));
+                }
+            }
+            LinkFrame::Join { group, user } if user.ends_with(&suffix) => {
+                if let (group, None) = self.topology.split(&group) {
+                    self.groups.entry(group.to_string()).or_default().insert(Member::Remote(user.clone()), user);
+                }
+            }
+            LinkFrame::Leave { group, user } if user.ends_with(&suffix) => {
+                if let (group, None) = self.topology.split(&group) {
+                    self.leave_group(group, &Member::Remote(user));
+                }
+            }
+            frame => warn!(%server, frame = frame.kind(), "Unexpected frame from server"),
+        }
+    }
+
+    /// Delivers `message` to its user or the members of its group, or
+    /// passes it on to the server they are on.
+    fn route(&mut self, message: Message, origin: Origin) {
+        let (Recipient::User(UserId(to)) | Recipient::Group(GroupId(to))) = &message.to;
+        let to = to.clone();
+        let known = match (self.topology.split(&to), &message.to) {
+            ((name, None), Recipient::User(_)) => match self.users.get(name).map(|user| user.connection) {
+                Some(connection) => {
+                    self.deliver(vec![connection], message, &origin);
+                    true
+                }
+                None => false,
+            },
+            ((name, None), Recipient::Group(_)) => self.fan_out(name, message, &origin),
+            ((_, Some(server)), _) => match origin {
+                Origin::Client { .. } => {
+                    let message = Message { from: UserId(self.qualify(&message.from.0)), ..message };
+                    self.forward(server, LinkFrame::Route(message))
+                }
+                // Messages are never relayed on to a third server.
+                Origin::Server(_) => true,
+            },
+        };
+        if !known {
+            match origin {
+                Origin::Client { connection, request } => {
+                    let error = ErrorCode::UnknownRecipient { name: to };
+                    self.send(connection, ServerFrame::Error { request: Some(request), error });
+                }
+                Origin::Server(server) => warn!(%server, to, "Dropping message to unknown recipient"),
+            }
+        }
+    }
+
+    /// Delivers a message for `group` to its members here and on other
+    /// servers. Returns whether the group exists.
+    fn fan_out(&mut self, group: &str, message: Message, origin: &Origin) -> bool {
+        let Some(members) = self.groups.get(group) else {
+            return false;
+        };
+        let (mut local, mut remote) = (Vec::new(), Vec::new());
+        for member in members.keys() {
+            match member {
+                Member::Local(connection) => local.push(*connection),
+                Member::Remote(user) => remote.push(user.clone()),
+            }
+        }
+        if !remote.is_empty() {
+            // Members on other servers need to know where to reply.
+            let from = match self.topology.split(&message.from.0) {
+                (name, None) => UserId(self.qualify(name)),
+                (_, Some(_)) => message.from.clone(),
+            };
+            let to = Recipient::Group(GroupId(self.qualify(group)));
+            let remote_message = Message { from, to, ..message.clone() };
+            for user in &remote {
+                if let (user, Some(server)) = self.topology.split(user) {
+                    let deliver = LinkFrame::Deliver { user: user.to_string(), message: remote_message.clone() };
+                    self.forward(server, deliver);
+                }
+            }
+        }
+        if !local.is_empty() {
+            self.deliver(local, message, origin);
+        }
+
+        true
+    }
+
+    /// Sends `message` to connections `to`, unless the sender's name pushed
+    /// a message that fit over the limit.
+    fn deliver(&mut self, to: Vec<ConnectionId>, message: Message, origin: &Origin) {
+        let frame = ServerFrame::Message(message);
+        match payload_len(&frame) {
+            Ok(len) if len > MAX_FRAME_LEN => {
+                warn!(len, "Message over the limit");
+                if let Origin::Client { connection, request } = *origin {
+                    let error = ErrorCode::TooLarge { len: len as u64 };
+                    self.send(connection, ServerFrame::Error { request: Some(request), error });
+                }
+            }
+            _ => self.outputs.push_back(Output::Send { to, frame }),
+        }
+    }
+
+    /// Whether `server` may hand `message` to our users: it has to be for a
+    /// group `server` hosts and from a user of `server` or another peer.
+    fn delivered_by(&self, server: &str, message: &Message) -> bool {
+        let hosted = match &message.to {
+            Recipient::Group(GroupId(group)) => self.topology.split(group).1 == Some(server),
+            Recipient::User(_) => false,
+        };
+        let sender = match self.topology.split(&message.from.0).1 {
+            Some(sender) => sender == server || self.topology.peers.contains(sender),
+            None => false,
+        };
+
+        hosted && sender
+    }
+
+    /// Sends `frame` to the federated server `server` through the shard
+    /// owning its link. Returns false if `server` isn't one of our peers.
+    fn forward(&mut self, server: &str, frame: LinkFrame) -> bool {
+        if !self.topology.peers.contains(server) {
+            debug!(%server, frame = frame.kind(), "Not forwarding to unknown server");
+            return false;
+        }
+        let relay = Relay::Forward { server: server.to_string(), frame };
+        self.relay(self.topology.owner(server), relay);
+
+        true
+    }
+
+    /// Hands `relay` to `shard`, or handles it right away if that is this
+    /// one.
+    fn relay(&mut self, shard: usize, relay: Relay) {
+        if shard == self.shard {
+            self.relayed(relay);
+        } else {
+            self.outputs.push_back(Output::Relay { shard, relay });
+        }
+    }
+
+    /// `name@server` for our user `name`.
+    fn qualify(&self, name: &str) -> String {
+        format!("{}@{}", name, self.topology.server_name)
+    }
+
+    fn send(&mut self, to: ConnectionId, frame: ServerFrame) {
+        self.outputs.push_back(Output::Send { to: vec![to], frame });
+    }
+
+    /// Takes `member` out of `group`, which goes away with its last member.
+    fn leave_group(&mut self, group: &str, member: &Member) {
+        if let Some(members) = self.groups.get_mut(group) {
+            members.remove(member);
+            if members.is_empty() {
+                self.groups.remove(group);
+            }
+        }
+    }
+
+    /// Forgets `connection`, and has its name and groups released. Returns
+    /// whether it was known. A claim still waiting is released once
+    /// answered.
+    fn disconnect(&mut self, connection: ConnectionId) -> bool {
+        let Some(client) = self.clients.remove(&connection) else {
+            return false;
+        };
+        if let Some(name) = client.name {
+            for group in client.groups {
+                let relay = Relay::Leave { group: group.clone(), connection, name: name.clone() };
+                self.relay(self.topology.owner(&group), relay);
+            }
+            self.relay(self.topology.owner(&name), Relay::Release { connection, name });
+        }
+
+        true
+    }
+}
+
+/// Longest nickname, in characters.
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Whether a client may log in as `name`. Names are 1 to `MAX_NAME_LEN`
+/// characters without spaces or control characters. `@` separates users
+/// from their server in federated addresses, `:` ends the recipient in
+/// the client's input and a leading `#` marks a group.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= MAX_NAME_LEN
+        && !name.starts_with('#')
+        && !name.chars().any(|c| c == '@' || c == ':' || c.is_whitespace() || c.is_control())
+}
+
+/// Adds to the `direct_addrs` of a client connecting from `addr` the same
+/// ports on `addr`. Behind a NAT the addresses the client knows aren't
+/// reachable, the one it connects from may be.
+pub fn with_observed_addrs(mut direct_addrs: Vec<SocketAddr>, addr: SocketAddr) -> Vec<SocketAddr> {
+    let observed: Vec<SocketAddr> = direct_addrs.iter()
+        .map(|candidate| SocketAddr::new(addr.ip(), candidate.port()))
+        .filter(|candidate| !direct_addrs.contains(candidate))
+        .collect();
+    direct_addrs.extend(observed);
+
+    direct_addrs
+}
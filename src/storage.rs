@@ -0,0 +1,63 @@
+//! Chat history.
+//!
+//! A server given `storage.path` appends every message it routes to that
+//! file, framed like the chat protocol. Only messages from logged in users
+//! are stored, with the sender's name and when the server got them.
+
+use crate::config::{Config, ConfigError};
+use crate::protocol::{encode_frame, read_frame, Message};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// A message in the history.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Stored {
+    /// When the server got the message, since the Unix epoch.
+    pub at: Duration,
+    pub message: Message,
+}
+
+/// The history file, open for appending.
+#[derive(Debug)]
+pub struct History {
+    file: File,
+}
+
+impl History {
+    /// Opens the file at `storage.path`, creating it if needed. There is no
+    /// history unless it is set.
+    pub fn from_config(config: &Config) -> Result<Option<History>, ConfigError> {
+        let Some(path) = config.get_str("storage.path") else {
+            return Ok(None);
+        };
+        let history = History::open(path).map_err(|e| config.invalid("storage.path", e))?;
+
+        Ok(Some(history))
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> io::Result<History> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(History { file })
+    }
+
+    /// Appends `message`, stamped with the current time.
+    pub fn append(&mut self, message: Message) -> io::Result<()> {
+        let at = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        self.file.write_all(&encode_frame(&Stored { at, message })?)
+    }
+
+    /// Reads back the history at `path`, oldest message first.
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<Stored>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut stored = Vec::new();
+        while let Some(message) = read_frame(&mut reader)? {
+            stored.push(message);
+        }
+
+        Ok(stored)
+    }
+}
@@ -0,0 +1,85 @@
+//! TLS for connections between clients and the server.
+//!
+//! A server given `tls.cert` and `tls.key` only speaks TLS to its clients.
+//! A client given `tls.ca` only trusts servers whose certificate that CA
+//! signed for `tls.server_name`, which defaults to the host in
+//! `server.addr`. Federation links and direct links between clients stay
+//! plain TCP.
+
+use crate::config::{Config, ConfigError};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::sync::Arc;
+
+/// Reads `tls.cert` and `tls.key`. TLS is off unless both are set.
+pub fn server_config(config: &Config) -> Result<Option<Arc<ServerConfig>>, ConfigError> {
+    let (cert, key) = match (config.get_str("tls.cert"), config.get_str("tls.key")) {
+        (None, None) => return Ok(None),
+        (Some(cert), Some(key)) => (cert, key),
+        (Some(_), None) => return Err(config.invalid("tls.key", "required when tls.cert is set")),
+        (None, Some(_)) => return Err(config.invalid("tls.cert", "required when tls.key is set")),
+    };
+    let chain = certificates(config, "tls.cert", cert)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| config.invalid("tls.key", e))?;
+    let server = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, key))
+        .map_err(|e| config.invalid("tls.key", e))?;
+
+    Ok(Some(Arc::new(server)))
+}
+
+/// What a client needs to connect over TLS.
+#[derive(Debug, Clone)]
+pub struct ClientTls {
+    pub config: Arc<ClientConfig>,
+    /// What the server's certificate has to be for.
+    pub server_name: ServerName<'static>,
+}
+
+impl ClientTls {
+    /// Reads `tls.ca` and `tls.server_name`, for a client connecting to
+    /// `addr`. TLS is off unless `tls.ca` is set.
+    pub fn from_config(config: &Config, addr: &str) -> Result<Option<ClientTls>, ConfigError> {
+        let Some(ca) = config.get_str("tls.ca") else {
+            return Ok(None);
+        };
+        let mut roots = RootCertStore::empty();
+        for cert in certificates(config, "tls.ca", ca)? {
+            roots.add(cert).map_err(|e| config.invalid("tls.ca", e))?;
+        }
+        let client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| config.invalid("tls.ca", e))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let (key, name) = match config.get_str("tls.server_name") {
+            Some(name) => ("tls.server_name", name),
+            None => ("server.addr", host(addr)),
+        };
+        let server_name = ServerName::try_from(name.to_string())
+            .map_err(|e| config.invalid(key, format!("{:?} is not a server name: {}", name, e)))?;
+
+        Ok(Some(ClientTls { config: Arc::new(client), server_name }))
+    }
+}
+
+/// The certificates in the PEM file `path`, the value of `key`.
+fn certificates(config: &Config, key: &str, path: &str) -> Result<Vec<CertificateDer<'static>>, ConfigError> {
+    let chain = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| config.invalid(key, e))?;
+    if chain.is_empty() {
+        return Err(config.invalid(key, "no certificates in the file"));
+    }
+
+    Ok(chain)
+}
+
+/// `addr` without its port, and without the brackets of an IPv6 address.
+fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host)
+}
@@ -0,0 +1,151 @@
+//! `chatctl` commands and the admin socket of `async_std_server`.
+
+use chat_rs::admin::{self, parse_command, AdminCommand, AdminResponse, AdminSettings, DEFAULT_BAN};
+use chat_rs::config::Config;
+use chat_rs::protocol::{encode_frame, read_frame, ClientFrame, ErrorCode, Request, ServerFrame};
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+fn parse(line: &str) -> Result<AdminCommand, String> {
+    let words: Vec<String> = line.split_whitespace().map(String::from).collect();
+    parse_command(&words)
+}
+
+#[test]
+fn parses_commands() {
+    assert_eq!(parse("users"), Ok(AdminCommand::Users));
+    assert_eq!(parse("kick bob"), Ok(AdminCommand::Kick { name: String::from("bob") }));
+    assert_eq!(parse("ban bob"), Ok(AdminCommand::Ban { name: String::from("bob"), duration: DEFAULT_BAN }));
+    assert_eq!(
+        parse("ban bob 60"),
+        Ok(AdminCommand::Ban { name: String::from("bob"), duration: Duration::from_secs(60) }),
+    );
+    assert_eq!(parse("announce back  in 5"), Ok(AdminCommand::Announce { text: String::from("back in 5") }));
+    assert_eq!(parse("groups"), Ok(AdminCommand::Groups { name: None }));
+    assert_eq!(parse("groups rust"), Ok(AdminCommand::Groups { name: Some(String::from("rust")) }));
+    assert_eq!(parse("stats"), Ok(AdminCommand::Stats));
+    assert_eq!(parse("reload"), Ok(AdminCommand::Reload));
+}
+
+#[test]
+fn refuses_bad_commands() {
+    assert_eq!(parse(""), Err(String::from("no command given, see --help")));
+    assert_eq!(parse("restart"), Err(String::from("unknown command or arguments for \"restart\", see --help")));
+    for line in ["kick", "kick bob carol", "announce", "users now", "ban bob 1 2"] {
+        assert!(parse(line).unwrap_err().starts_with("unknown command or arguments"), "{}", line);
+    }
+    assert!(parse("ban bob soon").unwrap_err().starts_with("ban length \"soon\": "));
+    assert!(parse("ban bob -1").is_err());
+}
+
+#[test]
+fn needs_a_token_with_the_socket() {
+    let load = |args: &[&str]| Config::from_sources(args.iter().map(|arg| arg.to_string()), |_| None).unwrap();
+    assert!(AdminSettings::from_config(&load(&[])).unwrap().is_none());
+
+    let e = AdminSettings::from_config(&load(&["--admin.socket=/tmp/chat.sock"])).unwrap_err();
+    assert_eq!(e.to_string(), "admin.token: required when admin.socket is set");
+
+    let admin = AdminSettings::from_config(&load(&["--admin.socket=/tmp/chat.sock", "--admin.token=s3cret"]))
+        .unwrap()
+        .unwrap();
+    assert!(admin.authorizes("s3cret"));
+    assert!(!admin.authorizes("s3cre"));
+    assert!(!admin.authorizes("s3cret!"));
+}
+
+/// An `async_std_server` reading `config`, killed when dropped.
+struct Server {
+    process: Child,
+    addr: String,
+    socket: PathBuf,
+}
+
+impl Server {
+    fn start(config: &Path, socket: &Path) -> Server {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let process = Command::new(env!("CARGO_BIN_EXE_async_std_server"))
+            .arg(&addr)
+            .env("CHAT_CONFIG", config)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server { process, addr, socket: socket.to_path_buf() };
+
+        for _ in 0..250 {
+            if server.call("token", AdminCommand::Users).is_ok() {
+                return server;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("no admin socket at {}", socket.display());
+    }
+
+    fn call(&self, token: &str, command: AdminCommand) -> std::io::Result<AdminResponse> {
+        admin::call(&self.socket, token, command)
+    }
+
+    /// Connects a client logged in as `name`.
+    fn login(&self, name: &str) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(&self.addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let login = ClientFrame::Login { name: name.to_string(), direct_addrs: Vec::new() };
+        stream.write_all(&encode_frame(&Request { id: 1, frame: login }).unwrap()).unwrap();
+        let mut reader = BufReader::new(stream);
+        match read_frame(&mut reader).unwrap() {
+            Some(ServerFrame::Welcome { .. }) => reader,
+            frame => panic!("could not log in as {}: {:?}", name, frame),
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+#[test]
+fn operates_a_running_server() {
+    let dir = std::env::temp_dir();
+    let socket = dir.join(format!("chat-rs-admin-{}.sock", std::process::id()));
+    let config = dir.join(format!("chat-rs-admin-{}.toml", std::process::id()));
+    let settings = format!("[admin]\nsocket = {:?}\ntoken = \"token\"\n", socket.to_str().unwrap());
+    std::fs::write(&config, &settings).unwrap();
+    let server = Server::start(&config, &socket);
+
+    assert_eq!(server.call("wrong", AdminCommand::Users).unwrap(), AdminResponse::Error(String::from("wrong token")));
+
+    let mut alice = server.login("alice");
+    let AdminResponse::Users(users) = server.call("token", AdminCommand::Users).unwrap() else {
+        panic!("users not listed");
+    };
+    assert_eq!(users.iter().map(|user| user.name.as_str()).collect::<Vec<_>>(), ["alice"]);
+    assert!(users[0].addr.ip().is_loopback());
+
+    let kick = || AdminCommand::Kick { name: String::from("alice") };
+    assert_eq!(server.call("token", kick()).unwrap(), AdminResponse::Done);
+    let mut frames = std::iter::from_fn(|| read_frame::<_, ServerFrame>(&mut alice).ok().flatten());
+    assert!(
+        frames.any(|frame| matches!(frame, ServerFrame::Error { error: ErrorCode::Kicked { .. }, .. })),
+        "alice was not told about the kick",
+    );
+    assert_eq!(server.call("token", AdminCommand::Users).unwrap(), AdminResponse::Users(Vec::new()));
+    assert_eq!(server.call("token", kick()).unwrap(), AdminResponse::Error(String::from("no user named alice")));
+
+    std::fs::write(&config, format!("{}[heartbeat]\ninterval = 5\n", settings)).unwrap();
+    assert_eq!(server.call("token", AdminCommand::Reload).unwrap(), AdminResponse::Done);
+    std::fs::write(&config, format!("{}[bans]\nduration = \"soon\"\n", settings)).unwrap();
+    let response = server.call("token", AdminCommand::Reload).unwrap();
+    std::fs::remove_file(&config).unwrap();
+    let AdminResponse::Error(e) = response else {
+        panic!("reloaded a broken configuration: {:?}", response);
+    };
+    assert!(e.starts_with("bans.duration = \"soon\""), "{}", e);
+}
@@ -0,0 +1,123 @@
+//! Where settings come from and how bad ones are reported.
+
+use chat_rs::config::{Config, MAX_SECS};
+use chat_rs::storage::History;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Writes `text` to a TOML file named after the test.
+fn file(test: &str, text: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("chat-rs-{}-{}.toml", test, std::process::id()));
+    std::fs::write(&path, text).unwrap();
+    path
+}
+
+fn load(args: &[&str], vars: &[(&str, &str)]) -> Result<Config, String> {
+    let args = args.iter().map(|arg| arg.to_string());
+    let vars: Vec<(String, String)> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    Config::from_sources(args, |var| vars.iter().find(|(k, _)| k == var).map(|(_, v)| v.clone()))
+        .map_err(|e| e.to_string())
+}
+
+#[test]
+fn flags_beat_the_environment_which_beats_the_file() {
+    let path = file("precedence", "[server]\nworkers = 1\nthreads = 2\nshards = 3\n");
+    let vars = [("CHAT_CONFIG", path.to_str().unwrap()), ("CHAT_THREADS", "20"), ("CHAT_BROKER_SHARDS", "30")];
+    let config = load(&["--server.shards", "300"], &vars).unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(config.get("server.workers", 0).unwrap(), 1);
+    assert_eq!(config.get("server.threads", 0).unwrap(), 20);
+    assert_eq!(config.get("server.shards", 0).unwrap(), 300);
+    assert_eq!(config.secs("server.shutdown_timeout", 5).unwrap(), Duration::from_secs(5));
+}
+
+#[test]
+fn positionals_set_the_address() {
+    let config = load(&["127.0.0.1:9000"], &[("CHAT_ADDR", "127.0.0.1:8000")]).unwrap();
+    assert_eq!(config.get_str("server.addr"), Some("127.0.0.1:9000"));
+
+    let e = load(&["--server.addr=127.0.0.1:8000", "127.0.0.1:9000"], &[]).unwrap_err();
+    assert_eq!(e, "unexpected argument \"127.0.0.1:9000\"");
+    assert!(load(&["127.0.0.1:9000", "more"], &[]).is_err());
+}
+
+#[test]
+fn switches_and_aliases() {
+    let config = load(&["--lan"], &[]).unwrap();
+    assert!(config.get("client.lan", false).unwrap());
+    let config = load(&["--client.lan=false"], &[("CHAT_LAN", "true")]).unwrap();
+    assert!(!config.get("client.lan", true).unwrap());
+}
+
+#[test]
+fn tables_and_lists_become_lists() {
+    let path = file("lists", "[federation.peers]\nb = \"127.0.0.1:9000\"\n");
+    let config = load(&["--config", path.to_str().unwrap()], &[]).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(config.get_str("federation.peers"), Some("b=127.0.0.1:9000"));
+
+    let path = file("arrays", "[federation]\npeers = [\"b\", \"c\"]\n");
+    let config = load(&[&format!("--config={}", path.display())], &[]).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(config.get_str("federation.peers"), Some("b,c"));
+}
+
+#[test]
+fn errors_name_the_key_and_where_it_was_set() {
+    let e = load(&["--server.workers", "many"], &[]).unwrap().get("server.workers", 0usize).unwrap_err();
+    assert!(e.to_string().starts_with("server.workers = \"many\" (on the command line): "), "{}", e);
+
+    let config = load(&[], &[("CHAT_MESSAGE_RATE", "-")]).unwrap();
+    let e = config.get("rates.messages", 10.0).unwrap_err();
+    assert!(e.to_string().starts_with("rates.messages = \"-\" (from CHAT_MESSAGE_RATE): "), "{}", e);
+
+    let path = file("errors", "[bans]\nduration = 99999999999\n");
+    let config = load(&["--config", path.to_str().unwrap()], &[]).unwrap();
+    let e = config.secs("bans.duration", 300).unwrap_err().to_string();
+    let expected = format!("bans.duration = \"99999999999\" (in {}): must be at most {} seconds", path.display(), MAX_SECS);
+    std::fs::remove_file(path).unwrap();
+    assert!(e.starts_with(&expected), "{}", e);
+
+    let e = config.invalid("heartbeat.interval", "must be positive").to_string();
+    assert_eq!(e, "heartbeat.interval: must be positive", "unset keys have no value to show");
+}
+
+#[test]
+fn refuses_unknown_settings() {
+    assert_eq!(load(&["--server.adr=x"], &[]).unwrap_err(), "--server.adr: unknown setting, see --help");
+    assert_eq!(load(&["--server.addr"], &[]).unwrap_err(), "--server.addr needs a value");
+    assert_eq!(load(&["--config"], &[]).unwrap_err(), "--config needs a file name");
+
+    let path = file("unknown", "[server]\nadr = \"x\"\n");
+    let e = load(&[], &[("CHAT_CONFIG", path.to_str().unwrap())]).unwrap_err();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(e, format!("server.adr (in {}): unknown setting", path.display()));
+}
+
+#[test]
+fn reports_changed_keys() {
+    let old = load(&["--heartbeat.interval=5"], &[]).unwrap();
+    let new = load(&["--heartbeat.interval=5", "--log.level=debug"], &[]).unwrap();
+    assert_eq!(old.changed(&new), ["log.level"]);
+    assert!(new.changed(&new).is_empty());
+}
+
+#[test]
+fn refuses_unsupported_settings() {
+    let config = load(&["--tls.cert=server.crt"], &[]).unwrap();
+    assert!(config.unsupported(&["storage.path"]).is_ok());
+    let e = config.unsupported(&["storage.path", "tls.cert"]).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "tls.cert = \"server.crt\" (on the command line): not supported by this server, use async_std_server",
+    );
+}
+
+#[test]
+fn history_needs_a_writable_file() {
+    assert!(History::from_config(&load(&[], &[]).unwrap()).unwrap().is_none());
+    let config = load(&[], &[("CHAT_STORAGE_PATH", "/nonexistent/history")]).unwrap();
+    let e = History::from_config(&config).unwrap_err();
+    assert!(e.to_string().starts_with("storage.path = \"/nonexistent/history\" (from CHAT_STORAGE_PATH): "), "{}", e);
+}
@@ -0,0 +1,134 @@
+//! Direct links between clients, over loopback.
+
+use chat_rs::direct::{DirectLinks, PeerEvent, WRITE_TIMEOUT};
+use chat_rs::protocol::{write_frame, Message, PeerFrame, Recipient, UserId};
+use std::io::{BufWriter, Read};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Accepts links on loopback. Events come out as text to compare them
+/// easily.
+fn listen() -> (DirectLinks, SocketAddr, Receiver<String>) {
+    let (sender, events) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    let (links, addr) = DirectLinks::listen("127.0.0.1:0", move |event| {
+        let event = match event {
+            PeerEvent::Connected(peer) => format!("connected {}", peer),
+            PeerEvent::Message(message) => format!("{}: {}", message.from.0, message.text.unwrap_or_default()),
+            PeerEvent::Disconnected(peer) => format!("disconnected {}", peer),
+        };
+        let _ = sender.lock().unwrap().send(event);
+    })
+    .unwrap();
+
+    (links, addr, events)
+}
+
+fn next(events: &Receiver<String>) -> Option<String> {
+    events.recv_timeout(Duration::from_secs(2)).ok()
+}
+
+fn message(from: &str, text: &str) -> Message {
+    Message {
+        from: UserId(from.to_string()),
+        to: Recipient::User(UserId(String::from("alice"))),
+        text: Some(text.to_string()),
+        media: None,
+    }
+}
+
+/// Opens a link to `addr` by hand, introducing ourselves as `name`.
+fn hello(addr: SocketAddr, name: &str) -> BufWriter<TcpStream> {
+    let mut writer = BufWriter::new(TcpStream::connect(addr).unwrap());
+    write_frame(&mut writer, &PeerFrame::Hello { name: name.to_string() }).unwrap();
+    writer
+}
+
+/// Whether the other end closed the link.
+fn refused(link: BufWriter<TcpStream>) -> bool {
+    let mut stream = link.into_inner().unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    match stream.read(&mut [0]) {
+        Ok(n) => n == 0,
+        Err(e) => e.kind() == std::io::ErrorKind::ConnectionReset,
+    }
+}
+
+#[test]
+fn links_with_expected_peers_only() {
+    let (alice, addr, events) = listen();
+
+    assert!(refused(hello(addr, "bob")), "nobody announced bob");
+    alice.expect("bob", &[SocketAddr::from(([10, 0, 0, 1], 7000))]);
+    assert!(refused(hello(addr, "bob")), "bob was announced on another address");
+    assert!(!alice.is_connected("bob"));
+
+    alice.expect("bob", &[SocketAddr::from(([127, 0, 0, 1], 7000))]);
+    let (bob, _, _) = listen();
+    bob.connect("bob", "alice", &[addr]).unwrap();
+    assert_eq!(next(&events).as_deref(), Some("connected bob"));
+    assert!(bob.send("alice", message("bob", "hi")));
+    assert_eq!(next(&events).as_deref(), Some("bob: hi"));
+}
+
+#[test]
+fn messages_are_from_the_linked_peer() {
+    let (alice, addr, events) = listen();
+    alice.expect("bob", &[]);
+    let mut bob = hello(addr, "bob");
+    assert_eq!(next(&events).as_deref(), Some("connected bob"));
+
+    write_frame(&mut bob, &PeerFrame::Message(message("carol", "it's carol"))).unwrap();
+    assert_eq!(next(&events).as_deref(), Some("bob: it's carol"));
+
+    alice.rename("bob", "robert");
+    write_frame(&mut bob, &PeerFrame::Message(message("bob", "renamed"))).unwrap();
+    assert_eq!(next(&events).as_deref(), Some("robert: renamed"));
+
+    drop(bob);
+    assert_eq!(next(&events).as_deref(), Some("disconnected robert"));
+    assert!(!alice.is_connected("robert"));
+}
+
+#[test]
+fn existing_links_are_kept() {
+    let (alice, addr, events) = listen();
+    alice.expect("bob", &[]);
+    let mut bob = hello(addr, "bob");
+    assert_eq!(next(&events).as_deref(), Some("connected bob"));
+
+    assert!(refused(hello(addr, "bob")));
+    write_frame(&mut bob, &PeerFrame::Message(message("bob", "still here"))).unwrap();
+    assert_eq!(next(&events).as_deref(), Some("bob: still here"));
+    assert_eq!(alice.peers(), ["bob"]);
+}
+
+#[test]
+fn peers_that_stop_reading_are_dropped() {
+    let (alice, addr, events) = listen();
+    alice.expect("bob", &[]);
+    // Bob links but never reads.
+    let _bob = hello(addr, "bob");
+    assert_eq!(next(&events).as_deref(), Some("connected bob"));
+
+    let sender = alice.clone();
+    let sending = thread::spawn(move || {
+        let text = "x".repeat(64 * 1024);
+        // Far more than the socket buffers hold.
+        (0..1000).all(|_| sender.send("bob", message("alice", &text)))
+    });
+    // A send stuck on bob holds up nothing else.
+    thread::sleep(Duration::from_millis(500));
+    let started = Instant::now();
+    assert!(alice.is_connected("bob"));
+    assert!(started.elapsed() < Duration::from_millis(100), "{:?}", started.elapsed());
+
+    let started = Instant::now();
+    assert!(!sending.join().unwrap(), "bob kept taking messages");
+    assert!(started.elapsed() < WRITE_TIMEOUT * 3, "{:?}", started.elapsed());
+    assert!(!alice.is_connected("bob"));
+    assert_eq!(next(&events).as_deref(), Some("disconnected bob"));
+}
@@ -0,0 +1,102 @@
+//! Federation settings, addresses and the link handshake over loopback.
+
+use async_std::net::{TcpListener, TcpStream};
+use async_std::task;
+use chat_rs::config::Config;
+use chat_rs::federation::{handshake, split_address, Federation};
+use std::io;
+
+fn federation(name: &str, secret: &str, peers: &[&str]) -> Federation {
+    Federation {
+        server_name: name.to_string(),
+        secret: secret.as_bytes().to_vec(),
+        listen_addr: None,
+        peers: peers.iter().map(|peer| (peer.to_string(), None)).collect(),
+    }
+}
+
+/// Runs the handshake between `dialer` and `listener` and returns what
+/// each of them concluded.
+fn link(dialer: Federation, listener: Federation) -> (io::Result<String>, io::Result<String>) {
+    task::block_on(async {
+        let socket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let accepted = task::spawn(async move {
+            let (mut stream, _) = socket.accept().await.unwrap();
+            handshake(&mut stream, &listener).await
+        });
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let dialed = handshake(&mut stream, &dialer).await;
+        // Whoever refuses hangs up, the other side may still be waiting.
+        drop(stream);
+
+        (dialed, accepted.await)
+    })
+}
+
+fn config(vars: &[(&str, &str)]) -> Config {
+    let vars: Vec<(String, String)> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    Config::from_sources(Vec::new(), |var| vars.iter().find(|(k, _)| k == var).map(|(_, v)| v.clone())).unwrap()
+}
+
+#[test]
+fn peers_learn_each_others_names() {
+    let (dialed, accepted) = link(federation("a", "secret", &["b"]), federation("b", "secret", &["a"]));
+    assert_eq!(dialed.unwrap(), "b");
+    assert_eq!(accepted.unwrap(), "a");
+}
+
+#[test]
+fn refuses_wrong_secrets() {
+    let (dialed, accepted) = link(federation("a", "secret", &["b"]), federation("b", "guess", &["a"]));
+    assert_eq!(dialed.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(accepted.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn refuses_servers_that_are_not_peers() {
+    let (_, accepted) = link(federation("c", "secret", &["b"]), federation("b", "secret", &["a"]));
+    assert_eq!(accepted.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+    let (dialed, _) = link(federation("a", "secret", &["b"]), federation("d", "secret", &["a"]));
+    assert_eq!(dialed.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn splits_addresses_on_the_last_at() {
+    assert_eq!(split_address("bob", "a"), ("bob", None));
+    assert_eq!(split_address("bob@a", "a"), ("bob", None));
+    assert_eq!(split_address("bob@b", "a"), ("bob", Some("b")));
+    assert_eq!(split_address("bob@c@b", "a"), ("bob@c", Some("b")));
+    // Without federation there is no local server name.
+    assert_eq!(split_address("bob@b", ""), ("bob", Some("b")));
+}
+
+#[test]
+fn reads_peers_from_the_config() {
+    assert!(Federation::from_config(&config(&[])).unwrap().is_none());
+
+    let federation = Federation::from_config(&config(&[
+        ("CHAT_FEDERATION_SECRET", "secret"),
+        ("CHAT_SERVER_NAME", "a"),
+        ("CHAT_FEDERATION_PEERS", "b=127.0.0.1:9000, c"),
+    ]))
+    .unwrap()
+    .unwrap();
+    let dialed = (String::from("b"), Some("127.0.0.1:9000".parse().unwrap()));
+    assert_eq!(federation.peers, [dialed, (String::from("c"), None)]);
+    assert!(federation.is_peer("c"));
+    assert!(!federation.is_peer("a"));
+
+    for (peers, problem) in [("b=nowhere", "nowhere"), ("x@y", "\"x@y\""), ("a", "\"a\"")] {
+        let e = Federation::from_config(&config(&[
+            ("CHAT_FEDERATION_SECRET", "secret"),
+            ("CHAT_SERVER_NAME", "a"),
+            ("CHAT_FEDERATION_PEERS", peers),
+        ]))
+        .unwrap_err()
+        .to_string();
+        assert!(e.starts_with("federation.peers"), "{}", e);
+        assert!(e.contains(problem), "{}", e);
+    }
+}
@@ -0,0 +1,99 @@
+//! The Prometheus text format and the scrape endpoint.
+
+use async_std::task;
+use chat_rs::metrics::{serve, Counter, Exposition, Gauge, Histogram};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+#[test]
+fn writes_counters_and_gauges() {
+    let messages = Counter::new();
+    messages.add(3);
+    messages.inc();
+    let peers = Gauge::new();
+    peers.inc();
+    peers.inc();
+    peers.dec();
+
+    let mut metrics = Exposition::new();
+    metrics.counter("chat_messages_total", "Messages routed.", &messages);
+    metrics.gauge("chat_peers", "Connected peers.", &peers);
+    assert_eq!(
+        metrics.into_text(),
+        "# HELP chat_messages_total Messages routed.\n\
+         # TYPE chat_messages_total counter\n\
+         chat_messages_total 4\n\
+         # HELP chat_peers Connected peers.\n\
+         # TYPE chat_peers gauge\n\
+         chat_peers 1\n",
+    );
+}
+
+#[test]
+fn histogram_buckets_are_cumulative() {
+    static BOUNDS: &[f64] = &[0.1, 1.0];
+    let latency = Histogram::new(BOUNDS);
+    for value in [0.05, 0.1, 0.5, 2.0] {
+        latency.observe(value);
+    }
+
+    let mut metrics = Exposition::new();
+    metrics.histogram("chat_lag_seconds", "Lag.", &latency);
+    assert_eq!(
+        metrics.into_text(),
+        "# HELP chat_lag_seconds Lag.\n\
+         # TYPE chat_lag_seconds histogram\n\
+         chat_lag_seconds_bucket{le=\"0.1\"} 2\n\
+         chat_lag_seconds_bucket{le=\"1\"} 3\n\
+         chat_lag_seconds_bucket{le=\"+Inf\"} 4\n\
+         chat_lag_seconds_sum 2.65\n\
+         chat_lag_seconds_count 4\n",
+    );
+}
+
+#[test]
+fn empty_histograms_have_every_bucket() {
+    static BOUNDS: &[f64] = &[0.5];
+    let mut metrics = Exposition::new();
+    metrics.histogram("chat_lag_seconds", "Lag.", &Histogram::new(BOUNDS));
+    let text = metrics.into_text();
+    assert!(text.contains("chat_lag_seconds_bucket{le=\"0.5\"} 0\n"), "{}", text);
+    assert!(text.ends_with("chat_lag_seconds_sum 0\nchat_lag_seconds_count 0\n"), "{}", text);
+}
+
+#[test]
+fn escapes_label_values() {
+    let mut metrics = Exposition::new();
+    metrics.sample("chat_queue", &[("peer", "a\"b\\c\nd"), ("shard", "0")], 7);
+    assert_eq!(metrics.into_text(), "chat_queue{peer=\"a\\\"b\\\\c\\nd\",shard=\"0\"} 7\n");
+}
+
+/// Sends `request` to the endpoint and returns the response.
+fn scrape(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn answers_scrapes_on_metrics_only() {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    task::spawn(serve(addr, || async { String::from("chat_peers 1\n") }));
+    // The endpoint starts in the background.
+    while TcpStream::connect(addr).is_err() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let response = scrape(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nchat_peers 1\n"), "{}", response);
+
+    let response = scrape(addr, "GET / HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+}
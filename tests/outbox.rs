@@ -0,0 +1,80 @@
+//! What each `Policy` gives up when an outbox is full.
+
+use async_std::task;
+use chat_rs::outbox::{outbox, OutboxReceiver, Policy};
+
+fn frame(text: &str) -> Vec<u8> {
+    text.as_bytes().to_vec()
+}
+
+/// Everything the consumer gets until the outbox ends.
+fn received(receiver: &mut OutboxReceiver) -> Vec<String> {
+    task::block_on(async {
+        let mut frames = Vec::new();
+        while let Some(bytes) = receiver.next().await {
+            frames.push(String::from_utf8(bytes).unwrap());
+        }
+        frames
+    })
+}
+
+#[test]
+fn drop_oldest_keeps_the_newest_frames() {
+    let (messages, mut receiver) = outbox(2, Policy::DropOldest);
+    for text in ["a", "b", "c"] {
+        assert!(messages.push(frame(text), false));
+    }
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages.dropped(), 1);
+
+    drop(messages);
+    assert_eq!(received(&mut receiver), ["b", "c"]);
+}
+
+#[test]
+fn drop_ephemeral_gives_up_heartbeats_first() {
+    let (messages, mut receiver) = outbox(2, Policy::DropEphemeral);
+    assert!(messages.push(frame("message"), false));
+    assert!(messages.push(frame("ping"), true));
+    assert!(messages.push(frame("reply"), false));
+    // Nothing ephemeral is left, the oldest frame goes.
+    assert!(messages.push(frame("another"), false));
+    assert_eq!(messages.dropped(), 2);
+
+    drop(messages);
+    assert_eq!(received(&mut receiver), ["reply", "another"]);
+}
+
+#[test]
+fn disconnect_cuts_the_consumer_off() {
+    let (messages, mut receiver) = outbox(1, Policy::Disconnect);
+    assert!(messages.push(frame("a"), false));
+    assert!(!messages.push(frame("b"), true));
+    assert!(!messages.push(frame("c"), false));
+    assert!(receiver.overflowed());
+
+    // What was queued stays around for whoever takes over, e.g. a link
+    // that comes back up.
+    assert_eq!(received(&mut receiver), Vec::<String>::new());
+    assert_eq!(receiver.drain(), [frame("a")]);
+}
+
+#[test]
+fn closed_outboxes_deliver_what_is_queued() {
+    let (messages, mut receiver) = outbox(4, Policy::Disconnect);
+    let other = messages.clone();
+    assert!(messages.push(frame("a"), false));
+    messages.close();
+    assert!(!other.push(frame("b"), false));
+
+    assert_eq!(received(&mut receiver), ["a"]);
+    assert!(!receiver.overflowed());
+}
+
+#[test]
+fn policies_parse_as_they_print() {
+    for policy in [Policy::DropOldest, Policy::DropEphemeral, Policy::Disconnect] {
+        assert_eq!(policy.to_string().parse::<Policy>(), Ok(policy));
+    }
+    assert!("drop-newest".parse::<Policy>().unwrap_err().contains("drop-newest"));
+}
@@ -0,0 +1,164 @@
+//! Runs the same checks against every poller backend of the host.
+
+use chat_rs::poller::{Event, Interest, Poll, Poller, Token};
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SHORT: Duration = Duration::from_millis(50);
+const LONG: Duration = Duration::from_secs(5);
+
+/// Waits until `token` is reported, folding its events into one.
+fn wait_for(poller: &dyn Poller, token: Token) -> Option<Event> {
+    let deadline = Instant::now() + LONG;
+    let mut events = Vec::new();
+    while Instant::now() < deadline {
+        poller.wait(&mut events, Some(SHORT)).unwrap();
+        let mut found: Option<Event> = None;
+        for event in events.iter().filter(|event| event.token == token) {
+            let merged = found.get_or_insert(*event);
+            merged.readable |= event.readable;
+            merged.writable |= event.writable;
+            merged.closed |= event.closed;
+        }
+        if found.is_some() {
+            return found;
+        }
+    }
+
+    None
+}
+
+fn ready(poller: &dyn Poller) -> Vec<Event> {
+    let mut events = Vec::new();
+    poller.wait(&mut events, Some(SHORT)).unwrap();
+    events
+}
+
+fn reports_readable_until_drained(poller: &dyn Poller) {
+    let (mut ours, mut theirs) = UnixStream::pair().unwrap();
+    poller.register(ours.as_raw_fd(), 7, Interest::READABLE).unwrap();
+    assert!(ready(poller).is_empty(), "nothing was sent yet");
+
+    theirs.write_all(b"hi").unwrap();
+    let event = wait_for(poller, 7).expect("readable");
+    assert!(event.readable && !event.writable && !event.closed, "{:?}", event);
+    // Level-triggered: still ready while unread.
+    assert!(wait_for(poller, 7).is_some());
+
+    let mut buf = [0u8; 2];
+    ours.read_exact(&mut buf).unwrap();
+    assert!(ready(poller).is_empty(), "everything was read");
+    poller.deregister(ours.as_raw_fd()).unwrap();
+}
+
+fn modify_changes_interest_and_token(poller: &dyn Poller) {
+    let (ours, _theirs) = UnixStream::pair().unwrap();
+    poller.register(ours.as_raw_fd(), 1, Interest::READABLE).unwrap();
+    assert!(ready(poller).is_empty());
+
+    poller.modify(ours.as_raw_fd(), 2, Interest::WRITABLE).unwrap();
+    let event = wait_for(poller, 2).expect("writable");
+    assert!(event.writable && !event.readable, "{:?}", event);
+    assert!(ready(poller).iter().all(|event| event.token != 1));
+
+    poller.modify(ours.as_raw_fd(), 3, Interest::READABLE).unwrap();
+    assert!(ready(poller).is_empty());
+    poller.deregister(ours.as_raw_fd()).unwrap();
+}
+
+fn deregistered_is_not_reported(poller: &dyn Poller) {
+    let (ours, mut theirs) = UnixStream::pair().unwrap();
+    poller.register(ours.as_raw_fd(), 4, Interest::BOTH).unwrap();
+    assert!(wait_for(poller, 4).is_some());
+
+    poller.deregister(ours.as_raw_fd()).unwrap();
+    theirs.write_all(b"hi").unwrap();
+    assert!(ready(poller).is_empty());
+}
+
+fn reports_hang_up(poller: &dyn Poller) {
+    let (mut ours, theirs) = UnixStream::pair().unwrap();
+    poller.register(ours.as_raw_fd(), 5, Interest::READABLE).unwrap();
+    drop(theirs);
+
+    let event = wait_for(poller, 5).expect("hang-up");
+    assert!(event.closed || event.readable, "{:?}", event);
+    assert_eq!(ours.read(&mut [0u8; 1]).unwrap(), 0, "reads EOF");
+    poller.deregister(ours.as_raw_fd()).unwrap();
+}
+
+fn wakes_up_for_registration_from_another_thread(poller: Arc<dyn Poller>) {
+    let (ours, mut theirs) = UnixStream::pair().unwrap();
+    theirs.write_all(b"hi").unwrap();
+
+    let waiter = {
+        let poller = Arc::clone(&poller);
+        thread::spawn(move || {
+            let mut events = Vec::new();
+            // Backends may return early without events, keep waiting.
+            while events.is_empty() {
+                poller.wait(&mut events, None).unwrap();
+            }
+            events
+        })
+    };
+    thread::sleep(SHORT);
+    poller.register(ours.as_raw_fd(), 6, Interest::READABLE).unwrap();
+
+    let events = waiter.join().unwrap();
+    assert!(events.iter().any(|event| event.token == 6 && event.readable), "{:?}", events);
+    poller.deregister(ours.as_raw_fd()).unwrap();
+}
+
+fn times_out(poller: &dyn Poller) {
+    let started = Instant::now();
+    assert!(ready(poller).is_empty());
+    assert!(started.elapsed() >= SHORT - Duration::from_millis(5));
+}
+
+fn exercise(poller: Arc<dyn Poller>) {
+    times_out(&*poller);
+    reports_readable_until_drained(&*poller);
+    modify_changes_interest_and_token(&*poller);
+    deregistered_is_not_reported(&*poller);
+    reports_hang_up(&*poller);
+    wakes_up_for_registration_from_another_thread(poller);
+}
+
+#[test]
+fn poll() {
+    exercise(Arc::new(Poll::new().unwrap()));
+}
+
+#[test]
+fn poll_refuses_unknown_descriptors() {
+    let poller = Poll::new().unwrap();
+    let (ours, _theirs) = UnixStream::pair().unwrap();
+    assert!(poller.modify(ours.as_raw_fd(), 1, Interest::READABLE).is_err());
+    assert!(poller.deregister(ours.as_raw_fd()).is_err());
+    poller.register(ours.as_raw_fd(), 1, Interest::READABLE).unwrap();
+    assert!(poller.register(ours.as_raw_fd(), 1, Interest::READABLE).is_err());
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn epoll() {
+    exercise(Arc::new(chat_rs::poller::Epoll::new().unwrap()));
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly",
+))]
+#[test]
+fn kqueue() {
+    exercise(Arc::new(chat_rs::poller::Kqueue::new().unwrap()));
+}
@@ -0,0 +1,126 @@
+//! Token buckets, budgets and the guard's bans.
+
+use chat_rs::ratelimit::{BanPolicy, Budget, Guard, Rate, TokenBucket};
+use std::net::{IpAddr, Ipv4Addr};
+use std::thread;
+use std::time::Duration;
+
+fn rate(per_second: f64, burst: f64) -> Rate {
+    Rate { per_second, burst }
+}
+
+const IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+fn no_bans() -> BanPolicy {
+    BanPolicy { strikes: 0, window: Duration::from_secs(60), duration: Duration::from_secs(60) }
+}
+
+#[test]
+fn buckets_hold_a_burst_and_refill() {
+    let mut bucket = TokenBucket::new(rate(100.0, 2.0));
+    assert_eq!(bucket.take(1.0), Ok(()));
+    assert_eq!(bucket.take(1.0), Ok(()));
+    let wait = bucket.take(1.0).unwrap_err();
+    assert!(wait > Duration::ZERO && wait <= Duration::from_millis(10), "{:?}", wait);
+
+    thread::sleep(Duration::from_millis(30));
+    assert_eq!(bucket.take(2.0), Ok(()));
+    assert!(bucket.take(1.0).is_err(), "refills stop at the burst");
+}
+
+#[test]
+fn checking_takes_nothing() {
+    let mut bucket = TokenBucket::new(rate(0.001, 1.0));
+    assert_eq!(bucket.check(1.0), Ok(()));
+    assert_eq!(bucket.check(1.0), Ok(()));
+    assert_eq!(bucket.take(1.0), Ok(()));
+    assert!(bucket.check(1.0).is_err());
+}
+
+#[test]
+fn large_amounts_need_a_full_bucket_and_leave_debt() {
+    let mut bucket = TokenBucket::new(rate(0.001, 10.0));
+    assert_eq!(bucket.take(25.0), Ok(()));
+    let wait = bucket.take(1.0).unwrap_err();
+    assert!(wait > Duration::from_secs(15_000), "{:?}", wait);
+}
+
+#[test]
+fn zero_rates_are_unlimited() {
+    let mut bucket = TokenBucket::new(rate(0.0, 0.0));
+    for _ in 0..1000 {
+        assert_eq!(bucket.take(1e9), Ok(()));
+    }
+}
+
+#[test]
+fn lower_rates_cap_what_was_collected() {
+    let mut bucket = TokenBucket::new(rate(0.001, 10.0));
+    bucket.set_rate(rate(0.001, 1.0));
+    assert_eq!(bucket.take(1.0), Ok(()));
+    assert!(bucket.take(1.0).is_err());
+}
+
+#[test]
+fn budgets_take_nothing_when_one_limit_is_hit() {
+    let mut budget = Budget::new(rate(0.001, 2.0), rate(0.001, 100.0));
+    assert_eq!(budget.charge(60, false), Ok(()));
+    // Too many bytes, the frame must not cost a message either.
+    assert!(budget.charge(60, false).is_err());
+    assert_eq!(budget.charge(40, false), Ok(()));
+    assert!(budget.charge(0, false).is_err(), "both messages are spent");
+}
+
+#[test]
+fn heartbeats_only_cost_bytes() {
+    let mut budget = Budget::new(rate(0.001, 1.0), rate(0.001, 100.0));
+    assert_eq!(budget.charge(10, false), Ok(()));
+    assert_eq!(budget.charge(10, true), Ok(()));
+    assert!(budget.charge(10, false).is_err());
+    assert!(budget.charge(100, true).is_err());
+}
+
+#[test]
+fn names_share_a_budget() {
+    let guard = Guard::new(rate(0.0, 0.0), no_bans());
+    let (messages, bytes) = (rate(0.001, 1.0), rate(0.0, 0.0));
+    assert_eq!(guard.charge("alice", messages, bytes, 10, false), Ok(()));
+    // Whoever logs in as alice next finds the budget spent.
+    assert!(guard.charge("alice", messages, bytes, 10, false).is_err());
+    assert_eq!(guard.charge("bob", messages, bytes, 10, false), Ok(()));
+}
+
+#[test]
+fn limits_connections_per_address() {
+    let guard = Guard::new(rate(0.001, 2.0), no_bans());
+    assert_eq!(guard.admit(IP), Ok(()));
+    assert_eq!(guard.admit(IP), Ok(()));
+    assert!(guard.admit(IP).is_err());
+    assert_eq!(guard.admit(IpAddr::V4([10, 0, 0, 1].into())), Ok(()));
+}
+
+#[test]
+fn bans_repeat_offenders() {
+    let bans = BanPolicy { strikes: 3, window: Duration::from_secs(60), duration: Duration::from_secs(60) };
+    let guard = Guard::new(rate(0.0, 0.0), bans);
+    assert_eq!(guard.strike(IP), None);
+    assert_eq!(guard.strike(IP), None);
+    assert_eq!(guard.strike(IP), Some(bans.duration));
+    let remaining = guard.admit(IP).unwrap_err();
+    assert!(remaining > Duration::from_secs(59), "{:?}", remaining);
+
+    guard.set_limits(rate(0.0, 0.0), no_bans());
+    assert!(guard.admit(IP).is_err(), "bans outlive changed limits");
+}
+
+#[test]
+fn operators_ban_for_the_longer_time() {
+    let guard = Guard::new(rate(0.0, 0.0), no_bans());
+    guard.ban(IP, Duration::from_secs(600));
+    guard.ban(IP, Duration::from_secs(1));
+    assert!(guard.admit(IP).unwrap_err() > Duration::from_secs(599));
+
+    // Bans end at now plus their length, which mustn't overflow.
+    guard.ban(IP, Duration::MAX);
+    assert!(guard.admit(IP).unwrap_err() > Duration::from_secs(600));
+}
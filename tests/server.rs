@@ -0,0 +1,463 @@
+//! Routing rules of `ChatServer`, checked without sockets.
+
+use chat_rs::protocol::{
+    ClientFrame, ErrorCode, GroupId, LinkFrame, Message, Recipient, Request, ServerFrame, UserId, MAX_FRAME_LEN,
+};
+use chat_rs::server::{ChatServer, ConnectionId, Event, Output, Topology, MAX_NAME_LEN};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([192, 0, 2, 1], port))
+}
+
+/// Everything the server has to do so far.
+fn outputs(server: &mut ChatServer) -> Vec<Output> {
+    std::iter::from_fn(|| server.poll_output()).collect()
+}
+
+fn send(to: &[ConnectionId], frame: ServerFrame) -> Output {
+    Output::Send { to: to.to_vec(), frame }
+}
+
+fn request(server: &mut ChatServer, connection: ConnectionId, id: u64, frame: ClientFrame) -> Vec<Output> {
+    server.handle(Event::Request { connection, request: Request { id, frame } });
+    outputs(server)
+}
+
+fn connect(server: &mut ChatServer, connection: ConnectionId) {
+    server.handle(Event::Connected { connection, addr: addr(40000 + connection as u16) });
+    assert_eq!(outputs(server), []);
+}
+
+/// Connects and logs in as `name`.
+fn login(server: &mut ChatServer, connection: ConnectionId, name: &str) {
+    connect(server, connection);
+    let frame = ClientFrame::Login { name: name.to_string(), direct_addrs: Vec::new() };
+    assert_eq!(request(server, connection, 0, frame), [send(&[connection], ServerFrame::Welcome { name: name.to_string() })]);
+}
+
+fn message(to: Recipient, text: &str) -> Message {
+    Message { from: UserId(String::new()), to, text: Some(text.to_string()), media: None }
+}
+
+fn to_user(name: &str) -> Recipient {
+    Recipient::User(UserId(name.to_string()))
+}
+
+fn to_group(name: &str) -> Recipient {
+    Recipient::Group(GroupId(name.to_string()))
+}
+
+/// `message` as delivered, from `from`.
+fn delivered(from: &str, message: Message) -> ServerFrame {
+    ServerFrame::Message(Message { from: UserId(from.to_string()), ..message })
+}
+
+fn error(request: Option<u64>, error: ErrorCode) -> ServerFrame {
+    ServerFrame::Error { request, error }
+}
+
+#[test]
+fn requires_login_but_ignores_early_heartbeats() {
+    let mut server = ChatServer::new();
+    connect(&mut server, 1);
+
+    assert_eq!(request(&mut server, 1, 3, ClientFrame::Ping { token: 9 }), []);
+    assert_eq!(
+        request(&mut server, 1, 4, ClientFrame::Join { group: String::from("rust") }),
+        [send(&[1], error(Some(4), ErrorCode::NotAuthorized))],
+    );
+    assert_eq!(server.name(1), None);
+}
+
+#[test]
+fn refuses_taken_names() {
+    let mut server = ChatServer::new();
+    login(&mut server, 1, "alice");
+    connect(&mut server, 2);
+
+    let frame = ClientFrame::Login { name: String::from("alice"), direct_addrs: Vec::new() };
+    assert_eq!(request(&mut server, 2, 1, frame), [send(&[2], ServerFrame::NameTaken { name: String::from("alice") })]);
+    assert_eq!(server.name(2), None);
+}
+
+#[test]
+fn refuses_invalid_names() {
+    let mut server = ChatServer::new();
+    connect(&mut server, 1);
+    let too_long = "a".repeat(MAX_NAME_LEN + 1);
+
+    for name in ["", "bob@elsewhere", "two words", "bob:", "#rust", "tab\t", too_long.as_str()] {
+        let frame = ClientFrame::Login { name: name.to_string(), direct_addrs: Vec::new() };
+        let invalid = error(Some(1), ErrorCode::InvalidName { name: name.to_string() });
+        assert_eq!(request(&mut server, 1, 1, frame), [send(&[1], invalid)]);
+    }
+    assert_eq!(server.name(1), None);
+
+    login(&mut server, 2, &"é".repeat(MAX_NAME_LEN));
+    assert_eq!(
+        request(&mut server, 2, 2, ClientFrame::Nick { name: String::from("a b") }),
+        [send(&[2], error(Some(2), ErrorCode::InvalidName { name: String::from("a b") }))],
+    );
+    assert_eq!(server.name(2), Some("é".repeat(MAX_NAME_LEN).as_str()));
+}
+
+#[test]
+fn answers_pings() {
+    let mut server = ChatServer::new();
+    login(&mut server, 1, "alice");
+
+    assert_eq!(request(&mut server, 1, 1, ClientFrame::Ping { token: 42 }), [send(&[1], ServerFrame::Pong { token: 42 })]);
+    assert_eq!(request(&mut server, 1, 2, ClientFrame::Pong { token: 42 }), []);
+}
+
+#[test]
+fn delivers_direct_messages_from_the_sender() {
+    let mut server = ChatServer::new();
+    login(&mut server, 1, "alice");
+    login(&mut server, 2, "bob");
+
+    // Whatever the client claims, the message is from its name.
+    let sent = Message { from: UserId(String::from("mallory")), ..message(to_user("bob"), "hi") };
+    assert_eq!(
+        request(&mut server, 1, 5, ClientFrame::Message(sent.clone())),
+        [send(&[2], delivered("alice", sent))],
+    );
+}
+
+#[test]
+fn reports_unknown_recipients() {
+    let mut server = ChatServer::new();
+    login(&mut server, 1, "alice");
+
+    for to in [to_user("nobody"), to_group("nowhere")] {
+        let (Recipient::User(UserId(name)) | Recipient::Group(GroupId(name))) = to.clone();
+        assert_eq!(
+            request(&mut server, 1, 6, ClientFrame::Message(message(to, "hello?"))),
+            [send(&[1], error(Some(6), ErrorCode::UnknownRecipient { name }))],
+        );
+    }
+}
+
+#[test]
+fn fans_group_messages_out_to_members() {
+    let mut server = ChatServer::new();
+    login(&mut server, 1, "alice");
+    login(&mut server, 2, "bob");
+    login(&mut server, 3, "carol");
+    for connection in [1, 2] {
+        assert_eq!(request(&mut server, connection, 1, ClientFrame::Join { group: String::from("rust") }), []);
+    }
+
+    // Members get their own messages, outsiders may send too.
+    for from in [1, 3] {
+        let Some(Output::Send { mut to, frame }) = request(&mut server, from, 2, ClientFrame::Message(message(to_group("rust"), "hi"))).pop() else {
+            panic!("nothing sent");
+        };
+        to.sort();
+        assert_eq!(to, [1, 2]);
+        assert_eq!(frame, delivered(server.name(from).unwrap(), message(to_group("rust"), "hi")));
+    }
+
+    assert_eq!(request(&mut server, 2, 3, ClientFrame::Leave { group: String::from("rust") }), []);
+    assert_eq!(
+        request(&mut server, 3, 4, ClientFrame::Message(message(to_group("rust"), "still there?"))),
+        [send(&[1], delivered("carol", message(to_group("rust"), "still there?")))],
+    );
+
+    // The group goes away with its last member.
+    assert_eq!(request(&mut server, 1, 5, ClientFrame::Leave { group: String::from("rust") }), []);
+    assert_eq!(
+        request(&mut server, 3, 6, ClientFrame::Message(message(to_group("rust"), "anyone?"))),
+        [send(&[3], error(Some(6), ErrorCode::UnknownRecipient { name: String::from("rust") }))],
+    );
+}
+
+#[test]
+fn renames_announce_and_keep_groups() {
+    let mut server = ChatServer::new();
+    login(&mut server, 1, "alice");
+    login(&mut server, 2, "bob");
+    assert_eq!(request(&mut server, 1, 1, ClientFrame::Join { group: String::from("rust") }), []);
+
+    assert_eq!(
+        request(&mut server, 1, 2, ClientFrame::Nick { name: String::from("bob") }),
+        [send(&[1], ServerFrame::NameTaken { name: String::from("bob") })],
+    );
+    // The renamer's confirmation answers its request, the others only
+    // hear about it.
+    login(&mut server, 3, "carol");
+    let mut renamed = request(&mut server, 1, 3, ClientFrame::Nick { name: String::from("ally") });
+    let Some(Output::Send { mut to, frame }) = renamed.pop() else {
+        panic!("rename not announced");
+    };
+    to.sort();
+    assert_eq!(to, [2, 3]);
+    assert_eq!(frame, ServerFrame::Renamed { old: String::from("alice"), new: String::from("ally") });
+    assert_eq!(renamed, [send(&[1], ServerFrame::NickAccepted { request: 3, name: String::from("ally") })]);
+    assert_eq!(server.name(1), Some("ally"));
+
+    // The old name is free, the group follows the new one.
+    assert_eq!(
+        request(&mut server, 2, 4, ClientFrame::Message(message(to_user("alice"), "hi"))),
+        [send(&[2], error(Some(4), ErrorCode::UnknownRecipient { name: String::from("alice") }))],
+    );
+    assert_eq!(
+        request(&mut server, 2, 5, ClientFrame::Message(message(to_group("rust"), "hi"))),
+        [send(&[1], delivered("bob", message(to_group("rust"), "hi")))],
+    );
+}
+
+#[test]
+fn rendezvous_adds_the_observed_address() {
+    let mut server = ChatServer::new();
+    connect(&mut server, 1);
+    let local = SocketAddr::from(([10, 0, 0, 5], 7000));
+    let frame = ClientFrame::Login { name: String::from("alice"), direct_addrs: vec![local] };
+    assert_eq!(request(&mut server, 1, 0, frame).len(), 1);
+    login(&mut server, 2, "bob");
+
+    // Alice learns who is about to link to her.
+    assert_eq!(
+        request(&mut server, 2, 1, ClientFrame::Rendezvous { with: String::from("alice") }),
+        [
+            send(&[1], ServerFrame::Candidates { name: String::from("bob"), addrs: Vec::new() }),
+            send(&[2], ServerFrame::Candidates {
+                name: String::from("alice"),
+                addrs: vec![local, SocketAddr::new(addr(0).ip(), 7000)],
+            }),
+        ],
+    );
+    assert_eq!(
+        request(&mut server, 2, 2, ClientFrame::Rendezvous { with: String::from("nobody") }),
+        [send(&[2], ServerFrame::Candidates { name: String::from("nobody"), addrs: Vec::new() })],
+    );
+}
+
+#[test]
+fn malformed_frames_keep_the_connection() {
+    let mut server = ChatServer::new();
+    login(&mut server, 1, "alice");
+
+    server.handle(Event::Malformed { connection: 1 });
+    assert_eq!(outputs(&mut server), [send(&[1], error(None, ErrorCode::MalformedFrame))]);
+    assert_eq!(server.name(1), Some("alice"));
+}
+
+#[test]
+fn closes_connections_sending_too_large_frames() {
+    let mut server = ChatServer::new();
+    login(&mut server, 1, "alice");
+    assert_eq!(request(&mut server, 1, 1, ClientFrame::Join { group: String::from("rust") }), []);
+
+    let len = MAX_FRAME_LEN + 1;
+    server.handle(Event::TooLarge { connection: 1, len });
+    assert_eq!(
+        outputs(&mut server),
+        [send(&[1], error(None, ErrorCode::TooLarge { len: len as u64 })), Output::Close { connection: 1 }],
+    );
+
+    // Already forgotten: its name is free and it is out of its groups.
+    server.handle(Event::Disconnected { connection: 1 });
+    assert_eq!(outputs(&mut server), []);
+    login(&mut server, 2, "alice");
+    assert_eq!(
+        request(&mut server, 2, 1, ClientFrame::Message(message(to_group("rust"), "hi"))),
+        [send(&[2], error(Some(1), ErrorCode::UnknownRecipient { name: String::from("rust") }))],
+    );
+}
+
+#[test]
+fn refuses_messages_that_grow_over_the_limit() {
+    let mut server = ChatServer::new();
+    login(&mut server, 1, "a-rather-long-name");
+
+    // Fits as sent with an empty `from`, not once it is filled in.
+    let text = "x".repeat(MAX_FRAME_LEN - 64);
+    let Some(Output::Send { to, frame: ServerFrame::Error { request: Some(7), error: ErrorCode::TooLarge { len } } }) =
+        request(&mut server, 1, 7, ClientFrame::Message(message(to_user("a-rather-long-name"), &text))).pop()
+    else {
+        panic!("message not refused");
+    };
+    assert_eq!(to, [1]);
+    assert!(len as usize > MAX_FRAME_LEN);
+}
+
+#[test]
+fn disconnecting_frees_the_name() {
+    let mut server = ChatServer::new();
+    login(&mut server, 1, "alice");
+    login(&mut server, 2, "bob");
+
+    server.handle(Event::Disconnected { connection: 1 });
+    assert_eq!(outputs(&mut server), []);
+    assert_eq!(
+        request(&mut server, 2, 1, ClientFrame::Message(message(to_user("alice"), "bye"))),
+        [send(&[2], error(Some(1), ErrorCode::UnknownRecipient { name: String::from("alice") }))],
+    );
+    // Late requests of a gone connection are dropped.
+    assert_eq!(request(&mut server, 1, 2, ClientFrame::Ping { token: 1 }), []);
+    login(&mut server, 1, "alice");
+}
+
+/// The shards of one server, each with its `ChatServer`.
+struct Shards {
+    topology: Topology,
+    servers: Vec<ChatServer>,
+}
+
+impl Shards {
+    fn new(topology: Topology) -> Shards {
+        let servers = (0..topology.shards).map(|shard| ChatServer::sharded(shard, topology.clone())).collect();
+        Shards { topology, servers }
+    }
+
+    /// Handles `event` on `shard`, then what the shards relay to each
+    /// other. Returns the rest of what they asked for and how many relays
+    /// it took.
+    fn handle(&mut self, shard: usize, event: Event) -> (Vec<Output>, usize) {
+        let (mut queue, mut done, mut relays) = (VecDeque::from([(shard, event)]), Vec::new(), 0);
+        while let Some((shard, event)) = queue.pop_front() {
+            self.servers[shard].handle(event);
+            while let Some(output) = self.servers[shard].poll_output() {
+                match output {
+                    Output::Relay { shard, relay } => {
+                        relays += 1;
+                        queue.push_back((shard, Event::Relayed(relay)));
+                    }
+                    output => done.push(output),
+                }
+            }
+        }
+
+        (done, relays)
+    }
+
+    fn request(&mut self, connection: ConnectionId, id: u64, frame: ClientFrame) -> (Vec<Output>, usize) {
+        let home = self.topology.home(connection);
+        self.handle(home, Event::Request { connection, request: Request { id, frame } })
+    }
+
+    /// Connects `connection` to its home shard and logs it in as `name`.
+    fn login(&mut self, connection: ConnectionId, name: &str) -> (Vec<Output>, usize) {
+        let home = self.topology.home(connection);
+        self.handle(home, Event::Connected { connection, addr: addr(40000 + connection as u16) });
+        self.request(connection, 0, ClientFrame::Login { name: name.to_string(), direct_addrs: Vec::new() })
+    }
+
+    /// A name that `shard` owns.
+    fn name_on(&self, shard: usize) -> String {
+        (0..).map(|i| format!("user{}", i)).find(|name| self.topology.owner(name) == shard).unwrap()
+    }
+}
+
+#[test]
+fn shards_hand_each_other_what_they_own() {
+    let mut shards = Shards::new(Topology { shards: 2, ..Topology::default() });
+    // Connection 1 is served by shard 1, its name is owned by shard 0.
+    let (alice, bob) = (shards.name_on(0), shards.name_on(1));
+    assert_eq!(shards.topology.home(1), 1);
+
+    let (welcome, relays) = shards.login(1, &alice);
+    assert_eq!(welcome, [send(&[1], ServerFrame::Welcome { name: alice.clone() })]);
+    assert_eq!(relays, 2, "claimed and answered by the owner");
+    assert_eq!(shards.servers[1].name(1), Some(alice.as_str()));
+    assert_eq!(shards.servers[0].user(&alice).map(|(connection, _)| connection), Some(1));
+    assert_eq!(shards.login(2, &bob).0, [send(&[2], ServerFrame::Welcome { name: bob.clone() })]);
+    assert_eq!(shards.login(3, &alice).0, [send(&[3], ServerFrame::NameTaken { name: alice.clone() })]);
+
+    let hi = message(to_user(&bob), "hi");
+    assert_eq!(shards.request(1, 1, ClientFrame::Message(hi.clone())).0, [send(&[2], delivered(&alice, hi))]);
+
+    // Groups live on their own shard, members are connections wherever
+    // they are served.
+    let group = shards.name_on(0);
+    for connection in [1, 2] {
+        assert_eq!(shards.request(connection, 2, ClientFrame::Join { group: group.clone() }).0, []);
+    }
+    let Some(Output::Send { mut to, .. }) = shards.request(2, 3, ClientFrame::Message(message(to_group(&group), "all"))).0.pop()
+    else {
+        panic!("nothing sent");
+    };
+    to.sort();
+    assert_eq!(to, [1, 2]);
+    let (_, members) = shards.servers[0].groups().find(|(name, _)| *name == group).unwrap();
+    assert_eq!(members.len(), 2);
+}
+
+#[test]
+fn sharded_renames_move_the_name() {
+    let mut shards = Shards::new(Topology { shards: 3, ..Topology::default() });
+    let (alice, ally, bob) = (shards.name_on(0), shards.name_on(1), shards.name_on(2));
+    shards.login(1, &alice);
+    shards.login(2, &bob);
+
+    let (mut renamed, _) = shards.request(1, 1, ClientFrame::Nick { name: ally.clone() });
+    assert_eq!(renamed.remove(0), send(&[1], ServerFrame::NickAccepted { request: 1, name: ally.clone() }));
+    assert_eq!(renamed, [send(&[2], ServerFrame::Renamed { old: alice.clone(), new: ally.clone() })]);
+    assert!(shards.servers[0].user(&alice).is_none());
+    assert_eq!(shards.servers[1].user(&ally).map(|(connection, _)| connection), Some(1));
+
+    // Disconnecting releases the new name.
+    shards.handle(shards.topology.home(1), Event::Disconnected { connection: 1 });
+    assert!(shards.servers.iter().all(|server| server.users().all(|(name, _, _)| name == bob)));
+}
+
+fn federated() -> Topology {
+    Topology { shards: 1, server_name: String::from("a"), peers: ["b".to_string()].into_iter().collect() }
+}
+
+#[test]
+fn forwards_to_federated_servers() {
+    let mut shards = Shards::new(federated());
+    shards.login(1, "alice");
+
+    let hi = message(to_user("bob@b"), "hi");
+    assert_eq!(
+        shards.request(1, 1, ClientFrame::Message(hi.clone())).0,
+        [Output::Forward {
+            server: String::from("b"),
+            frame: LinkFrame::Route(Message { from: UserId(String::from("alice@a")), ..hi }),
+        }],
+    );
+    assert_eq!(
+        shards.request(1, 2, ClientFrame::Message(message(to_user("carol@c"), "hi"))).0,
+        [send(&[1], error(Some(2), ErrorCode::UnknownRecipient { name: String::from("carol@c") }))],
+    );
+    let join = ClientFrame::Join { group: String::from("rust@b") };
+    assert_eq!(
+        shards.request(1, 3, join).0,
+        [Output::Forward {
+            server: String::from("b"),
+            frame: LinkFrame::Join { group: String::from("rust"), user: String::from("alice@a") },
+        }],
+    );
+}
+
+#[test]
+fn federated_servers_speak_for_their_own_users() {
+    let mut shards = Shards::new(federated());
+    shards.login(1, "alice");
+    let remote = |frame| Event::Remote { server: String::from("b"), frame };
+
+    let hi = Message { from: UserId(String::from("bob@b")), ..message(to_user("alice"), "hi") };
+    assert_eq!(shards.handle(0, remote(LinkFrame::Route(hi.clone()))).0, [send(&[1], ServerFrame::Message(hi.clone()))]);
+    let forged = Message { from: UserId(String::from("eve@c")), ..hi };
+    assert_eq!(shards.handle(0, remote(LinkFrame::Route(forged))).0, []);
+
+    // Remote members of our groups get group messages through their server.
+    let join = LinkFrame::Join { group: String::from("rust"), user: String::from("bob@b") };
+    assert_eq!(shards.handle(0, remote(join)).0, []);
+    let sent = message(to_group("rust"), "hello");
+    assert_eq!(
+        shards.request(1, 1, ClientFrame::Message(sent.clone())).0,
+        [Output::Forward {
+            server: String::from("b"),
+            frame: LinkFrame::Deliver {
+                user: String::from("bob"),
+                message: Message { from: UserId(String::from("alice@a")), to: to_group("rust@a"), ..sent },
+            },
+        }],
+    );
+}
@@ -0,0 +1,175 @@
+//! TLS settings and `async_std_server` speaking TLS, with its history on.
+
+use chat_rs::config::Config;
+use chat_rs::protocol::{encode_frame, read_frame, ClientFrame, Message, Recipient, Request, ServerFrame, UserId};
+use chat_rs::storage::History;
+use chat_rs::tls::{self, ClientTls};
+use rustls::{ClientConnection, StreamOwned};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+fn load(args: &[&str]) -> Config {
+    Config::from_sources(args.iter().map(|arg| arg.to_string()), |_| None).unwrap()
+}
+
+/// A certificate for `localhost` and its key, written to PEM files named
+/// after the test.
+fn certificate(test: &str) -> (PathBuf, PathBuf) {
+    let certified = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    let dir = std::env::temp_dir();
+    let cert = dir.join(format!("chat-rs-{}-{}.crt", test, std::process::id()));
+    let key = dir.join(format!("chat-rs-{}-{}.key", test, std::process::id()));
+    std::fs::write(&cert, certified.cert.pem()).unwrap();
+    std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    (cert, key)
+}
+
+#[test]
+fn needs_a_certificate_and_its_key() {
+    assert!(tls::server_config(&load(&[])).unwrap().is_none());
+    let e = tls::server_config(&load(&["--tls.cert=server.crt"])).unwrap_err();
+    assert_eq!(e.to_string(), "tls.key: required when tls.cert is set");
+    let e = tls::server_config(&load(&["--tls.key=server.key"])).unwrap_err();
+    assert_eq!(e.to_string(), "tls.cert: required when tls.key is set");
+
+    let e = tls::server_config(&load(&["--tls.cert=/nonexistent.crt", "--tls.key=/nonexistent.key"])).unwrap_err();
+    assert!(e.to_string().starts_with("tls.cert = \"/nonexistent.crt\" (on the command line): "), "{}", e);
+
+    let (cert, key) = certificate("keys");
+    let (cert, key) = (cert.to_str().unwrap(), key.to_str().unwrap());
+    assert!(tls::server_config(&load(&["--tls.cert", cert, "--tls.key", key])).unwrap().is_some());
+    // Not a key.
+    let e = tls::server_config(&load(&["--tls.cert", cert, "--tls.key", cert])).unwrap_err();
+    assert!(e.to_string().starts_with("tls.key = "), "{}", e);
+    // Not a certificate.
+    let e = ClientTls::from_config(&load(&["--tls.ca", key]), "localhost:8000").unwrap_err();
+    assert!(e.to_string().ends_with("no certificates in the file"), "{}", e);
+    std::fs::remove_file(cert).unwrap();
+    std::fs::remove_file(key).unwrap();
+}
+
+#[test]
+fn checks_the_server_name() {
+    let (cert, key) = certificate("names");
+    let ca = cert.to_str().unwrap();
+    let client = |args: &[&str], addr| ClientTls::from_config(&load(args), addr).map(|tls| tls.unwrap().server_name);
+    assert!(ClientTls::from_config(&load(&[]), "localhost:8000").unwrap().is_none());
+    assert_eq!(client(&["--tls.ca", ca], "localhost:8000").unwrap().to_str(), "localhost");
+    assert_eq!(client(&["--tls.ca", ca], "[::1]:8000").unwrap().to_str(), "::1");
+    assert_eq!(client(&["--tls.ca", ca, "--tls.server_name=chat.example"], "127.0.0.1:8000").unwrap().to_str(), "chat.example");
+    let e = client(&["--tls.ca", ca, "--tls.server_name=not a name"], "127.0.0.1:8000").unwrap_err();
+    assert!(e.to_string().starts_with("tls.server_name = \"not a name\" (on the command line): "), "{}", e);
+    std::fs::remove_file(cert).unwrap();
+    std::fs::remove_file(key).unwrap();
+}
+
+/// An `async_std_server` run with `args`, killed when dropped.
+struct Server {
+    process: Child,
+    addr: String,
+}
+
+impl Server {
+    fn start(args: &[&str]) -> Server {
+        let addr = free_addr();
+        let process = Command::new(env!("CARGO_BIN_EXE_async_std_server"))
+            .arg(&addr)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        Server { process, addr }
+    }
+
+    /// Connects over TLS, retrying until the server listens.
+    fn connect(&self, tls: &ClientTls) -> StreamOwned<ClientConnection, TcpStream> {
+        for _ in 0..250 {
+            if let Ok(socket) = TcpStream::connect(&self.addr) {
+                socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                let connection = ClientConnection::new(Arc::clone(&tls.config), tls.server_name.clone()).unwrap();
+                return StreamOwned::new(connection, socket);
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("nothing listens on {}", self.addr);
+    }
+
+    /// Shuts the server down like an operator would, so that it writes out
+    /// what it still holds.
+    fn stop(mut self) {
+        unsafe { libc::kill(self.process.id() as libc::pid_t, libc::SIGTERM) };
+        let _ = self.process.wait();
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+fn free_addr() -> String {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+}
+
+/// The metrics served at `addr`.
+fn scrape(addr: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn send(stream: &mut StreamOwned<ClientConnection, TcpStream>, id: u64, frame: ClientFrame) {
+    stream.write_all(&encode_frame(&Request { id, frame }).unwrap()).unwrap();
+}
+
+#[test]
+fn serves_clients_over_tls_and_stores_their_messages() {
+    let (cert, key) = certificate("serve");
+    let history = std::env::temp_dir().join(format!("chat-rs-history-{}", std::process::id()));
+    let metrics = free_addr();
+    let server = Server::start(&[
+        "--tls.cert", cert.to_str().unwrap(),
+        "--tls.key", key.to_str().unwrap(),
+        "--storage.path", history.to_str().unwrap(),
+        "--metrics.addr", &metrics,
+    ]);
+    let config = load(&["--tls.ca", cert.to_str().unwrap(), "--tls.server_name=localhost"]);
+    let tls = ClientTls::from_config(&config, &server.addr).unwrap().unwrap();
+
+    let mut alice = server.connect(&tls);
+    send(&mut alice, 1, ClientFrame::Login { name: String::from("alice"), direct_addrs: Vec::new() });
+    assert!(matches!(read_frame(&mut alice).unwrap(), Some(ServerFrame::Welcome { .. })));
+    let message = Message {
+        from: UserId(String::from("whoever")),
+        to: Recipient::User(UserId(String::from("alice"))),
+        text: Some(String::from("hello over TLS")),
+        media: None,
+    };
+    send(&mut alice, 2, ClientFrame::Message(message.clone()));
+    let signed = Message { from: UserId(String::from("alice")), ..message };
+    assert_eq!(read_frame(&mut alice).unwrap(), Some(ServerFrame::Message(signed.clone())));
+    // The history is written after the message is routed.
+    let stored = (0..250).any(|_| {
+        thread::sleep(Duration::from_millis(20));
+        scrape(&metrics).contains("\nchat_storage_write_seconds_count 1\n")
+    });
+    assert!(stored, "no storage latency measured: {}", scrape(&metrics));
+    server.stop();
+
+    let stored: Vec<_> = History::read(&history).unwrap().into_iter().map(|stored| stored.message).collect();
+    assert_eq!(stored, [signed]);
+    for path in [cert, key, history] {
+        std::fs::remove_file(path).unwrap();
+    }
+}